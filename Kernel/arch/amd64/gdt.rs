@@ -0,0 +1,156 @@
+use core::mem;
+use alloc::boxed::Box;
+
+use mm::pmm::FrameAllocator;
+use mm::vmm;
+use super::percpu;
+
+/*
+ * Segment selectors. The order is dictated by syscall/sysret: STAR.SYSCALL
+ * takes the kernel code selector with kernel data right after it, while
+ * STAR.SYSRET takes USER_CS32 and expects user data at +8 and 64-bit user
+ * code at +16.
+ */
+pub const KERNEL_CS: u16 = 0x08;
+pub const KERNEL_DS: u16 = 0x10;
+pub const USER_CS32: u16 = 0x18 | 3;
+pub const USER_DS: u16 = 0x20 | 3;
+pub const USER_CS: u16 = 0x28 | 3;
+pub const TSS_SELECTOR: u16 = 0x30;
+
+/* Interrupt stack table slots, as used by the IDT (1-based) */
+pub const IST_DOUBLE_FAULT: u8 = 1;
+pub const IST_NMI: u8 = 2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+const IST_STACKS: usize = 3;
+/* Pages per IST stack, enough for a panic message in a debug build */
+const IST_STACK_PAGES: usize = 4;
+
+/* Null, kernel code/data, user code32/data/code64 and a 16 byte TSS */
+const GDT_ENTRIES: usize = 8;
+
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub iomap_base: u16,
+}
+
+/* The GDT and TSS of a single CPU */
+#[repr(C, align(16))]
+pub struct CpuTables {
+    gdt: [u64; GDT_ENTRIES],
+    pub tss: TaskStateSegment,
+}
+
+#[repr(C, packed)]
+struct DescriptorPointer {
+    limit: u16,
+    base: u64,
+}
+
+/* Encode the two halves of the 64-bit TSS descriptor for `tss` */
+fn tss_descriptor(tss: &TaskStateSegment) -> (u64, u64)
+{
+    let base = tss as *const TaskStateSegment as u64;
+    let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+
+    let low = (limit & 0xFFFF)
+            | ((base & 0xFFFFFF) << 16)
+            | (0x89 << 40)              /* present, 64-bit available TSS */
+            | (((limit >> 16) & 0xF) << 48)
+            | (((base >> 24) & 0xFF) << 56);
+    let high = base >> 32;
+    (low, high)
+}
+
+/*
+ * Build the GDT and TSS for a CPU. The IST stacks get a guard page below
+ * them, so that an overflow faults rather than tramples other memory.
+ */
+pub fn allocate(fma: &mut FrameAllocator) -> usize
+{
+    let mut tables = Box::new(CpuTables {
+        gdt: [
+            0,
+            0x00209A0000000000,     /* 0x08: 64-bit Kernel Code */
+            0x0000920000000000,     /* 0x10: Kernel Data */
+            0x00CFFA000000FFFF,     /* 0x18: 32-bit User Code */
+            0x0000F20000000000,     /* 0x20: User Data */
+            0x0020FA0000000000,     /* 0x28: 64-bit User Code */
+            0,                      /* 0x30: TSS, filled in below */
+            0,
+        ],
+        tss: TaskStateSegment {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            /* No IO permission bitmap */
+            iomap_base: mem::size_of::<TaskStateSegment>() as u16,
+        },
+    });
+
+    for i in 0..IST_STACKS {
+        tables.tss.ist[i] = vmm::map_stack(fma, IST_STACK_PAGES) as u64;
+    }
+
+    let (low, high) = tss_descriptor(&tables.tss);
+    tables.gdt[6] = low;
+    tables.gdt[7] = high;
+
+    Box::into_raw(tables) as usize
+}
+
+/*
+ * Load the tables built by `allocate` on the current CPU, reload the
+ * segment registers and the task register.
+ *
+ * The GS base is preserved, since loading %gs would clobber it.
+ */
+pub unsafe fn load(tables: usize)
+{
+    let tables = &mut *(tables as *mut CpuTables);
+    let ptr = DescriptorPointer {
+        limit: (mem::size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
+        base: tables.gdt.as_ptr() as u64,
+    };
+
+    asm!("lgdt ($0)" :: "r"(&ptr) : "memory" : "volatile");
+
+    /* Reload %cs with a far return, then the data segments */
+    asm!("pushq $0
+          leaq 1f(%rip), %rax
+          pushq %rax
+          lretq
+          1:
+          movw $1, %ax
+          movw %ax, %ds
+          movw %ax, %es
+          movw %ax, %ss"
+         :: "i"(KERNEL_CS as u64), "i"(KERNEL_DS) : "rax", "memory" : "volatile");
+
+    asm!("ltr $0" :: "r"(TSS_SELECTOR) :: "volatile");
+
+    percpu::this_cpu_area().tss = &mut tables.tss as *mut TaskStateSegment as usize;
+}
+
+/*
+ * Set the stack the CPU switches to on an interrupt from ring 3. The
+ * scheduler updates this to the kernel stack of the task it switches to.
+ */
+pub fn set_kernel_stack(rsp: usize)
+{
+    unsafe {
+        let tss = percpu::this_cpu_area().tss as *mut TaskStateSegment;
+        if !tss.is_null() {
+            (*tss).rsp[0] = rsp as u64;
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use core::mem;
+
+use super::gdt;
+use super::percpu;
+use super::uaccess;
+use super::vectors;
+
+/* Vectors used by the kernel */
+pub const TIMER_VECTOR: u8 = 0x20;
+pub const RESCHED_VECTOR: u8 = 0x21;
+pub const CALL_FUNCTION_VECTOR: u8 = 0x22;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/* Vectors below this are CPU exceptions */
+const FIRST_IRQ_VECTOR: usize = 32;
+
+const NMI_VECTOR: usize = 2;
+const GENERAL_PROTECTION_VECTOR: usize = 13;
+const PAGE_FAULT_VECTOR: usize = 14;
+const DOUBLE_FAULT_VECTOR: usize = 8;
+const MACHINE_CHECK_VECTOR: usize = 18;
+
+static EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error", "Debug", "Non-maskable Interrupt", "Breakpoint",
+    "Overflow", "BOUND Range Exceeded", "Invalid Opcode", "Device Not Available",
+    "Double Fault", "Coprocessor Segment Overrun", "Invalid TSS", "Segment Not Present",
+    "Stack-Segment Fault", "General Protection", "Page Fault", "Reserved",
+    "x87 Floating-Point Error", "Alignment Check", "Machine Check", "SIMD Floating-Point",
+    "Virtualization", "Control Protection", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Reserved", "Reserved",
+    "Hypervisor Injection", "VMM Communication", "Security", "Reserved",
+];
+
+/* The register state saved by isr_common, see arch/amd64/isr.S */
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    /* Pushed by the CPU */
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    flags: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+const MISSING_ENTRY: IdtEntry = IdtEntry {
+    offset_low: 0,
+    selector: 0,
+    ist: 0,
+    flags: 0,
+    offset_mid: 0,
+    offset_high: 0,
+    reserved: 0,
+};
+
+impl IdtEntry {
+    /* A present, ring 0 interrupt gate */
+    fn new(handler: usize, ist: u8) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: gdt::KERNEL_CS,
+            ist: ist,
+            flags: 0x8E,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/* The IDT is shared by all CPUs */
+static mut IDT: [IdtEntry; 256] = [MISSING_ENTRY; 256];
+static mut HANDLERS: [Option<InterruptHandler>; 256] = [None; 256];
+
+/* Fill in the IDT, must be called on the BSP before `load` */
+pub fn init()
+{
+    extern {
+        /* Defined in arch/amd64/isr.S */
+        static isr_stubs: u8;
+    }
+    let stubs: usize = unsafe { &isr_stubs as *const u8 as usize };
+
+    for vector in 0..256 {
+        /* isr.S sends these to isr_paranoid, keep the two in step */
+        let ist = match vector {
+            NMI_VECTOR => gdt::IST_NMI,
+            DOUBLE_FAULT_VECTOR => gdt::IST_DOUBLE_FAULT,
+            MACHINE_CHECK_VECTOR => gdt::IST_MACHINE_CHECK,
+            _ => 0,
+        };
+        unsafe {
+            IDT[vector] = IdtEntry::new(stubs + 16 * vector, ist);
+        }
+    }
+}
+
+/*
+ * Load the IDT on the current CPU.
+ *
+ * The IST entries refer to the TSS, so on the BSP exceptions that use them
+ * aren't usable until its own GDT has been loaded in late_init.
+ */
+pub unsafe fn load()
+{
+    let ptr = IdtPointer {
+        limit: (mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
+        base: IDT.as_ptr() as u64,
+    };
+    asm!("lidt ($0)" :: "r"(&ptr) : "memory" : "volatile");
+}
+
+/*
+ * Install `handler` for the interrupt `vector`. Handlers run with
+ * interrupts disabled, the LAPIC has already been sent an EOI.
+ */
+pub fn register_handler(vector: u8, handler: InterruptHandler)
+{
+    assert!(vector as usize >= FIRST_IRQ_VECTOR, "vector {} is an exception", vector);
+    assert!(!vectors::is_device_vector(vector), "vector 0x{:x} is for devices", vector);
+    unsafe {
+        if let Some(_) = HANDLERS[vector as usize] {
+            panic!("interrupt vector 0x{:x} registered twice", vector);
+        }
+        HANDLERS[vector as usize] = Some(handler);
+    }
+}
+
+fn exception(frame: &mut InterruptFrame)
+{
+    let vector = frame.vector as usize;
+
+    /* A fault the kernel expected, e.g. on a bad user pointer */
+    if (vector == GENERAL_PROTECTION_VECTOR || vector == PAGE_FAULT_VECTOR) && frame.cs & 3 == 0 {
+        if let Some(fixup) = uaccess::fixup(frame.rip as usize) {
+            frame.rip = fixup as u64;
+            return;
+        }
+    }
+
+    let mut cr2: u64 = 0;
+    if vector == PAGE_FAULT_VECTOR {
+        unsafe { asm!("mov %cr2, $0" : "=r"(cr2)); }
+    }
+
+    /* A user program did something bad, it goes rather than the kernel */
+    if frame.cs & 3 == 3 && vector != NMI_VECTOR && vector != MACHINE_CHECK_VECTOR {
+        log!("task {}: {} at rip 0x{:x}, error code 0x{:x}, cr2 0x{:x}, killed",
+             ::sched::current_id(), EXCEPTION_NAMES[vector], frame.rip,
+             frame.error_code, cr2);
+        ::sched::exit();
+    }
+
+    log!("{} (vector {}) on CPU {}, error code 0x{:x}",
+         EXCEPTION_NAMES[vector], vector, percpu::cpu_id(), frame.error_code);
+    log!("rip 0x{:016x} cs 0x{:x} rflags 0x{:x} rsp 0x{:016x} ss 0x{:x} cr2 0x{:016x}",
+         frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss, cr2);
+    log!("{:?}", frame);
+    panic!("Unhandled exception: {}", EXCEPTION_NAMES[vector]);
+}
+
+/* Called by isr_common for every interrupt and exception */
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame)
+{
+    let vector = frame.vector as usize;
+
+    if vector < FIRST_IRQ_VECTOR {
+        return exception(frame);
+    }
+
+    /* Spurious interrupts must not be acknowledged */
+    if vector == SPURIOUS_VECTOR as usize {
+        return;
+    }
+
+    /*
+     * Acknowledge before running the handler, as the handler may switch
+     * to another task and not come back here for a while.
+     */
+    if let Some(ref lapic) = unsafe { percpu::this_cpu_area() }.lapic {
+        lapic.eoi();
+    }
+
+    percpu::set_irq_depth(percpu::irq_depth() + 1);
+    match unsafe { HANDLERS[vector] } {
+        Some(handler) => handler(frame),
+        None if vectors::dispatch(vector as u8) => {}
+        None => log!("Unhandled interrupt vector 0x{:x} on CPU {}", vector, percpu::cpu_id()),
+    }
+    percpu::set_irq_depth(percpu::irq_depth() - 1);
+}
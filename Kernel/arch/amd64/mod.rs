@@ -18,158 +18,117 @@ mod x86_io;
 #[path = "../x86_common/debug.rs"]
 pub mod debug;
 
-#[path = "./apic.rs"]
+// Multiboot2 boot information
+#[path = "../x86_common/multiboot2.rs"]
+mod multiboot2;
+
+// Reading the Multiboot information
+#[path = "../x86_common/discover.rs"]
+mod discover;
+
+#[path = "../x86_common/apic.rs"]
 mod apic;
 
-// Logging code
-#[path = "../../logging.rs"]
-mod logging;
+// Model specific registers
+#[path = "../x86_common/msr.rs"]
+mod msr;
 
-/* Some globals. */
-pub const KERNEL_BASE: usize = 0xFFFFFFFF80000000;
-pub const PAGE_SHIFT: usize = 12;
-pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+// Interrupt flag manipulation
+#[path = "../x86_common/irq.rs"]
+pub mod irq;
 
-extern crate multiboot;
+// Per-CPU data, reached through %gs
+#[path = "../x86_common/percpu.rs"]
+pub mod percpu;
 
-use self::multiboot::{Multiboot, PAddr};
-use core::slice;
-use core::mem;
-use core::ptr;
-use alloc::Vec;
+// Per-CPU GDT and TSS
+#[path = "./gdt.rs"]
+mod gdt;
 
-extern crate x86_mp;
-use x86_mp::{ProcessorEntry, MPEntryCode, MPFloatingPointer, MPConfigurationTableHeader};
+// Interrupt descriptor table and dispatch
+#[path = "./idt.rs"]
+mod idt;
 
-use mm::vmm::Pml4Entry;
-use mm::pmm::FrameAllocator;
+// CPUID and other processor helpers
+#[path = "../x86_common/cpu.rs"]
+pub mod cpu;
 
-pub fn paddr_to_slice<'a>(p: multiboot::PAddr, sz: usize) -> Option<&'a [u8]> {
-    unsafe {
-        let ptr = mem::transmute(p as usize + KERNEL_BASE);
-        Some(slice::from_raw_parts(ptr, sz))
-    }
-}
+// Legacy programmable interval timer
+#[path = "../x86_common/pit.rs"]
+mod pit;
 
-#[derive(Clone, Copy, Debug)]
-pub struct Processor {
-    pub id: usize,
-    pub apic_id: usize,
-    pub stack_frame: usize,
-}
+// Legacy 8259 interrupt controller
+#[path = "../x86_common/pic.rs"]
+mod pic;
 
-pub static mut processor_list: Vec<Processor> = Vec::new();
+// LAPIC timer
+#[path = "../x86_common/timer.rs"]
+pub mod timer;
 
-/*
- * This method is responsible for discovering the available memory
- * on the system.
- */
-unsafe fn discover_memory() -> usize
-{
-    /* External variables, where we saved the multiboot data.
-     * These are defined in arch/amd64/start.S
-     */
-    extern {
-        static mboot_sig: u32;
-        static mboot_ptr: u32;
-    }
+// Task context switching
+#[path = "../x86_common/context.rs"]
+pub mod context;
 
-    if mboot_sig != 0x2badb002 {
-        panic!("Invalid Multiboot signature: 0x{:x}", mboot_sig);
-    }
+// FPU and SIMD state
+#[path = "../x86_common/fpu.rs"]
+mod fpu;
 
-    log!("Multiboot pointer: 0x{:016x}, Signature: 0x{:08x}", mboot_ptr, mboot_sig);
+// Accessing user memory
+#[path = "../x86_common/uaccess.rs"]
+pub mod uaccess;
 
-    /* First, try the Multiboot-provided memory map. */
-    let mb = Multiboot::new(mboot_ptr as multiboot::PAddr, paddr_to_slice).unwrap();
+// System calls and user mode
+#[path = "./syscall.rs"]
+pub mod syscall;
 
-    /* Print the memory map. */
-    for area in mb.memory_regions().unwrap() {
-        log!("[0x{:x} - 0x{:x}] (length: {} Kb): {:?}",
-            area.base_address(), area.base_address() + area.length(),
-            area.length() / 1024, area.memory_type());
-    }
+// The page table format
+#[path = "./paging.rs"]
+pub mod paging;
 
-    /* Calculate the available memory that's in "high memory". */
-    return (mb.upper_memory_bound().unwrap() * 1024) as usize;
-}
+// ACPI table discovery
+#[path = "../x86_common/acpi.rs"]
+mod acpi;
 
-fn copy_smp_into_to(target_addr: usize, start: usize, end: usize)
-{
-    /* verify that the code there is correct */
-    unsafe {
-        let verify_ptr: *const u32 = (target_addr + ::arch::KERNEL_BASE) as *const u32;
-        let data: u32 = *verify_ptr;
-        log!("Data at 0x{:016x} is 0x{:08x}", target_addr, data);
-    }
-}
+// PCI configuration space access
+#[path = "../x86_common/pci.rs"]
+pub mod pci;
 
-unsafe fn find_mp_tables() -> usize
-{
-    let base_mem_location: *const u16 = (KERNEL_BASE + 0x413) as *const u16;
-    let base_mem_size: u16 = *base_mem_location;
-    let base_mem_end: usize = (base_mem_size as usize) << 10;
-    let search_mem_start: usize = (base_mem_end - (2 << 10));
-    log!("Base memory size: {} KiB => [0x0 - 0x{:x}]", base_mem_size, base_mem_end);
-
-    let mut search_now: *const u32 = (KERNEL_BASE + search_mem_start) as *const u32;
-    loop {
-        if (search_now as usize) >= base_mem_end {
-            log!("Didn't find MP tables in base memory");
-            break;
-        }
-
-        if *search_now == 0x5F504D5F {
-            log!("found MP tables at 0x{:016x}", search_now as usize);
-            return 0;
-        }
-
-        search_now = ((search_now as usize) + 16) as *const u32;
-    }
+// Device interrupt vectors and MSI messages
+#[path = "../x86_common/vectors.rs"]
+pub mod vectors;
 
-    search_now = (KERNEL_BASE + 0x9fc00) as *const u32;
-    loop {
-        if (search_now as usize) >= (KERNEL_BASE + 0x9ffff) {
-            log!("Didn't find MP tables in EBDA memory");
-            break;
-        }
+// High precision event timer
+#[path = "../x86_common/hpet.rs"]
+mod hpet;
 
-        if *search_now == 0x5F504D5F {
-            log!("found MP tables at 0x{:016x} in EBDA", search_now as usize);
-            return 0;
-        }
+// CMOS real time clock
+#[path = "../x86_common/rtc.rs"]
+pub mod rtc;
 
-        search_now = ((search_now as usize) + 16) as *const u32;
-    }
+// Clocksource selection
+#[path = "../x86_common/clock.rs"]
+pub mod clock;
 
-    extern {
-        static SMP_AP_START: u32;
-        static SMP_AP_END: u32;
-    }
-    let smp_ap_start_addr: usize = unsafe { mem::transmute(&SMP_AP_START) };
-    let smp_ap_end_addr: usize = unsafe { mem::transmute(&SMP_AP_END) };
-    log!("SMP_AP_START: [0x{:016x} - 0x{:016x}]",
-         smp_ap_start_addr, smp_ap_end_addr);
-
-    /* FIXME: this is not needed */
-    copy_smp_into_to(0xA000, smp_ap_start_addr, smp_ap_end_addr);
-
-    search_now = (KERNEL_BASE + 0xa000) as *const u32;
-    loop {
-        if (search_now as usize) >= (KERNEL_BASE + 0xfffff) {
-            log!("Didn't find MP tables in ROM memory");
-            break;
-        }
-
-        if *search_now == 0x5F504D5F {
-            log!("found MP tables at 0x{:016x} in ROM", search_now as usize);
-            return search_now as usize;
-        }
-
-        search_now = ((search_now as usize) + 16) as *const u32;
-    }
-    0
-}
+// Processor enumeration and AP startup
+#[path = "../x86_common/mp.rs"]
+mod mp;
+
+// Logging code
+#[path = "../../logging.rs"]
+mod logging;
+
+/* Some globals. */
+pub const KERNEL_BASE: usize = 0xFFFFFFFF80000000;
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+use boot::BootInfo;
+use mm::layout::phys_to_virt;
+use mm::pmm::FrameAllocator;
+
+pub use self::mp::{Processor, with_processors, processor, cpu_count, start_aps, send_ipi};
+pub use self::idt::{InterruptFrame, InterruptHandler, register_handler,
+                    RESCHED_VECTOR, CALL_FUNCTION_VECTOR};
 
 pub unsafe fn set_page_directory(pml4: usize)
 {
@@ -177,157 +136,71 @@ pub unsafe fn set_page_directory(pml4: usize)
     asm!("mov $0, %cr3" :: "r" (pml4) : "memory")
 }
 
-pub unsafe fn get_page_directory<'a>() -> &'a mut [Pml4Entry]
+/* Physical address of the page tables in use */
+pub fn page_directory_addr() -> usize
 {
-    let mut value: u64;
-    asm!("mov %cr3, $0" :"=r" (value) :: "memory");
-    value = value + ::arch::KERNEL_BASE as u64;
-    slice::from_raw_parts_mut(mem::transmute(value), 512)
+    let value: usize;
+    unsafe { asm!("mov %cr3, $0" : "=r"(value) ::: "volatile"); }
+    value & !0xFFF
 }
 
-pub fn early_init() -> (usize, usize)
+pub fn early_init() -> BootInfo
 {
     log!("Initializing AMD64 processors");
-    let available_memory: usize;
-    let mp_table_location: *const MPFloatingPointer;
 
-    /* Discover the available memory. */
+    /* Find out what the CPU can do, everything below may ask. */
+    cpu::init_bsp();
+    fpu::init_cpu(true);
+    cpu::enable_protection(true);
+    syscall::init_cpu();
+
+    /* Set up the per-CPU area of the BSP before anything needs it. */
     unsafe {
-        available_memory = discover_memory();
+        percpu::init_bsp();
     }
 
-    (available_memory, PAGE_SIZE)
-}
-
-fn enumerate_processors(fma: &mut FrameAllocator) -> usize
-{
-    let mp_ptr_location = unsafe { find_mp_tables() as *const MPFloatingPointer };
-    let mp_ptr: MPFloatingPointer;
-
-    // extern {
-        // static smp_ap_booted: u16;
-    // }
-
-    if mp_ptr_location as usize != 0 {
-        unsafe {
-            mp_ptr = *mp_ptr_location;
-        }
-    } else {
-        log!("MP Table not found, assuming 1 CPU");
-        return 1;
+    /* Catch exceptions from here on. */
+    idt::init();
+    unsafe {
+        idt::load();
+        pic::disable();
     }
 
-    if (mp_ptr.is_valid()) {
-        log!("Enumerating available processors...");
-        let mut processors = 0;
-        unsafe {
-            let mp_hdr_loc = KERNEL_BASE + mp_ptr.physical_address_pointer as usize;
-            let mp_hdr: MPConfigurationTableHeader = *(mp_hdr_loc as *const MPConfigurationTableHeader);
-            log!("MP header has {} entries, at 0x{:016x}, LAPIC at 0x{:016x}",
-                 mp_hdr.entry_count, mp_hdr_loc, mp_hdr.local_apic_addr);
-
-            let lapic: apic::LAPIC = apic::LAPIC::new(fma, mp_hdr.local_apic_addr as usize, 0);
-
-            let mp_hdr_iter = mp_hdr.iter(mp_hdr_loc);
-
-            /* Count the number of processors */
-            processors = mp_hdr_iter.clone().map(|x|
-                                if x.code == MPEntryCode::Processor {
-                                    1
-                                } else {
-                                    0
-                                }).sum();
-
-            /* Allocate the stack frames for the APs */
-            for i in 0..processors {
-                processor_list.push(Processor {
-                    stack_frame: fma.allocate_frame().frame_addr(),
-                    id: i,
-                    apic_id: 0xffffffffffffffff,
-                });
-            }
-
-            let mut p_c = 0;
-            let mut b_c = 0;
-            let mut __did_an_ap_boot: u32;
-            let mut res: u32 = 0;
-
-            /* Wake up the APs */
-            for i in mp_hdr_iter {
-                if i.code == MPEntryCode::Processor {
-                    p_c = p_c + 1;
-                }
-                asm!("mfence; movl unique_stack_id, $0; movl did_an_ap_boot, $1":"=r"(res),"=r"(__did_an_ap_boot));
-                log!("p_c = {} b_c = {} res = {} daab = {}", p_c, b_c, res, __did_an_ap_boot);
-                //if p_c != 0 && p_c % 4 == 0 && i.code == MPEntryCode::Processor {
-                if i.code == MPEntryCode::Processor && __did_an_ap_boot != b_c {
-                    log!("Reached maximum parallel boot, waiting...");
-                    loop {
-                        asm!("mfence; movl unique_stack_id, $0; movl did_an_ap_boot, $1":"=r"(res),"=r"(__did_an_ap_boot));
-                        //log!("Parallel boot hang: usi: {} daab: {}", res, __did_an_ap_boot);
-                        if __did_an_ap_boot == b_c /* && res == 0 */ {
-                            //asm!("movl $$0x0, did_an_ap_boot");
-                            log!("Parallel boot hang done.");
-                            break;
-                        }
-                    }
-                }
-                if i.code == MPEntryCode::Processor {
-                    let proc = i.get_processor_entry().unwrap();
-                    if proc.lapic_id == 0 {
-                        continue;
-                    }
-                    lapic.send_init_to(proc.lapic_id);
-                    let mut wait = 400000;
-                    loop {
-                        wait = wait - 1;
-                        if wait == 0 {
-                            break;
-                        }
-                    }
-                    lapic.send_sipi_to(proc.lapic_id, 0xA);
-                    let mut wait = 400000;
-                    loop {
-                        wait = wait - 1;
-                        if wait == 0 {
-                            break;
-                        }
-                    }
-                    b_c += 1;
-                }
-            }
-        };
-        log!("Found {} processors in total", processors);
-        // unsafe {
-            // let smp_ap_booted_addr: usize = mem::transmute(&smp_ap_booted);
-            // let smp_ap_booted_ptr: *const u16 = (KERNEL_BASE + smp_ap_booted_addr) as *const u16;
-            // log!("{} processors booted", ptr::read_volatile(smp_ap_booted_ptr));
-        // }
-        return processors;
-    } else {
-        log!("MP table was invalid, assuming 1 CPU");
-        return 1;
-    }
+    /* Find out what the bootloader knows, including the available memory. */
+    unsafe { discover::discover_boot_info() }
 }
 
-pub unsafe fn new_cpu_init(cpu_id: usize)
+pub unsafe fn new_cpu_init(boot_id: usize)
 {
-    let _cpu_id: u32 = cpu_id as u32;
-
-    /* save the current CPU id in the %fs register of the AP */
-    asm!("xor %edx, %edx; movl $$0xC0000103, %ecx; wrmsr"
-         ::"{rax}"(_cpu_id):"rdx","rcx");
-
     /* synchronize page tables */
     ::arch::set_page_directory(::mm::vmm::KERNEL_PAGE_DIRECTORY);
 
-    /* get the processor structure for this AP */
-    let this_ap = processor_list.iter().filter(|x| x.id == cpu_id).next().unwrap();
+    /*
+     * get the processor structure for this AP. The BSP has left it for us,
+     * the processor list can't be read before we have a per-CPU area.
+     */
+    let apic_id = cpu::initial_apic_id() as usize;
+    let this_ap = mp::ap_boot_processor();
+    assert!(this_ap.apic_id == apic_id,
+            "AP with APIC id {} came up instead of {}", apic_id, this_ap.apic_id);
+    let cpu_id = this_ap.id;
+    log!("AP {} has APIC id {}, it is CPU {}", boot_id, apic_id, cpu_id);
+    cpu::check_ap(cpu_id);
+    fpu::init_cpu(false);
+    cpu::enable_protection(false);
+    syscall::init_cpu();
+
+    /* point %gs at the per-CPU area of this AP, it survives the stack switch */
+    percpu::init_area(this_ap.percpu_area as *mut percpu::PerCpuArea, cpu_id);
+
+    /* switch from the boot GDT to this AP's own GDT and TSS */
+    gdt::load(this_ap.cpu_tables);
+    idt::load();
 
     /* allocate a new stack */
-    let frame = this_ap.stack_frame + ::arch::PAGE_SIZE + ::arch::KERNEL_BASE;
+    let frame = phys_to_virt(this_ap.stack_frame + ::arch::PAGE_SIZE);
 
-    log!("AP {} about to switch stack to 0x{:016x}", cpu_id, frame);
+    log!("AP {} about to switch stack to frame 0x{:x}", cpu_id, this_ap.stack_frame);
 
     /* switch stacks */
     asm!("movq %rax, %rsp;
@@ -338,35 +211,29 @@ pub unsafe fn new_cpu_init(cpu_id: usize)
 #[no_mangle]
 pub unsafe fn new_cpu_init_tail()
 {
-    let cpu_id: u32;
-
-    //asm!("movl %fs, $0":"=r"(cpu_id));
-    asm!("rdtscp":"={ecx}"(cpu_id)::"eax", "edx");
-
     /* get back the AP id */
+    let cpu_id = percpu::cpu_id();
     log!("Switched stack for AP {}", cpu_id);
 
-    /* get the procesor structure */
-    let this_ap = processor_list.iter().filter(|x|
-                    x.id == cpu_id as usize
-                    ).next().unwrap();
+    /* enable the LAPIC of this AP */
+    percpu::this_cpu_area().lapic = Some(apic::LAPIC::for_this_cpu(mp::lapic_addr()));
 
-    /* TODO: allocate percpu storage */
-    //::arch::allocate_percpu_storage<Processor>(cpu_id);
-
-    /* TODO: set percpu storage in %gs */
+    /* start ticking, the BSP has calibrated the TSC by now */
+    ::timer::init_cpu();
 
     /* signal to the BSP that we are now done */
     asm!("lock decl unique_stack_id; lock incl did_an_ap_boot");
 
-    loop {}
+    ::sched::start();
 }
 
-//pub fn allocate_percpu_storage<T>(cpu_id: u32) -> PerCpu<T>
-//{
-//}
-
 pub fn late_init(fma: &mut FrameAllocator)
 {
-    enumerate_processors(fma);
+    mp::enumerate_processors(fma);
+    vectors::init();
+
+    /* switch the BSP from the boot GDT to its own GDT and TSS */
+    unsafe {
+        gdt::load(processor(0).unwrap().cpu_tables);
+    }
 }
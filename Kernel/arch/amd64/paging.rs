@@ -0,0 +1,41 @@
+/*
+ * Four level paging: PML4, PDPT, page directory and page table, 512
+ * entries of 64 bits each. mm::vmm walks the tables, this describes them.
+ */
+
+pub type Entry = u64;
+
+pub const LEVELS: usize = 4;
+pub const ENTRIES: usize = 512;
+
+/* The lowest bit of the virtual address indexing each level, root first */
+pub const LEVEL_SHIFTS: [usize; LEVELS] = [39, 30, 21, 12];
+
+/* Entries of the root table, and the first one of the kernel half */
+pub const ROOT_ENTRIES: usize = 512;
+pub const KERNEL_ROOT_START: usize = 256;
+
+/* The CPU reads PML4 entries through the cache like any other */
+pub const ROOT_ENTRIES_CACHED: bool = false;
+
+pub const PRESENT: Entry = 1 << 0;
+pub const WRITABLE: Entry = 1 << 1;
+pub const USER: Entry = 1 << 2;
+/* A 2MiB or 1GiB page rather than a table, in PDEs and PDPTEs */
+pub const HUGE: Entry = 1 << 7;
+/* Page-level cache disable and write-through, for device registers */
+pub const NO_CACHE: Entry = (1 << 4) | (1 << 3);
+
+pub const ADDRESS_MASK: Entry = 0x000F_FFFF_FFFF_F000;
+
+/* start.S enables EFER.NXE before paging, so the bit is always usable */
+pub fn no_execute() -> Entry
+{
+    1 << 63
+}
+
+/* The flags an entry pointing to a table may have at `level` */
+pub fn table_flags(_level: usize) -> Entry
+{
+    WRITABLE | USER
+}
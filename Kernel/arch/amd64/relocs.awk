@@ -0,0 +1,93 @@
+#
+# Turn the relocations `readelf -rW` lists for a kernel linked with
+# --emit-relocs into the table start.S applies when it moves the image
+# (KASLR). The image is linked at KERNEL_BASE and the Rust code is built
+# with the static relocation model and the kernel code model (see
+# target.json), so every absolute address in it has a relocation.
+#
+# Two lists of physical addresses of 32-bit words come out, separated by a
+# zero word:
+#  - words to add the slide to: absolute addresses of the high mapping,
+#    and calls from the low .init code to it
+#  - words to take it off: calls from the high mapping to the .init code
+# 64-bit addresses only need their low half fixed, the slide is less than
+# 1GiB and the image is in the bottom half of the top 2GiB, so it never
+# carries. Addresses in the low mapping don't move.
+#
+
+BEGIN {
+	KERNEL_BASE_HIGH = "ffffffff"
+	SPLIT = 2147483648	# 0x80000000, the low half of KERNEL_BASE
+	nr_add = 0
+	nr_sub = 0
+}
+
+function hex(s,    i, c, v) {
+	v = 0
+	s = tolower(s)
+	sub(/^0x/, "", s)
+	for (i = 1; i <= length(s); i++) {
+		c = index("0123456789abcdef", substr(s, i, 1))
+		if (c == 0)
+			return -1
+		v = v * 16 + c - 1
+	}
+	return v
+}
+
+# The offset of address `s` from KERNEL_BASE, or -1 if it's below it
+function high(s,    v) {
+	if (length(s) == 16 && substr(s, 1, 8) == KERNEL_BASE_HIGH) {
+		v = hex(substr(s, 9)) - SPLIT
+		if (v >= 0)
+			return v
+	}
+	return -1
+}
+
+# Where the relocation points to: the symbol's value plus the addend
+function target_high(value, sign, addend,    t) {
+	t = high(value)
+	if (t < 0)
+		return 0
+	if (sign == "-")
+		t -= hex(addend)
+	else
+		t += hex(addend)
+	return t >= 0 && t < SPLIT
+}
+
+/^Relocation section/ {
+	# The relocations of debug information don't end up in memory
+	section = $3
+	gsub(/'/, "", section)
+	wanted = section !~ /^\.rela\.debug/
+	next
+}
+
+# Relocations without a symbol are plain numbers, which don't move
+wanted && NF >= 7 && $3 ~ /^R_X86_64_/ {
+	location = high($1)
+	phys = location >= 0 ? location : hex($1)
+	to_high = target_high($4, $6, $7)
+
+	if ($3 == "R_X86_64_64" || $3 == "R_X86_64_32S") {
+		if (to_high)
+			add[nr_add++] = phys
+	} else if ($3 == "R_X86_64_PC32" || $3 == "R_X86_64_PLT32") {
+		if (location < 0 && to_high)
+			add[nr_add++] = phys
+		else if (location >= 0 && !to_high)
+			sub_[nr_sub++] = phys
+	}
+}
+
+END {
+	print "/* Generated by arch/amd64/relocs.awk, see start.S */"
+	print ".section .kaslr_relocs, \"a\""
+	for (i = 0; i < nr_add; i++)
+		printf "\t.long 0x%x\n", add[i]
+	print "\t.long 0"
+	for (i = 0; i < nr_sub; i++)
+		printf "\t.long 0x%x\n", sub_[i]
+}
@@ -0,0 +1,64 @@
+use super::gdt::{KERNEL_CS, USER_CS32};
+use super::idt::InterruptFrame;
+use super::irq;
+use super::msr;
+use super::uaccess::USER_END;
+
+/* EFER.SCE enables syscall/sysret */
+const EFER_SCE: u64 = 1 << 0;
+
+/* RFLAGS bits cleared on syscall: TF, IF, DF, NT and AC */
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 14) | (1 << 18);
+
+extern "C" {
+    /* Defined in arch/amd64/syscall.S */
+    fn syscall_entry();
+    fn enter_user(rip: usize, rsp: usize) -> !;
+}
+
+/* Point the syscall instruction of the current CPU at syscall_entry */
+pub fn init_cpu()
+{
+    unsafe {
+        msr::wrmsr(msr::IA32_STAR,
+                   ((USER_CS32 as u64) << 48) | ((KERNEL_CS as u64) << 32));
+        msr::wrmsr(msr::IA32_LSTAR, syscall_entry as usize as u64);
+        msr::wrmsr(msr::IA32_FMASK, SYSCALL_RFLAGS_MASK);
+        msr::wrmsr(msr::IA32_EFER, msr::rdmsr(msr::IA32_EFER) | EFER_SCE);
+    }
+}
+
+/* Called by syscall_entry with interrupts disabled, on the task's kernel stack */
+#[no_mangle]
+pub extern "C" fn syscall_dispatch(frame: &mut InterruptFrame)
+{
+    unsafe { irq::enable(); }
+
+    let args = [frame.rdi as usize, frame.rsi as usize, frame.rdx as usize,
+                frame.r10 as usize, frame.r8 as usize, frame.r9 as usize];
+    frame.rax = ::syscall::dispatch(frame.rax as usize, &args) as u64;
+
+    /*
+     * sysret with a non-canonical rip faults in ring 0 on the user's
+     * stack, so a task that managed to get one is not returned to.
+     */
+    if frame.rip as usize >= USER_END {
+        log!("task {} would return to non-canonical rip 0x{:x}",
+             ::sched::current_id(), frame.rip);
+        ::sched::exit();
+    }
+
+    irq::disable();
+}
+
+/*
+ * Leave the kernel for good and run the current task in user mode at
+ * `rip`, with its stack pointer at `rsp`. Its address space must already
+ * be loaded.
+ */
+pub fn jump_to_user(rip: usize, rsp: usize) -> !
+{
+    assert!(rip < USER_END && rsp <= USER_END, "jumping to a kernel address");
+    irq::disable();
+    unsafe { enter_user(rip, rsp) }
+}
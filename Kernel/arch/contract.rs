@@ -0,0 +1,195 @@
+/*
+ * Rust BareBones OS
+ * - By John Hodge (Mutabah/thePowersGang)
+ *
+ * arch/contract.rs
+ * - What every architecture port provides
+ *
+ * == LICENCE ==
+ * This code has been put into the public domain, there are no restrictions on
+ * its use, and the author takes no liability.
+ */
+
+/*
+ * main.rs picks one of arch/<arch>/mod.rs as the `arch` module. Generic code
+ * uses only the items below, so a new port implements these and nothing
+ * else; everything else in a port (GDTs, APICs, MSRs, ...) stays private to
+ * it. check() names each item with the type generic code expects, so a port
+ * that lacks one or gets its signature wrong fails to build.
+ *
+ * Constants and boot
+ *   KERNEL_BASE, PAGE_SHIFT, PAGE_SIZE
+ *   kernel_slide          - a usize symbol, how far start.S moved the image
+ *                           up from KERNEL_BASE (see mm/layout.rs)
+ *   early_init()          - first thing kmain calls, returns the BootInfo
+ *   late_init(fma)        - once the heap works, before the other CPUs start
+ *   start_aps()           - bring up the other CPUs, which enter kmain_ap
+ *   new_cpu_init(id)      - called by kmain_ap, never returns
+ *
+ * Paging
+ *   paging                - the page table format mm::vmm walks: Entry (an
+ *                           unsigned integer), LEVELS, ENTRIES, LEVEL_SHIFTS,
+ *                           ROOT_ENTRIES, KERNEL_ROOT_START,
+ *                           ROOT_ENTRIES_CACHED, the PRESENT, WRITABLE, USER,
+ *                           HUGE and NO_CACHE bits, ADDRESS_MASK,
+ *                           no_execute() and table_flags(level)
+ *   set_page_directory(root), page_directory_addr()
+ *
+ * Interrupts
+ *   irq                   - enable, disable, enabled, save_and_disable,
+ *                           restore and enable_and_halt
+ *   InterruptFrame, InterruptHandler, register_handler(vector, handler)
+ *   RESCHED_VECTOR, CALL_FUNCTION_VECTOR - for sched and smp
+ *
+ * Timers and clocks
+ *   timer                 - init, init_cpu(tick_ns) and program(deadline),
+ *                           which arms this CPU's timer in clock::read_ns
+ *                           nanoseconds and calls ::timer::tick when it fires
+ *   clock                 - init(fma) and read_ns()
+ *   rtc                   - read(), the wall-clock time at boot
+ *
+ * CPU identification and IPIs
+ *   percpu                - PerCpu<T>, cpu_id, try_cpu_id, current_task,
+ *                           set_current_task, irq_depth, set_irq_depth and
+ *                           in_interrupt
+ *   cpu_count(), send_ipi(cpu, vector)
+ *   cpu::random_u64()     - for KASLR and AT_RANDOM, need not be strong
+ *
+ * PCI
+ *   pci                   - init(), root_buses(), the (segment, bus) pairs
+ *                           enumeration starts from, and read(addr, offset,
+ *                           size) and write(addr, offset, size, value) of
+ *                           configuration space, all ones where nothing is
+ *
+ * Device interrupts
+ *   vectors               - allocate(cpu, count), a power of two of aligned
+ *                           vectors on a CPU that MSIs can reach,
+ *                           free(cpu, vector),
+ *                           bind(cpu, vector, handler, data), unbind and
+ *                           msi_message(cpu, vector), the address and data
+ *                           an MSI writes to raise it. Handlers run in an
+ *                           RCU read-side critical section, so one may
+ *                           still be running after unbind or free until a
+ *                           grace period has passed
+ *
+ * Debug console
+ *   debug                 - puts and putb, usable from the first instruction
+ *                           and with interrupts disabled
+ *
+ * Tasks and user space
+ *   context               - FpuState, switch_context, init_stack, save_fpu,
+ *                           restore_fpu and set_kernel_stack
+ *   uaccess               - USER_END, is_user_range, copy_from_user and
+ *                           copy_to_user
+ *   syscall::jump_to_user(entry, stack), calling ::syscall::dispatch for
+ *                           every system call
+ */
+
+use alloc::Vec;
+
+use boot::BootInfo;
+use errno::Errno;
+use mm::pmm::FrameAllocator;
+use pci::Address;
+use arch;
+use arch::{paging, irq, timer, clock, rtc, percpu, cpu, pci, vectors, debug, context, uaccess, syscall};
+
+#[allow(dead_code)]
+fn check()
+{
+    /* Constants and boot */
+    let _: usize = arch::KERNEL_BASE;
+    let _: usize = arch::PAGE_SHIFT;
+    let _: usize = arch::PAGE_SIZE;
+    let _: fn() -> BootInfo = arch::early_init;
+    let _: fn(&mut FrameAllocator) = arch::late_init;
+    let _: fn() = arch::start_aps;
+    let _: unsafe fn(usize) = arch::new_cpu_init;
+
+    /* Paging */
+    let _: usize = paging::LEVELS;
+    let _: usize = paging::ENTRIES;
+    let _: [usize; paging::LEVELS] = paging::LEVEL_SHIFTS;
+    let _: usize = paging::ROOT_ENTRIES;
+    let _: usize = paging::KERNEL_ROOT_START;
+    let _: bool = paging::ROOT_ENTRIES_CACHED;
+    let _: [paging::Entry; 6] = [paging::PRESENT, paging::WRITABLE, paging::USER,
+                                 paging::HUGE, paging::NO_CACHE, paging::ADDRESS_MASK];
+    let _: fn() -> paging::Entry = paging::no_execute;
+    let _: fn(usize) -> paging::Entry = paging::table_flags;
+    let _: unsafe fn(usize) = arch::set_page_directory;
+    let _: fn() -> usize = arch::page_directory_addr;
+
+    /* Interrupts */
+    let _: unsafe fn() = irq::enable;
+    let _: fn() = irq::disable;
+    let _: fn() -> bool = irq::enabled;
+    let _: fn() -> usize = irq::save_and_disable;
+    let _: fn(usize) = irq::restore;
+    let _: unsafe fn() = irq::enable_and_halt;
+    let _: arch::InterruptHandler = dummy_handler;
+    let _: fn(u8, arch::InterruptHandler) = arch::register_handler;
+    let _: [u8; 2] = [arch::RESCHED_VECTOR, arch::CALL_FUNCTION_VECTOR];
+
+    /* Timers and clocks */
+    let _: fn() = timer::init;
+    let _: fn(u64) = timer::init_cpu;
+    let _: fn(u64) = timer::program;
+    let _: fn() -> u64 = clock::read_ns;
+    let _: fn() -> u64 = || rtc::read().to_unix();
+    let _ = |fma: &mut FrameAllocator| { clock::init(fma); };
+
+    /* CPU identification and IPIs */
+    let _: percpu::PerCpu<usize> = percpu::PerCpu::new();
+    let _: fn() -> usize = percpu::cpu_id;
+    let _: fn() -> Option<usize> = percpu::try_cpu_id;
+    let _: fn() -> usize = percpu::current_task;
+    let _: fn(usize) = percpu::set_current_task;
+    let _: fn() -> usize = percpu::irq_depth;
+    let _: fn(usize) = percpu::set_irq_depth;
+    let _: fn() -> bool = percpu::in_interrupt;
+    let _: fn() -> usize = arch::cpu_count;
+    let _: fn(usize, u8) = arch::send_ipi;
+    let _: fn() -> u64 = cpu::random_u64;
+
+    /* PCI */
+    let _: fn() = pci::init;
+    let _: fn() -> Vec<(u16, u8)> = pci::root_buses;
+    let _: fn(Address, u16, usize) -> u32 = pci::read;
+    let _: fn(Address, u16, usize, u32) = pci::write;
+
+    /* Device interrupts */
+    let _: fn(Option<usize>, usize) -> Option<(usize, u8)> = vectors::allocate;
+    let _: fn(usize, u8) = vectors::free;
+    let _: fn(usize, u8, vectors::IrqHandler, usize) -> bool = vectors::bind;
+    let _: fn(usize, u8) = vectors::unbind;
+    let _: fn(usize, u8) -> (u64, u32) = vectors::msi_message;
+    let _: vectors::IrqHandler = dummy_irq_handler;
+
+    /* Debug console */
+    let _: unsafe fn(&str) = debug::puts;
+    let _: unsafe fn(u8) = debug::putb;
+
+    /* Tasks and user space */
+    let _: fn() -> context::FpuState = context::FpuState::new;
+    let _: unsafe extern "C" fn(*mut usize, usize) = context::switch_context;
+    let _: fn(usize, extern "C" fn() -> !) -> usize = context::init_stack;
+    let _: unsafe fn(&mut context::FpuState) = context::save_fpu;
+    let _: unsafe fn(&context::FpuState) = context::restore_fpu;
+    let _: fn(usize) = context::set_kernel_stack;
+    let _: usize = uaccess::USER_END;
+    let _: fn(usize, usize) -> bool = uaccess::is_user_range;
+    let _: fn(&mut [u8], usize) -> Result<(), Errno> = uaccess::copy_from_user;
+    let _: fn(usize, &[u8]) -> Result<(), Errno> = uaccess::copy_to_user;
+    let _: fn(usize, usize) -> ! = syscall::jump_to_user;
+}
+
+#[allow(dead_code)]
+fn dummy_handler(_frame: &mut arch::InterruptFrame)
+{
+}
+
+#[allow(dead_code)]
+fn dummy_irq_handler(_data: usize)
+{
+}
@@ -0,0 +1,169 @@
+use core::mem;
+use alloc::boxed::Box;
+
+use mm::pmm::FrameAllocator;
+use super::percpu;
+
+/*
+ * Segment selectors. All segments are flat except the per-CPU one, whose
+ * base is the CPU's per-CPU area, see arch/x86_common/percpu.rs. The boot
+ * GDT in start.S has the same layout.
+ */
+pub const KERNEL_CS: u16 = 0x08;
+pub const KERNEL_DS: u16 = 0x10;
+pub const USER_CS: u16 = 0x18 | 3;
+pub const USER_DS: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+pub const PERCPU_SELECTOR: u16 = 0x30;
+
+/* Null, kernel code/data, user code/data, the TSS and the per-CPU segment */
+const GDT_ENTRIES: usize = 7;
+const PERCPU_ENTRY: usize = PERCPU_SELECTOR as usize / 8;
+
+/*
+ * The 32-bit TSS. Only the ring 0 stack is used, there is no hardware
+ * task switching, so a double fault on a bad kernel stack is fatal
+ * without a word: that would need a task gate and a second TSS.
+ */
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    link: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    unused: [u32; 22],
+    reserved: u16,
+    pub iomap_base: u16,
+}
+
+/* The GDT and TSS of a single CPU */
+#[repr(C, align(16))]
+pub struct CpuTables {
+    gdt: [u64; GDT_ENTRIES],
+    pub tss: TaskStateSegment,
+}
+
+#[repr(C, packed)]
+struct DescriptorPointer {
+    limit: u16,
+    base: u32,
+}
+
+/* Descriptor flags: 32-bit segment, only for code and data */
+const FLAG_32BIT: u64 = 0x4;
+
+/* Encode a byte granular descriptor with the given access byte and flags */
+fn descriptor(base: usize, limit: usize, access: u64, flags: u64) -> u64
+{
+    let base = base as u64;
+    let limit = limit as u64;
+
+    (limit & 0xFFFF)
+        | ((base & 0xFFFFFF) << 16)
+        | (access << 40)
+        | (((limit >> 16) & 0xF) << 48)
+        | (flags << 52)
+        | (((base >> 24) & 0xFF) << 56)
+}
+
+/* The per-CPU segment for the area at `base`: present kernel data, just the area */
+fn percpu_descriptor(base: usize) -> u64
+{
+    descriptor(base, mem::size_of::<percpu::PerCpuArea>() - 1, 0x92, FLAG_32BIT)
+}
+
+/* Build the GDT and TSS for a CPU */
+pub fn allocate(_fma: &mut FrameAllocator) -> usize
+{
+    let mut tables = Box::new(CpuTables {
+        gdt: [
+            0,
+            0x00CF9A000000FFFF,     /* 0x08: Kernel Code */
+            0x00CF92000000FFFF,     /* 0x10: Kernel Data */
+            0x00CFFA000000FFFF,     /* 0x18: User Code */
+            0x00CFF2000000FFFF,     /* 0x20: User Data */
+            0,                      /* 0x28: TSS, filled in below */
+            0,                      /* 0x30: per-CPU, filled in by `load` */
+        ],
+        tss: TaskStateSegment {
+            link: 0,
+            esp0: 0,
+            ss0: KERNEL_DS as u32,
+            unused: [0; 22],
+            reserved: 0,
+            /* No IO permission bitmap */
+            iomap_base: mem::size_of::<TaskStateSegment>() as u16,
+        },
+    });
+
+    /* Present, 32-bit available TSS */
+    let tss = &tables.tss as *const TaskStateSegment as usize;
+    tables.gdt[5] = descriptor(tss, mem::size_of::<TaskStateSegment>() - 1, 0x89, 0);
+
+    Box::into_raw(tables) as usize
+}
+
+/*
+ * Load the tables built by `allocate` on the current CPU, reload the
+ * segment registers and the task register.
+ *
+ * The per-CPU segment is copied from the current area, so `percpu::init_area`
+ * must have run on this CPU.
+ */
+pub unsafe fn load(tables: usize)
+{
+    let tables = &mut *(tables as *mut CpuTables);
+    let area = percpu::this_cpu_area() as *mut percpu::PerCpuArea as usize;
+    tables.gdt[PERCPU_ENTRY] = percpu_descriptor(area);
+
+    let ptr = DescriptorPointer {
+        limit: (mem::size_of::<[u64; GDT_ENTRIES]>() - 1) as u16,
+        base: tables.gdt.as_ptr() as u32,
+    };
+
+    asm!("lgdt ($0)" :: "r"(&ptr) : "memory" : "volatile");
+
+    /* Reload %cs with a far jump, then the data segments and %gs */
+    asm!("ljmp $0, $$1f
+          1:
+          movw $1, %ax
+          movw %ax, %ds
+          movw %ax, %es
+          movw %ax, %ss
+          movw $2, %ax
+          movw %ax, %gs"
+         :: "i"(KERNEL_CS), "i"(KERNEL_DS), "i"(PERCPU_SELECTOR) : "eax", "memory" : "volatile");
+
+    asm!("ltr $0" :: "r"(TSS_SELECTOR) :: "volatile");
+
+    percpu::this_cpu_area().tss = &mut tables.tss as *mut TaskStateSegment as usize;
+}
+
+/*
+ * Point the per-CPU segment of the GDT in use at `base` and load it into
+ * %gs. Before `load` that is the boot GDT of start.S, which the APs share,
+ * but they are started one at a time and each loads its own GDT before the
+ * next one comes up.
+ */
+pub unsafe fn set_percpu_base(base: usize)
+{
+    let mut ptr = DescriptorPointer { limit: 0, base: 0 };
+    asm!("sgdt ($0)" :: "r"(&mut ptr) : "memory" : "volatile");
+    let gdt = ptr.base as *mut u64;
+    *gdt.offset(PERCPU_ENTRY as isize) = percpu_descriptor(base);
+
+    asm!("movw $0, %gs" :: "r"(PERCPU_SELECTOR) : "memory" : "volatile");
+}
+
+/*
+ * Set the stack the CPU switches to on an interrupt from ring 3. The
+ * scheduler updates this to the kernel stack of the task it switches to.
+ */
+pub fn set_kernel_stack(esp: usize)
+{
+    unsafe {
+        let tss = percpu::this_cpu_area().tss as *mut TaskStateSegment;
+        if !tss.is_null() {
+            (*tss).esp0 = esp as u32;
+        }
+    }
+}
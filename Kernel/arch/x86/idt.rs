@@ -0,0 +1,223 @@
+use core::mem;
+
+use super::gdt;
+use super::percpu;
+use super::syscall;
+use super::uaccess;
+use super::vectors;
+
+/* Vectors used by the kernel */
+pub const TIMER_VECTOR: u8 = 0x20;
+pub const RESCHED_VECTOR: u8 = 0x21;
+pub const CALL_FUNCTION_VECTOR: u8 = 0x22;
+pub const SYSCALL_VECTOR: u8 = 0x80;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/* Vectors below this are CPU exceptions */
+const FIRST_IRQ_VECTOR: usize = 32;
+
+const NMI_VECTOR: usize = 2;
+const GENERAL_PROTECTION_VECTOR: usize = 13;
+const PAGE_FAULT_VECTOR: usize = 14;
+const MACHINE_CHECK_VECTOR: usize = 18;
+
+static EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error", "Debug", "Non-maskable Interrupt", "Breakpoint",
+    "Overflow", "BOUND Range Exceeded", "Invalid Opcode", "Device Not Available",
+    "Double Fault", "Coprocessor Segment Overrun", "Invalid TSS", "Segment Not Present",
+    "Stack-Segment Fault", "General Protection", "Page Fault", "Reserved",
+    "x87 Floating-Point Error", "Alignment Check", "Machine Check", "SIMD Floating-Point",
+    "Virtualization", "Control Protection", "Reserved", "Reserved",
+    "Reserved", "Reserved", "Reserved", "Reserved",
+    "Hypervisor Injection", "VMM Communication", "Security", "Reserved",
+];
+
+/*
+ * The register state saved by isr_common, see arch/x86/isr.S. The CPU
+ * only pushes esp and ss when coming from ring 3, kernel_esp is what
+ * pusha saved and points at the vector.
+ */
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub kernel_esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub vector: u32,
+    pub error_code: u32,
+    /* Pushed by the CPU */
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    pub esp: u32,
+    pub ss: u32,
+}
+
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    reserved: u8,
+    flags: u8,
+    offset_high: u16,
+}
+
+const MISSING_ENTRY: IdtEntry = IdtEntry {
+    offset_low: 0,
+    selector: 0,
+    reserved: 0,
+    flags: 0,
+    offset_high: 0,
+};
+
+impl IdtEntry {
+    /* A present 32-bit interrupt gate, which ring `dpl` may use with int */
+    fn new(handler: usize, dpl: u8) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector: gdt::KERNEL_CS,
+            reserved: 0,
+            flags: 0x8E | (dpl << 5),
+            offset_high: (handler >> 16) as u16,
+        }
+    }
+}
+
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u32,
+}
+
+/* The IDT is shared by all CPUs */
+static mut IDT: [IdtEntry; 256] = [MISSING_ENTRY; 256];
+static mut HANDLERS: [Option<InterruptHandler>; 256] = [None; 256];
+
+/* Fill in the IDT, must be called on the BSP before `load` */
+pub fn init()
+{
+    extern {
+        /* Defined in arch/x86/isr.S */
+        static isr_stubs: u8;
+    }
+    let stubs: usize = unsafe { &isr_stubs as *const u8 as usize };
+
+    for vector in 0..256 {
+        /* User space may only raise the system call vector itself */
+        let dpl = if vector == SYSCALL_VECTOR as usize { 3 } else { 0 };
+        unsafe {
+            IDT[vector] = IdtEntry::new(stubs + 16 * vector, dpl);
+        }
+    }
+}
+
+/* Load the IDT on the current CPU */
+pub unsafe fn load()
+{
+    let ptr = IdtPointer {
+        limit: (mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
+        base: IDT.as_ptr() as u32,
+    };
+    asm!("lidt ($0)" :: "r"(&ptr) : "memory" : "volatile");
+}
+
+/*
+ * Install `handler` for the interrupt `vector`. Handlers run with
+ * interrupts disabled, the LAPIC has already been sent an EOI.
+ */
+pub fn register_handler(vector: u8, handler: InterruptHandler)
+{
+    assert!(vector as usize >= FIRST_IRQ_VECTOR, "vector {} is an exception", vector);
+    assert!(vector != SYSCALL_VECTOR, "vector 0x{:x} is for system calls", vector);
+    assert!(!vectors::is_device_vector(vector), "vector 0x{:x} is for devices", vector);
+    unsafe {
+        if let Some(_) = HANDLERS[vector as usize] {
+            panic!("interrupt vector 0x{:x} registered twice", vector);
+        }
+        HANDLERS[vector as usize] = Some(handler);
+    }
+}
+
+fn exception(frame: &mut InterruptFrame)
+{
+    let vector = frame.vector as usize;
+
+    /* A fault the kernel expected, e.g. on a bad user pointer */
+    if (vector == GENERAL_PROTECTION_VECTOR || vector == PAGE_FAULT_VECTOR) && frame.cs & 3 == 0 {
+        if let Some(fixup) = uaccess::fixup(frame.eip as usize) {
+            frame.eip = fixup as u32;
+            return;
+        }
+    }
+
+    let mut cr2: u32 = 0;
+    if vector == PAGE_FAULT_VECTOR {
+        unsafe { asm!("mov %cr2, $0" : "=r"(cr2)); }
+    }
+
+    /* A user program did something bad, it goes rather than the kernel */
+    if frame.cs & 3 == 3 && vector != NMI_VECTOR && vector != MACHINE_CHECK_VECTOR {
+        log!("task {}: {} at eip 0x{:x}, error code 0x{:x}, cr2 0x{:x}, killed",
+             ::sched::current_id(), EXCEPTION_NAMES[vector], frame.eip,
+             frame.error_code, cr2);
+        ::sched::exit();
+    }
+
+    /* The kernel's stack before the fault, past the vector, error code, eip, cs and eflags */
+    let esp = frame.kernel_esp + 5 * 4;
+    log!("{} (vector {}) on CPU {}, error code 0x{:x}",
+         EXCEPTION_NAMES[vector], vector, percpu::cpu_id(), frame.error_code);
+    log!("eip 0x{:08x} cs 0x{:x} eflags 0x{:x} esp 0x{:08x} cr2 0x{:08x}",
+         frame.eip, frame.cs & 0xFFFF, frame.eflags, esp, cr2);
+    log!("{:?}", frame);
+    panic!("Unhandled exception: {}", EXCEPTION_NAMES[vector]);
+}
+
+/* Called by isr_common for every interrupt, exception and system call */
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame)
+{
+    let vector = frame.vector as usize;
+
+    if vector < FIRST_IRQ_VECTOR {
+        return exception(frame);
+    }
+
+    /* Raised by int, so there's nothing to acknowledge */
+    if vector == SYSCALL_VECTOR as usize {
+        return syscall::syscall_dispatch(frame);
+    }
+
+    /* Spurious interrupts must not be acknowledged */
+    if vector == SPURIOUS_VECTOR as usize {
+        return;
+    }
+
+    /*
+     * Acknowledge before running the handler, as the handler may switch
+     * to another task and not come back here for a while.
+     */
+    if let Some(ref lapic) = unsafe { percpu::this_cpu_area() }.lapic {
+        lapic.eoi();
+    }
+
+    percpu::set_irq_depth(percpu::irq_depth() + 1);
+    match unsafe { HANDLERS[vector] } {
+        Some(handler) => handler(frame),
+        None if vectors::dispatch(vector as u8) => {}
+        None => log!("Unhandled interrupt vector 0x{:x} on CPU {}", vector, percpu::cpu_id()),
+    }
+    percpu::set_irq_depth(percpu::irq_depth() - 1);
+}
@@ -18,13 +18,221 @@ mod x86_io;
 #[path = "../x86_common/debug.rs"]
 pub mod debug;
 
+// Multiboot2 boot information
+#[path = "../x86_common/multiboot2.rs"]
+mod multiboot2;
+
+// Reading the Multiboot information
+#[path = "../x86_common/discover.rs"]
+mod discover;
+
+#[path = "../x86_common/apic.rs"]
+mod apic;
+
+// Model specific registers
+#[path = "../x86_common/msr.rs"]
+mod msr;
+
+// Interrupt flag manipulation
+#[path = "../x86_common/irq.rs"]
+pub mod irq;
+
+// Per-CPU data, reached through %gs
+#[path = "../x86_common/percpu.rs"]
+pub mod percpu;
+
+// Per-CPU GDT and TSS
+#[path = "./gdt.rs"]
+mod gdt;
+
+// Interrupt descriptor table and dispatch
+#[path = "./idt.rs"]
+mod idt;
+
+// CPUID and other processor helpers
+#[path = "../x86_common/cpu.rs"]
+pub mod cpu;
+
+// Legacy programmable interval timer
+#[path = "../x86_common/pit.rs"]
+mod pit;
+
+// Legacy 8259 interrupt controller
+#[path = "../x86_common/pic.rs"]
+mod pic;
+
+// LAPIC timer
+#[path = "../x86_common/timer.rs"]
+pub mod timer;
+
+// Task context switching
+#[path = "../x86_common/context.rs"]
+pub mod context;
+
+// FPU and SIMD state
+#[path = "../x86_common/fpu.rs"]
+mod fpu;
+
+// Accessing user memory
+#[path = "../x86_common/uaccess.rs"]
+pub mod uaccess;
+
+// System calls and user mode
+#[path = "./syscall.rs"]
+pub mod syscall;
+
+// The page table format
+#[path = "./paging.rs"]
+pub mod paging;
+
+// ACPI table discovery
+#[path = "../x86_common/acpi.rs"]
+mod acpi;
+
+// PCI configuration space access
+#[path = "../x86_common/pci.rs"]
+pub mod pci;
+
+// Device interrupt vectors and MSI messages
+#[path = "../x86_common/vectors.rs"]
+pub mod vectors;
+
+// High precision event timer
+#[path = "../x86_common/hpet.rs"]
+mod hpet;
+
+// CMOS real time clock
+#[path = "../x86_common/rtc.rs"]
+pub mod rtc;
+
+// Clocksource selection
+#[path = "../x86_common/clock.rs"]
+pub mod clock;
+
+// Processor enumeration and AP startup
+#[path = "../x86_common/mp.rs"]
+mod mp;
+
+// Logging code
+#[path = "../../logging.rs"]
+mod logging;
+
+/* Some globals. */
+pub const KERNEL_BASE: usize = 0xC0000000;
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+use boot::BootInfo;
+use mm::layout::phys_to_virt;
+use mm::pmm::FrameAllocator;
+
+pub use self::mp::{Processor, with_processors, processor, cpu_count, start_aps, send_ipi};
+pub use self::idt::{InterruptFrame, InterruptHandler, register_handler,
+                    RESCHED_VECTOR, CALL_FUNCTION_VECTOR};
+
+pub unsafe fn set_page_directory(root: usize)
+{
+    asm!("mov $0, %cr3" :: "r" (root) : "memory")
+}
+
+/* Physical address of the page tables in use */
+pub fn page_directory_addr() -> usize
+{
+    let value: usize;
+    unsafe { asm!("mov %cr3, $0" : "=r"(value) ::: "volatile"); }
+    value & !0xFFF
+}
+
+pub fn early_init() -> BootInfo
+{
+    log!("Initializing x86 processors");
+
+    /* Find out what the CPU can do, everything below may ask. */
+    cpu::init_bsp();
+    fpu::init_cpu(true);
+    cpu::enable_protection(true);
+    syscall::init_cpu();
+
+    /* Set up the per-CPU area of the BSP before anything needs it. */
+    unsafe {
+        percpu::init_bsp();
+    }
+
+    /* Catch exceptions from here on. */
+    idt::init();
+    unsafe {
+        idt::load();
+        pic::disable();
+    }
+
+    /* Find out what the bootloader knows, including the available memory. */
+    unsafe { discover::discover_boot_info() }
+}
+
+pub unsafe fn new_cpu_init(boot_id: usize)
+{
+    /* synchronize page tables */
+    ::arch::set_page_directory(::mm::vmm::KERNEL_PAGE_DIRECTORY);
+
+    /*
+     * get the processor structure for this AP. The BSP has left it for us,
+     * the processor list can't be read before we have a per-CPU area.
+     */
+    let apic_id = cpu::initial_apic_id() as usize;
+    let this_ap = mp::ap_boot_processor();
+    assert!(this_ap.apic_id == apic_id,
+            "AP with APIC id {} came up instead of {}", apic_id, this_ap.apic_id);
+    let cpu_id = this_ap.id;
+    log!("AP {} has APIC id {}, it is CPU {}", boot_id, apic_id, cpu_id);
+    cpu::check_ap(cpu_id);
+    fpu::init_cpu(false);
+    cpu::enable_protection(false);
+    syscall::init_cpu();
+
+    /* point %gs at the per-CPU area of this AP, it survives the stack switch */
+    percpu::init_area(this_ap.percpu_area as *mut percpu::PerCpuArea, cpu_id);
+
+    /* switch from the boot GDT to this AP's own GDT and TSS */
+    gdt::load(this_ap.cpu_tables);
+    idt::load();
+
+    /* allocate a new stack */
+    let frame = phys_to_virt(this_ap.stack_frame + ::arch::PAGE_SIZE);
+
+    log!("AP {} about to switch stack to frame 0x{:x}", cpu_id, this_ap.stack_frame);
+
+    /* switch stacks */
+    asm!("movl %eax, %esp;
+         movl %eax, %ebp;
+         call new_cpu_init_tail"::"{eax}"(frame));
+}
+
 #[no_mangle]
-pub fn x86_prep_page_table(buf: &mut [u32; 1024])
+pub unsafe fn new_cpu_init_tail()
 {
-	for i in 0u32 .. 1024
-	{
-		buf[i as usize] = i * 0x1000 + 3;
-	}
+    /* get back the AP id */
+    let cpu_id = percpu::cpu_id();
+    log!("Switched stack for AP {}", cpu_id);
+
+    /* enable the LAPIC of this AP */
+    percpu::this_cpu_area().lapic = Some(apic::LAPIC::for_this_cpu(mp::lapic_addr()));
+
+    /* start ticking, the BSP has calibrated the TSC by now */
+    ::timer::init_cpu();
+
+    /* signal to the BSP that we are now done */
+    asm!("lock decl unique_stack_id; lock incl did_an_ap_boot");
+
+    ::sched::start();
 }
 
+pub fn late_init(fma: &mut FrameAllocator)
+{
+    mp::enumerate_processors(fma);
+    vectors::init();
 
+    /* switch the BSP from the boot GDT to its own GDT and TSS */
+    unsafe {
+        gdt::load(processor(0).unwrap().cpu_tables);
+    }
+}
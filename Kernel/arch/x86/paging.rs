@@ -0,0 +1,99 @@
+/*
+ * Two level paging with 32-bit entries, a page directory and page tables
+ * of 1024 entries each. Built with the "pae" feature, PAE paging instead:
+ * a 4 entry page directory pointer table above page directories and page
+ * tables of 512 64-bit entries, which brings the NX bit.
+ *
+ * mm::vmm walks the tables, this describes them.
+ */
+#[cfg(feature = "pae")]
+use super::cpu::{self, Features};
+
+#[cfg(not(feature = "pae"))]
+pub type Entry = u32;
+#[cfg(feature = "pae")]
+pub type Entry = u64;
+
+#[cfg(not(feature = "pae"))]
+pub const LEVELS: usize = 2;
+#[cfg(feature = "pae")]
+pub const LEVELS: usize = 3;
+
+#[cfg(not(feature = "pae"))]
+pub const ENTRIES: usize = 1024;
+#[cfg(feature = "pae")]
+pub const ENTRIES: usize = 512;
+
+/* The lowest bit of the virtual address indexing each level, root first */
+#[cfg(not(feature = "pae"))]
+pub const LEVEL_SHIFTS: [usize; LEVELS] = [22, 12];
+#[cfg(feature = "pae")]
+pub const LEVEL_SHIFTS: [usize; LEVELS] = [30, 21, 12];
+
+/* Entries of the root table, and the first one of the kernel's 3GiB and up */
+#[cfg(not(feature = "pae"))]
+pub const ROOT_ENTRIES: usize = 1024;
+#[cfg(not(feature = "pae"))]
+pub const KERNEL_ROOT_START: usize = 768;
+#[cfg(feature = "pae")]
+pub const ROOT_ENTRIES: usize = 4;
+#[cfg(feature = "pae")]
+pub const KERNEL_ROOT_START: usize = 3;
+
+/*
+ * The CPU loads the four PDPTEs when %cr3 is written and doesn't look at
+ * the table again, so they have to be filled in up front.
+ */
+#[cfg(not(feature = "pae"))]
+pub const ROOT_ENTRIES_CACHED: bool = false;
+#[cfg(feature = "pae")]
+pub const ROOT_ENTRIES_CACHED: bool = true;
+
+pub const PRESENT: Entry = 1 << 0;
+pub const WRITABLE: Entry = 1 << 1;
+pub const USER: Entry = 1 << 2;
+/* A 4MiB (2MiB with PAE) page rather than a table, in PDEs */
+pub const HUGE: Entry = 1 << 7;
+/* Page-level cache disable and write-through, for device registers */
+pub const NO_CACHE: Entry = (1 << 4) | (1 << 3);
+
+#[cfg(not(feature = "pae"))]
+pub const ADDRESS_MASK: Entry = 0xFFFF_F000;
+#[cfg(feature = "pae")]
+pub const ADDRESS_MASK: Entry = 0x000F_FFFF_FFFF_F000;
+
+/* There is no NX bit without PAE */
+#[cfg(not(feature = "pae"))]
+pub fn no_execute() -> Entry
+{
+    0
+}
+
+/*
+ * The bit is reserved unless start.S found NX and enabled EFER.NXE, and
+ * once it's used an AP without NX would fault on every kernel page.
+ */
+#[cfg(feature = "pae")]
+pub fn no_execute() -> Entry
+{
+    if cpu::has(Features::NX) {
+        cpu::mark_used(Features::NX);
+        1 << 63
+    } else {
+        0
+    }
+}
+
+/* The flags an entry pointing to a table may have at `level` */
+#[cfg(not(feature = "pae"))]
+pub fn table_flags(_level: usize) -> Entry
+{
+    WRITABLE | USER
+}
+
+/* PDPTEs only have the present bit, the others are reserved */
+#[cfg(feature = "pae")]
+pub fn table_flags(level: usize) -> Entry
+{
+    if level == 0 { 0 } else { WRITABLE | USER }
+}
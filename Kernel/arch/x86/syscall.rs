@@ -0,0 +1,44 @@
+use super::idt::InterruptFrame;
+use super::irq;
+use super::uaccess::USER_END;
+
+extern "C" {
+    /* Defined in arch/x86/syscall.S */
+    fn enter_user(eip: usize, esp: usize) -> !;
+}
+
+/*
+ * System calls use int $0x80, whose gate the IDT sets up for every CPU, so
+ * there is nothing to do per CPU.
+ */
+pub fn init_cpu()
+{
+}
+
+/*
+ * Called by interrupt_dispatch for int $0x80, with interrupts disabled on
+ * the task's kernel stack. The number is in eax and the arguments in ebx,
+ * ecx, edx, esi, edi and ebp, as on Linux.
+ */
+pub fn syscall_dispatch(frame: &mut InterruptFrame)
+{
+    unsafe { irq::enable(); }
+
+    let args = [frame.ebx as usize, frame.ecx as usize, frame.edx as usize,
+                frame.esi as usize, frame.edi as usize, frame.ebp as usize];
+    frame.eax = ::syscall::dispatch(frame.eax as usize, &args) as u32;
+
+    irq::disable();
+}
+
+/*
+ * Leave the kernel for good and run the current task in user mode at
+ * `eip`, with its stack pointer at `esp`. Its address space must already
+ * be loaded.
+ */
+pub fn jump_to_user(eip: usize, esp: usize) -> !
+{
+    assert!(eip < USER_END && esp <= USER_END, "jumping to a kernel address");
+    irq::disable();
+    unsafe { enter_user(eip, esp) }
+}
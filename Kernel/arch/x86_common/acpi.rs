@@ -0,0 +1,142 @@
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::str;
+
+use mm::layout::phys_to_virt;
+use mm::pmm::FrameAllocator;
+use mm::vmm;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    /* ACPI 2.0+ */
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/* The header every system description table starts with */
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/* Physical address of the RSDP, 0 if there's none */
+static mut RSDP_ADDR: usize = 0;
+
+/* The bytes of a table add up to zero */
+fn checksum_ok(addr: usize, len: usize) -> bool
+{
+    let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/* Look for the RSDP in the first KiB of the EBDA and in the BIOS ROM */
+unsafe fn find_rsdp() -> Option<usize>
+{
+    let ebda = (*(phys_to_virt(0x40E) as *const u16) as usize) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for &(start, end) in areas.iter() {
+        let mut addr = start;
+        while addr + mem::size_of::<Rsdp>() <= end {
+            let virt = phys_to_virt(addr);
+            if slice::from_raw_parts(virt as *const u8, 8) == b"RSD PTR "
+                && checksum_ok(virt, 20) {
+                return Some(addr);
+            }
+            addr += 16;
+        }
+    }
+    None
+}
+
+/*
+ * Locate the RSDP, returns false if the system has no ACPI. A Multiboot2
+ * bootloader may have passed it, otherwise it is searched for.
+ */
+pub fn init() -> bool
+{
+    unsafe {
+        if RSDP_ADDR == 0 {
+            match ::boot::info().rsdp.or_else(|| find_rsdp()) {
+                Some(addr) => RSDP_ADDR = addr,
+                None => {
+                    log!("No ACPI RSDP found");
+                    return false;
+                }
+            }
+        }
+        let rsdp = &*(phys_to_virt(RSDP_ADDR) as *const Rsdp);
+        log!("ACPI RSDP at 0x{:x}, revision {}, OEM '{}'", RSDP_ADDR, rsdp.revision,
+             str::from_utf8(&rsdp.oem_id).unwrap_or("?"));
+    }
+    true
+}
+
+/* Identity map the whole of the table at `addr` and return its header */
+fn map_table<'a>(fma: &mut FrameAllocator, addr: usize) -> &'a SdtHeader
+{
+    vmm::identity_map_current(fma, addr, mem::size_of::<SdtHeader>());
+    let header = unsafe { &*(addr as *const SdtHeader) };
+    vmm::identity_map_current(fma, addr, header.length as usize);
+    header
+}
+
+/*
+ * Find the table with `signature` through the XSDT (or the RSDT on ACPI
+ * 1.0 systems). The table is identity mapped, its physical address can be
+ * used as a pointer.
+ */
+pub fn find_table(fma: &mut FrameAllocator, signature: &[u8; 4]) -> Option<usize>
+{
+    if unsafe { RSDP_ADDR } == 0 {
+        return None;
+    }
+    let rsdp = unsafe { &*(phys_to_virt(RSDP_ADDR) as *const Rsdp) };
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address as usize, 8)
+    } else {
+        (rsdp.rsdt_address as usize, 4)
+    };
+
+    let root_header = map_table(fma, root);
+    let entries = (root_header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let first = root + mem::size_of::<SdtHeader>();
+
+    for i in 0..entries {
+        let table = unsafe {
+            if entry_size == 8 {
+                ptr::read_unaligned((first + i * 8) as *const u64) as usize
+            } else {
+                ptr::read_unaligned((first + i * 4) as *const u32) as usize
+            }
+        };
+        let header = map_table(fma, table);
+        if &header.signature != signature {
+            continue;
+        }
+        if !checksum_ok(table, header.length as usize) {
+            log!("ACPI table {} at 0x{:x} has a bad checksum",
+                 str::from_utf8(signature).unwrap_or("?"), table);
+            continue;
+        }
+        return Some(table);
+    }
+    None
+}
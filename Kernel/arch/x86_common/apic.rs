@@ -0,0 +1,139 @@
+#[path = "../../logging.rs"]
+mod logging;
+
+use super::idt::SPURIOUS_VECTOR;
+
+/* LAPIC registers */
+const LAPIC_ID: isize = 0x20;
+const LAPIC_EOI: isize = 0xB0;
+const LAPIC_SVR: isize = 0xF0;
+const LAPIC_LVT_TIMER: isize = 0x320;
+const LAPIC_TIMER_INITIAL: isize = 0x380;
+const LAPIC_TIMER_CURRENT: isize = 0x390;
+const LAPIC_TIMER_DIVIDE: isize = 0x3E0;
+
+/* LVT timer bits */
+const LVT_MASKED: u32 = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
+pub struct LAPIC {
+    lapic_addr: usize, /* Address of the LAPIC from this CPU */
+    lapic_id: u8, /* The CPU's LAPIC id */
+}
+
+impl LAPIC {
+
+    pub fn new(fma: &mut ::mm::pmm::FrameAllocator, lapic_addr: usize, lapic_id: u8) -> LAPIC {
+        ::mm::vmm::map_addr_current(fma, lapic_addr, lapic_addr);
+        let ret = LAPIC {
+            lapic_addr: lapic_addr,
+            lapic_id: lapic_id,
+        };
+        ret.enable();
+        ret
+    }
+
+    /*
+     * Get a handle to the LAPIC of the current CPU and software-enable it.
+     * The registers must already have been mapped by `LAPIC::new` on the BSP.
+     */
+    pub fn for_this_cpu(lapic_addr: usize) -> LAPIC {
+        let mut ret = LAPIC {
+            lapic_addr: lapic_addr,
+            lapic_id: 0,
+        };
+        ret.lapic_id = (ret.read_u32(LAPIC_ID) >> 24) as u8;
+        ret.enable();
+        ret
+    }
+
+    pub fn id(&self) -> u8 {
+        self.lapic_id
+    }
+
+    /* Software-enable the LAPIC and route spurious interrupts */
+    fn enable(&self) {
+        let svr = self.read_u32(LAPIC_SVR) & !0xFF;
+        self.write_u32(LAPIC_SVR, svr | 0x100 | SPURIOUS_VECTOR as u32);
+    }
+
+    /* Signal the end of the interrupt currently being serviced */
+    pub fn eoi(&self) {
+        self.write_u32(LAPIC_EOI, 0);
+    }
+
+    /* Program the timer LVT, the timer is left masked if `masked` is set */
+    pub fn set_timer(&self, vector: u8, mode: TimerMode, masked: bool) {
+        let mut lvt = (vector as u32) | ((mode as u32) << 17);
+        if masked {
+            lvt = lvt | LVT_MASKED;
+        }
+        self.write_u32(LAPIC_LVT_TIMER, lvt);
+    }
+
+    /* Set the timer's divide configuration to divide by 16 */
+    pub fn set_timer_divide_16(&self) {
+        self.write_u32(LAPIC_TIMER_DIVIDE, 0x3);
+    }
+
+    /* Writing the initial count (re)starts the timer, 0 stops it */
+    pub fn set_timer_initial_count(&self, count: u32) {
+        self.write_u32(LAPIC_TIMER_INITIAL, count);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.read_u32(LAPIC_TIMER_CURRENT)
+    }
+
+    fn read_u32(&self, register: isize) -> u32 {
+        let lapic_ptr: *const u32 = (self.lapic_addr + register as usize) as *const u32;
+        unsafe { *lapic_ptr }
+    }
+
+    fn write_u32(&self, register: isize, value: u32) {
+        let lapic_ptr: *mut u32 = (self.lapic_addr + register as usize) as *mut u32;
+        unsafe { *lapic_ptr = value };
+    }
+
+    /* Wait for the current LAPIC to clear any pending IPI */
+    fn wait_for_pending_ipi(&self) {
+        loop {
+            if (self.read_u32(0x30)) & (1 << 12) == 0 {
+                break;
+            }
+        }
+    }
+
+    pub fn send_ipi_to(&self, target_id: u8, vector: u8) {
+        let mut control: u32 = vector as u32;
+        self.wait_for_pending_ipi();
+
+        self.write_u32(0x31 * 0x10, (target_id as u32) << 24);
+
+        control = control | (1 << 14);
+
+        self.write_u32(0x30 * 0x10, control);
+    }
+
+    pub fn send_sipi_to(&self, target_id: u8, vector: u8) {
+        let higher: u32 = (target_id as u32) << 24;
+        let lower: u32 = (6 << 8) | (1 << 14) | (vector as u32);
+
+        self.write_u32(0x31 * 0x10, higher);
+        self.write_u32(0x30 * 0x10, lower);
+    }
+
+    pub fn send_init_to(&self, target_id: u8) {
+        let higher: u32 = (target_id as u32) << 24;
+        let lower: u32 = (5 << 8);
+
+        self.write_u32(0x31 * 0x10, higher);
+        self.write_u32(0x30 * 0x10, lower);
+    }
+}
@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use mm::pmm::FrameAllocator;
+use sync::SpinLock;
+use super::{acpi, cpu, hpet, pit};
+
+/* How long the reference clock is watched when calibrating the TSC */
+const CALIBRATION_MS: u64 = 50;
+
+/* Serializes the use of PIT channel 2 by calibrating CPUs */
+static PIT_LOCK: SpinLock<()> = SpinLock::named("pit channel 2", ());
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClockSource {
+    None = 0,
+    Tsc = 1,
+    Hpet = 2,
+    Pit = 3,
+}
+
+static SOURCE: AtomicUsize = AtomicUsize::new(ClockSource::None as usize);
+
+/* TSC frequency in kHz */
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+/*
+ * Busy wait for `ms` milliseconds to calibrate another timer against: on
+ * the HPET if ACPI reported one, otherwise on channel 2 of the PIT, which
+ * can wait at most 54ms.
+ */
+pub fn calibration_wait_ms(ms: u64)
+{
+    if hpet::is_present() {
+        let end = hpet::read_ns() + ms * 1_000_000;
+        while hpet::read_ns() < end {
+            /* Do nothing */
+        }
+    } else {
+        let _guard = PIT_LOCK.lock();
+        unsafe { pit::wait_ms(ms); }
+    }
+}
+
+/* Measure the TSC frequency against the HPET or the PIT */
+fn calibrate_tsc()
+{
+    let start = cpu::rdtsc();
+    calibration_wait_ms(CALIBRATION_MS);
+    let end = cpu::rdtsc();
+
+    TSC_KHZ.store((end - start) / CALIBRATION_MS, Ordering::Relaxed);
+    log!("TSC runs at {} kHz", TSC_KHZ.load(Ordering::Relaxed));
+}
+
+/*
+ * Pick the best clocksource available: an invariant TSC, then the HPET
+ * from the ACPI tables, then the PIT. The TSC is calibrated regardless, as
+ * the TSC-deadline timer needs its frequency, against the HPET if there is
+ * one.
+ */
+pub fn init(fma: &mut FrameAllocator) -> ClockSource
+{
+    let hpet_table = if acpi::init() { acpi::find_table(fma, b"HPET") } else { None };
+    let have_hpet = match hpet_table {
+        Some(table) => hpet::init(fma, table),
+        None => false,
+    };
+
+    calibrate_tsc();
+
+    let source = if cpu::has(cpu::Features::INVARIANT_TSC) {
+        cpu::mark_used(cpu::Features::INVARIANT_TSC);
+        ClockSource::Tsc
+    } else if have_hpet {
+        ClockSource::Hpet
+    } else {
+        unsafe { pit::start_counter(); }
+        ClockSource::Pit
+    };
+
+    SOURCE.store(source as usize, Ordering::Relaxed);
+    source
+}
+
+pub fn source() -> ClockSource
+{
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => ClockSource::Tsc,
+        2 => ClockSource::Hpet,
+        3 => ClockSource::Pit,
+        _ => ClockSource::None,
+    }
+}
+
+pub fn tsc_khz() -> u64
+{
+    TSC_KHZ.load(Ordering::Relaxed)
+}
+
+pub fn tsc_to_ns(tsc: u64) -> u64
+{
+    let khz = tsc_khz();
+    if khz == 0 {
+        return 0;
+    }
+    (tsc as u128 * 1_000_000 / khz as u128) as u64
+}
+
+pub fn ns_to_tsc(ns: u64) -> u64
+{
+    (ns as u128 * tsc_khz() as u128 / 1_000_000) as u64
+}
+
+/* Read the clocksource in nanoseconds, 0 before `init` */
+pub fn read_ns() -> u64
+{
+    match source() {
+        ClockSource::Tsc => tsc_to_ns(cpu::rdtsc()),
+        ClockSource::Hpet => hpet::read_ns(),
+        ClockSource::Pit => pit::read_ns(),
+        ClockSource::None => 0,
+    }
+}
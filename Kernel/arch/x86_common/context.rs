@@ -0,0 +1,97 @@
+use core::mem;
+use core::ptr;
+use alloc::Vec;
+
+use super::fpu;
+use super::gdt;
+use super::percpu;
+
+/* XSAVE needs its area to be 64-byte aligned, FXSAVE 16 */
+const FPU_AREA_ALIGN: usize = 64;
+
+/* The callee-saved registers and the flags switch_context keeps on the stack */
+#[cfg(target_arch="x86_64")]
+const SAVED_REGISTERS: usize = 7;       /* r15, r14, r13, r12, rbx, rbp, rflags */
+#[cfg(target_arch="x86")]
+const SAVED_REGISTERS: usize = 5;       /* edi, esi, ebx, ebp, eflags */
+
+/* The flags a new task starts with: interrupts disabled, as task_start expects, and AC clear */
+const INITIAL_FLAGS: usize = 1 << 1;
+
+/*
+ * The FPU and vector state of a task, sized for whatever the CPU saves.
+ * Only valid once the BSP has enabled the FPU.
+ */
+pub struct FpuState {
+    buf: Vec<u8>,
+    offset: usize,
+}
+
+impl FpuState {
+    /* The reset state: everything zero, all SIMD exceptions masked */
+    pub fn new() -> FpuState {
+        let size = fpu::area_size();
+        let buf = vec![0u8; size + FPU_AREA_ALIGN];
+        let addr = buf.as_ptr() as usize;
+        let offset = ((addr + FPU_AREA_ALIGN - 1) & !(FPU_AREA_ALIGN - 1)) - addr;
+        let mut state = FpuState { buf: buf, offset: offset };
+        unsafe {
+            /* FCW, with all x87 exceptions masked */
+            ptr::write(state.area_mut() as *mut u16, 0x37F);
+            ptr::write(state.area_mut().offset(fpu::MXCSR_OFFSET as isize) as *mut u32,
+                       fpu::MXCSR_DEFAULT);
+        }
+        state
+    }
+
+    pub fn area(&self) -> *const u8 {
+        unsafe { self.buf.as_ptr().offset(self.offset as isize) }
+    }
+
+    pub fn area_mut(&mut self) -> *mut u8 {
+        unsafe { self.buf.as_mut_ptr().offset(self.offset as isize) }
+    }
+}
+
+extern "C" {
+    /* Defined in arch/<arch>/switch.S */
+    pub fn switch_context(prev_rsp: *mut usize, next_rsp: usize);
+}
+
+/*
+ * Lay out a fresh kernel stack so that the first switch_context to it
+ * "returns" into `entry`, with the stack aligned as if `entry` was called.
+ * Returns the initial stack pointer.
+ */
+pub fn init_stack(stack_top: usize, entry: extern "C" fn() -> !) -> usize
+{
+    let top = stack_top & !0xF;
+    /* The saved registers, the return address of switch_context and a fake one of entry */
+    let mut frame = [0usize; SAVED_REGISTERS + 2];
+    frame[SAVED_REGISTERS - 1] = INITIAL_FLAGS;
+    frame[SAVED_REGISTERS] = entry as usize;
+    let sp = top - mem::size_of::<[usize; SAVED_REGISTERS + 2]>();
+    unsafe {
+        *(sp as *mut [usize; SAVED_REGISTERS + 2]) = frame;
+    }
+    sp
+}
+
+/* Save the FPU state of the current task */
+pub unsafe fn save_fpu(state: &mut FpuState)
+{
+    fpu::save(state.area_mut());
+}
+
+/* Load the FPU state saved by `save_fpu`, or the reset state of a new task */
+pub unsafe fn restore_fpu(state: &FpuState)
+{
+    fpu::restore(state.area());
+}
+
+/* The stack to use for interrupts and syscalls from ring 3 while this task runs */
+pub fn set_kernel_stack(stack_top: usize)
+{
+    gdt::set_kernel_stack(stack_top);
+    unsafe { percpu::this_cpu_area().kernel_rsp = stack_top & !0xF; }
+}
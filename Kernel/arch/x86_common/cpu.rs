@@ -0,0 +1,433 @@
+use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/* The registers returned by the cpuid instruction */
+#[derive(Clone, Copy, Debug)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/* Execute cpuid for `leaf` and `subleaf` */
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult
+{
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+             : "{eax}"(leaf), "{ecx}"(subleaf));
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/* The initial APIC id of the current CPU */
+pub fn initial_apic_id() -> u8
+{
+    (cpuid(1, 0).ebx >> 24) as u8
+}
+
+/* Read the time stamp counter */
+pub fn rdtsc() -> u64
+{
+    let low: u32;
+    let high: u32;
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile"); }
+    ((high as u64) << 32) | (low as u64)
+}
+
+/* CR4 bits for the protection features */
+const CR4_UMIP: usize = 1 << 11;
+const CR4_SMEP: usize = 1 << 20;
+const CR4_SMAP: usize = 1 << 21;
+
+pub unsafe fn read_cr0() -> usize
+{
+    let value: usize;
+    asm!("mov %cr0, $0" : "=r"(value) ::: "volatile");
+    value
+}
+
+pub unsafe fn write_cr0(value: usize)
+{
+    asm!("mov $0, %cr0" :: "r"(value) : "memory" : "volatile");
+}
+
+pub unsafe fn read_cr4() -> usize
+{
+    let value: usize;
+    asm!("mov %cr4, $0" : "=r"(value) ::: "volatile");
+    value
+}
+
+pub unsafe fn write_cr4(value: usize)
+{
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
+
+extern "C" {
+    /* Defined in arch/<arch>/isr.S */
+    static mut smap_enabled: u8;
+}
+
+/*
+ * Keep the kernel from executing (SMEP) or touching (SMAP) user pages,
+ * and user space from reading the descriptor tables (UMIP), on the
+ * current CPU. User memory has to be accessed through arch::uaccess.
+ */
+pub fn enable_protection(bsp: bool)
+{
+    let mut cr4 = 0;
+    for &(feature, bit) in [(Features::SMEP, CR4_SMEP), (Features::SMAP, CR4_SMAP),
+                            (Features::UMIP, CR4_UMIP)].iter() {
+        if has(feature) {
+            mark_used(feature);
+            cr4 |= bit;
+        }
+    }
+    unsafe {
+        write_cr4(read_cr4() | cr4);
+        if cr4 & CR4_SMAP != 0 {
+            /* Start with user access closed, and have interrupt entry close it too */
+            asm!("clac" ::: "memory" : "volatile");
+            smap_enabled = 1;
+        }
+    }
+
+    if bsp {
+        log!("Protection: SMEP {}, SMAP {}, UMIP {}",
+             if cr4 & CR4_SMEP != 0 { "on" } else { "off" },
+             if cr4 & CR4_SMAP != 0 { "on" } else { "off" },
+             if cr4 & CR4_UMIP != 0 { "on" } else { "off" });
+    }
+}
+
+/* Halt until the next interrupt */
+pub fn halt()
+{
+    unsafe { asm!("hlt" :::: "volatile"); }
+}
+
+bitflags! {
+    pub struct Features : u32 {
+        const FXSR          = (1 << 0);
+        const SSE           = (1 << 1);
+        const SSE2          = (1 << 2);
+        const SSE3          = (1 << 3);
+        const SSSE3         = (1 << 4);
+        const SSE4_1        = (1 << 5);
+        const SSE4_2        = (1 << 6);
+        const AVX           = (1 << 7);
+        const AVX2          = (1 << 8);
+        const AVX512F       = (1 << 9);
+        const XSAVE         = (1 << 10);
+        const X2APIC        = (1 << 11);
+        const TSC_DEADLINE  = (1 << 12);
+        const INVARIANT_TSC = (1 << 13);
+        const RDTSCP        = (1 << 14);
+        const NX            = (1 << 15);
+        const PAGE_1GB      = (1 << 16);
+        const PCID          = (1 << 17);
+        const INVPCID       = (1 << 18);
+        const SMEP          = (1 << 19);
+        const SMAP          = (1 << 20);
+        const UMIP          = (1 << 21);
+        const RDRAND        = (1 << 22);
+        const RDSEED        = (1 << 23);
+        const FSGSBASE      = (1 << 24);
+    }
+}
+
+/* Names for the boot log, in the order of the bits */
+static FEATURE_NAMES: [(Features, &str); 25] = [
+    (Features::FXSR, "fxsr"), (Features::SSE, "sse"), (Features::SSE2, "sse2"),
+    (Features::SSE3, "sse3"), (Features::SSSE3, "ssse3"), (Features::SSE4_1, "sse4.1"),
+    (Features::SSE4_2, "sse4.2"), (Features::AVX, "avx"), (Features::AVX2, "avx2"),
+    (Features::AVX512F, "avx512f"), (Features::XSAVE, "xsave"), (Features::X2APIC, "x2apic"),
+    (Features::TSC_DEADLINE, "tsc_deadline"), (Features::INVARIANT_TSC, "invariant_tsc"),
+    (Features::RDTSCP, "rdtscp"), (Features::NX, "nx"), (Features::PAGE_1GB, "1gb_pages"),
+    (Features::PCID, "pcid"), (Features::INVPCID, "invpcid"), (Features::SMEP, "smep"),
+    (Features::SMAP, "smap"), (Features::UMIP, "umip"), (Features::RDRAND, "rdrand"),
+    (Features::RDSEED, "rdseed"), (Features::FSGSBASE, "fsgsbase"),
+];
+
+/* What cpuid tells us about a CPU */
+#[derive(Clone, Copy)]
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub flags: Features,
+}
+
+impl CpuFeatures {
+    /* Query cpuid on the current CPU */
+    pub fn detect() -> CpuFeatures {
+        let leaf0 = cpuid(0, 0);
+        let max_leaf = leaf0.eax;
+        let max_ext_leaf = cpuid(0x80000000, 0).eax;
+
+        let mut vendor = [0u8; 12];
+        for i in 0..4 {
+            vendor[i] = (leaf0.ebx >> (8 * i)) as u8;
+            vendor[4 + i] = (leaf0.edx >> (8 * i)) as u8;
+            vendor[8 + i] = (leaf0.ecx >> (8 * i)) as u8;
+        }
+
+        let mut brand = [0u8; 48];
+        if max_ext_leaf >= 0x80000004 {
+            for leaf in 0..3 {
+                let regs = cpuid(0x80000002 + leaf, 0);
+                for (reg_idx, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+                    for byte in 0..4 {
+                        brand[leaf as usize * 16 + reg_idx * 4 + byte] = (reg >> (8 * byte)) as u8;
+                    }
+                }
+            }
+        }
+
+        let leaf1 = cpuid(1, 0);
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((leaf1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            (((leaf1.eax >> 16) & 0xF) << 4) | base_model
+        } else {
+            base_model
+        };
+
+        let mut flags = Features::empty();
+        {
+            let mut set = |feature: Features, reg: u32, bit: u32| {
+                if reg & (1 << bit) != 0 {
+                    flags.insert(feature);
+                }
+            };
+
+            set(Features::FXSR, leaf1.edx, 24);
+            set(Features::SSE, leaf1.edx, 25);
+            set(Features::SSE2, leaf1.edx, 26);
+            set(Features::SSE3, leaf1.ecx, 0);
+            set(Features::SSSE3, leaf1.ecx, 9);
+            set(Features::PCID, leaf1.ecx, 17);
+            set(Features::SSE4_1, leaf1.ecx, 19);
+            set(Features::SSE4_2, leaf1.ecx, 20);
+            set(Features::X2APIC, leaf1.ecx, 21);
+            set(Features::TSC_DEADLINE, leaf1.ecx, 24);
+            set(Features::XSAVE, leaf1.ecx, 26);
+            set(Features::AVX, leaf1.ecx, 28);
+            set(Features::RDRAND, leaf1.ecx, 30);
+
+            if max_leaf >= 7 {
+                let leaf7 = cpuid(7, 0);
+                set(Features::FSGSBASE, leaf7.ebx, 0);
+                set(Features::AVX2, leaf7.ebx, 5);
+                set(Features::SMEP, leaf7.ebx, 7);
+                set(Features::INVPCID, leaf7.ebx, 10);
+                set(Features::AVX512F, leaf7.ebx, 16);
+                set(Features::RDSEED, leaf7.ebx, 18);
+                set(Features::SMAP, leaf7.ebx, 20);
+                set(Features::UMIP, leaf7.ecx, 2);
+            }
+
+            if max_ext_leaf >= 0x80000001 {
+                let ext1 = cpuid(0x80000001, 0);
+                set(Features::NX, ext1.edx, 20);
+                set(Features::PAGE_1GB, ext1.edx, 26);
+                set(Features::RDTSCP, ext1.edx, 27);
+            }
+            if max_ext_leaf >= 0x80000007 {
+                set(Features::INVARIANT_TSC, cpuid(0x80000007, 0).edx, 8);
+            }
+        }
+
+        CpuFeatures {
+            vendor: vendor,
+            brand: brand,
+            family: family,
+            model: model,
+            stepping: leaf1.eax & 0xF,
+            flags: flags,
+        }
+    }
+
+    /* e.g. "GenuineIntel" or "AuthenticAMD" */
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /* The marketing name, if the CPU has one */
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+
+    pub fn has(&self, features: Features) -> bool {
+        self.flags.contains(features)
+    }
+}
+
+/* Log the names of `features` on one line */
+fn log_features(prefix: &str, features: Features)
+{
+    let mut writer = ::logging::Writer::get(module_path!());
+    {
+        use core::fmt::Write;
+        let _ = write!(&mut writer, "{}", prefix);
+        for &(feature, name) in FEATURE_NAMES.iter() {
+            if features.contains(feature) {
+                let _ = write!(&mut writer, " {}", name);
+            }
+        }
+    }
+}
+
+/* The boot CPU's identity, and the features every CPU started so far has */
+static mut BSP_FEATURES: Option<CpuFeatures> = None;
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+static FEATURES_READY: AtomicBool = AtomicBool::new(false);
+
+/* Features the kernel has started relying on, see `mark_used` */
+static FEATURES_USED: AtomicU32 = AtomicU32::new(0);
+
+/* Detect the features of the BSP, before anything asks for them */
+pub fn init_bsp()
+{
+    let features = CpuFeatures::detect();
+    unsafe { BSP_FEATURES = Some(features); }
+    FEATURES.store(features.flags.bits(), Ordering::SeqCst);
+    FEATURES_READY.store(true, Ordering::SeqCst);
+
+    log!("CPU: {} family 0x{:x} model 0x{:x} stepping {}, {}",
+         features.vendor(), features.family, features.model, features.stepping,
+         features.brand());
+    log_features("CPU features:", features.flags);
+}
+
+/*
+ * Compare the features of an AP with the BSP's. Features it lacks are no
+ * longer reported by `features()`, unless the kernel already relies on
+ * them, in which case the AP can't be used.
+ */
+pub fn check_ap(cpu_id: usize)
+{
+    let bsp = features();
+    let this = CpuFeatures::detect();
+
+    if this.vendor != bsp.vendor || this.family != bsp.family
+        || this.model != bsp.model || this.stepping != bsp.stepping {
+        log!("CPU {} is a {} family 0x{:x} model 0x{:x} stepping {}, unlike the BSP",
+             cpu_id, this.vendor(), this.family, this.model, this.stepping);
+    }
+
+    let missing = bsp.flags - this.flags;
+    if missing.is_empty() {
+        return;
+    }
+    log_features("An AP lacks features of the BSP, disabling:", missing);
+
+    let used = Features::from_bits_truncate(FEATURES_USED.load(Ordering::SeqCst));
+    if used.intersects(missing) {
+        panic!("CPU {} lacks features that are already in use: {:?}", cpu_id, used & missing);
+    }
+    FEATURES.fetch_and(!missing.bits(), Ordering::SeqCst);
+}
+
+/*
+ * The features of the system: the BSP's vendor and model, with the
+ * features that every CPU started so far supports.
+ */
+pub fn features() -> CpuFeatures
+{
+    assert!(FEATURES_READY.load(Ordering::SeqCst), "CPU features used before init_bsp");
+    let mut features = unsafe { BSP_FEATURES.unwrap() };
+    features.flags = Features::from_bits_truncate(FEATURES.load(Ordering::SeqCst));
+    features
+}
+
+/* Are all of `features` supported on every CPU? */
+pub fn has(features: Features) -> bool
+{
+    features().has(features)
+}
+
+/*
+ * Record that the kernel now relies on `features`, so that an AP without
+ * them is refused rather than silently misbehaving.
+ */
+pub fn mark_used(features: Features)
+{
+    assert!(has(features), "using unsupported CPU features {:?}", features);
+    FEATURES_USED.fetch_or(features.bits(), Ordering::SeqCst);
+}
+
+/*
+ * A random number for seeding, e.g. the address space layout. RDSEED and
+ * RDRAND may run dry for a moment so they are retried a few times, and
+ * without either we fall back to the TSC, which is at least hard to guess
+ * from outside the machine.
+ */
+pub fn random_u64() -> u64
+{
+    const RETRIES: usize = 10;
+
+    for &(feature, rdseed) in [(Features::RDSEED, true), (Features::RDRAND, false)].iter() {
+        if !has(feature) {
+            continue;
+        }
+        for _ in 0..RETRIES {
+            if let Some(value) = unsafe { hw_random(rdseed) } {
+                return value;
+            }
+        }
+    }
+
+    /* Mix the TSC so that the low, fast-changing bits reach the top too */
+    let mut value = rdtsc();
+    value ^= value << 29;
+    value ^= value >> 17;
+    value ^= value << 37;
+    value.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+/* A single RDSEED or RDRAND, None if it ran dry */
+#[cfg(target_arch="x86_64")]
+unsafe fn hw_random(rdseed: bool) -> Option<u64>
+{
+    let value: u64;
+    let ok: u8;
+    if rdseed {
+        asm!("rdseed $0; setc $1" : "=r"(value), "=r"(ok) ::: "volatile");
+    } else {
+        asm!("rdrand $0; setc $1" : "=r"(value), "=r"(ok) ::: "volatile");
+    }
+    if ok != 0 { Some(value) } else { None }
+}
+
+/* Without 64-bit registers, the two halves are drawn separately */
+#[cfg(target_arch="x86")]
+unsafe fn hw_random(rdseed: bool) -> Option<u64>
+{
+    let mut value: u64 = 0;
+    for _ in 0..2 {
+        let half: u32;
+        let ok: u8;
+        if rdseed {
+            asm!("rdseed $0; setc $1" : "=r"(half), "=r"(ok) ::: "volatile");
+        } else {
+            asm!("rdrand $0; setc $1" : "=r"(half), "=r"(ok) ::: "volatile");
+        }
+        if ok == 0 {
+            return None;
+        }
+        value = (value << 32) | half as u64;
+    }
+    Some(value)
+}
@@ -5,13 +5,65 @@
  * arch/x86/debug.rs
  * - Debug output channel
  *
- * Writes debug to the standard PC serial port (0x3F8 .. 0x3FF)
+ * Writes debug to a standard PC serial port, COM1 (0x3F8 .. 0x3FF) unless
+ * "console=ttyS<n>[,<baud>]" picks another one, at 115200 baud unless
+ * it says otherwise
  * 
  * == LICENCE ==
  * This code has been put into the public domain, there are no restrictions on
  * its use, and the author takes no liability.
  */
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// I/O ports of the serial ports ttyS0 to ttyS3
+const SERIAL_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+
+/// The serial port in use
+static PORT: AtomicUsize = AtomicUsize::new(0x3F8);
+
+/// The UART's clock divided by 16, the baud rate with a divisor of 1
+const BASE_BAUD: usize = 115200;
+
+kernel_param!(PARAM_CONSOLE, "console", set_console);
+
+fn set_console(value: &'static str) -> Result<(), ::cmdline::ParamError>
+{
+	if !value.starts_with("ttyS") {
+		return Err(::cmdline::ParamError::Invalid);
+	}
+	let (index, baud) = match value[4..].find(',') {
+		Some(idx) => (&value[4..4 + idx], ::cmdline::parse_number(&value[5 + idx..])?),
+		None => (&value[4..], BASE_BAUD),
+	};
+	if baud == 0 || baud > BASE_BAUD || BASE_BAUD % baud != 0 {
+		return Err(::cmdline::ParamError::Invalid);
+	}
+	match ::cmdline::parse_number(index) {
+		Ok(n) if n < SERIAL_PORTS.len() => {
+			// The firmware only sets up COM1, if that
+			unsafe { init_port(SERIAL_PORTS[n], (BASE_BAUD / baud) as u16); }
+			PORT.store(SERIAL_PORTS[n] as usize, Ordering::Relaxed);
+			Ok( () )
+		},
+		_ => Err(::cmdline::ParamError::Invalid),
+	}
+}
+
+/// Program a UART for 8N1 at BASE_BAUD / `divisor`, with the FIFOs on and
+/// its interrupts off
+unsafe fn init_port(port: u16, divisor: u16)
+{
+	use arch::x86_io::outb;
+	outb(port+1, 0x00);	// IER: no interrupts
+	outb(port+3, 0x80);	// LCR: DLAB, to reach the divisor latch
+	outb(port+0, divisor as u8);
+	outb(port+1, (divisor >> 8) as u8);
+	outb(port+3, 0x03);	// LCR: 8 data bits, no parity, 1 stop bit
+	outb(port+2, 0xC7);	// FCR: enable and clear the FIFOs, 14 byte threshold
+	outb(port+4, 0x03);	// MCR: DTR and RTS
+}
+
 /// Write a string to the output channel
 ///
 /// This method is unsafe because it does port accesses without synchronisation
@@ -28,13 +80,14 @@ pub unsafe fn puts(s: &str)
 /// This method is unsafe because it does port accesses without synchronisation
 pub unsafe fn putb(b: u8)
 {
+	let port = PORT.load(Ordering::Relaxed) as u16;
 	// Wait for the serial port's fifo to not be empty
-	while (::arch::x86_io::inb(0x3F8+5) & 0x20) == 0
+	while (::arch::x86_io::inb(port+5) & 0x20) == 0
 	{
 		// Do nothing
 	}
 	// Send the byte out the serial port
-	::arch::x86_io::outb(0x3F8, b);
+	::arch::x86_io::outb(port, b);
 	
 	// Also send to the bochs 0xe9 hack
 	::arch::x86_io::outb(0xe9, b);
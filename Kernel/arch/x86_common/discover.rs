@@ -0,0 +1,197 @@
+/*
+ * Turning what a Multiboot 1 or Multiboot2 bootloader left us into a
+ * BootInfo. Both x86 ports are booted the same way, see their start.S.
+ */
+extern crate multiboot;
+
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use self::multiboot::Multiboot;
+use boot::{BootInfo, Framebuffer, MemoryKind};
+use mm::layout::phys_to_virt;
+use super::multiboot2;
+
+/* What a Multiboot 1 bootloader leaves in %eax */
+const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2badb002;
+
+/* Multiboot 1 information flags the multiboot crate doesn't know about */
+const MULTIBOOT_INFO_FRAMEBUFFER: u32 = 1 << 12;
+
+/* Size of the Multiboot 1 information, up to the framebuffer fields */
+const MULTIBOOT_INFO_SIZE: usize = 116;
+
+const ONE_MB: usize = 1024 * 1024;
+
+pub fn paddr_to_slice<'a>(p: multiboot::PAddr, sz: usize) -> Option<&'a [u8]> {
+    unsafe {
+        let ptr = mem::transmute(phys_to_virt(p as usize));
+        Some(slice::from_raw_parts(ptr, sz))
+    }
+}
+
+/* Keep the NUL terminated string `s` the bootloader left in memory */
+fn keep_string(boot_info: &mut BootInfo, s: &str)
+{
+    let start = s.as_ptr() as usize - phys_to_virt(0);
+    boot_info.add_boot_data(start, start + s.len() + 1);
+}
+
+/*
+ * Multiboot 1 loaders (GRUB legacy, QEMU's -kernel) start the command line
+ * with the kernel's path, like argv[0], which isn't an option.
+ */
+fn skip_kernel_path(cmdline: &'static str) -> &'static str
+{
+    let cmdline = cmdline.trim_left();
+    match cmdline.find(char::is_whitespace) {
+        Some(idx) => cmdline[idx..].trim_left(),
+        None => "",
+    }
+}
+
+/* Read what a Multiboot 1 bootloader left at `info` */
+unsafe fn discover_multiboot(info: usize) -> BootInfo
+{
+    let mut boot_info = BootInfo::empty();
+    let mb = Multiboot::new(info as multiboot::PAddr, paddr_to_slice).unwrap();
+
+    /* The strings stay where the bootloader put them, as does the information */
+    boot_info.add_boot_data(info, info + MULTIBOOT_INFO_SIZE);
+    boot_info.bootloader_name = mb.boot_loader_name();
+    if let Some(name) = boot_info.bootloader_name {
+        keep_string(&mut boot_info, name);
+    }
+    if let Some(cmdline) = mb.command_line() {
+        keep_string(&mut boot_info, cmdline);
+        boot_info.cmdline = skip_kernel_path(cmdline);
+    }
+
+    if let Some(modules) = mb.modules() {
+        for module in modules {
+            if let Some(name) = module.string {
+                keep_string(&mut boot_info, name);
+            }
+            boot_info.add_module(module.start as usize, module.end as usize,
+                                 module.string.unwrap_or(""));
+        }
+    }
+
+    /* The framebuffer fields follow the VBE ones, at offset 88 */
+    let raw = phys_to_virt(info);
+    if *(raw as *const u32) & MULTIBOOT_INFO_FRAMEBUFFER != 0 {
+        boot_info.framebuffer = Some(Framebuffer {
+            addr: ptr::read_unaligned((raw + 88) as *const u64),
+            pitch: ptr::read_unaligned((raw + 96) as *const u32),
+            width: ptr::read_unaligned((raw + 100) as *const u32),
+            height: ptr::read_unaligned((raw + 104) as *const u32),
+            bpp: *((raw + 108) as *const u8),
+            fb_type: *((raw + 109) as *const u8),
+        });
+    }
+
+    /* Use the Multiboot-provided memory map, or make one up from the bounds */
+    match mb.memory_regions() {
+        Some(regions) => {
+            for area in regions {
+                add_area(&mut boot_info, area.base_address(), area.length(),
+                         area.memory_type() as u32);
+            }
+        }
+        None => {
+            let upper = mb.upper_memory_bound().unwrap() as usize * 1024;
+            boot_info.add_memory_region(ONE_MB, ONE_MB + upper, MemoryKind::Usable);
+        }
+    }
+
+    boot_info
+}
+
+/* Read what a Multiboot2 bootloader left at `info` */
+unsafe fn discover_multiboot2(info: usize) -> BootInfo
+{
+    let mut boot_info = BootInfo::empty();
+    let mb = multiboot2::Info::new(info);
+
+    /* The strings and the RSDP below are read where the bootloader put them */
+    let (start, end) = mb.range();
+    boot_info.add_boot_data(start, end);
+    boot_info.bootloader_name = mb.bootloader_name();
+    boot_info.cmdline = mb.command_line().unwrap_or("");
+    for module in mb.modules() {
+        boot_info.add_module(module.start, module.end, module.string);
+    }
+    boot_info.framebuffer = mb.framebuffer();
+    boot_info.rsdp = mb.rsdp();
+    for section in mb.elf_sections() {
+        debug!("ELF section {}: type {}, [0x{:x} - 0x{:x}]", section.name,
+               section.section_type, section.addr, section.addr + section.size);
+    }
+
+    for area in mb.memory_map() {
+        add_area(&mut boot_info, area.base, area.length, area.area_type);
+    }
+    if boot_info.memory_map().is_empty() {
+        if let Some((_, upper_kb)) = mb.basic_meminfo() {
+            boot_info.add_memory_region(ONE_MB, ONE_MB + upper_kb * 1024, MemoryKind::Usable);
+        }
+    }
+
+    boot_info
+}
+
+/*
+ * Add a memory map entry. The map is 64-bit even for 32-bit kernels,
+ * which can't address anything past 4GiB and so drop it.
+ */
+fn add_area(boot_info: &mut BootInfo, base: u64, length: u64, area_type: u32)
+{
+    let limit = usize::max_value() as u64;
+    if base > limit {
+        debug!("Ignoring memory above 4GiB at 0x{:x}", base);
+        return;
+    }
+    let end = ::core::cmp::min(base.saturating_add(length), limit);
+    boot_info.add_memory_region(base as usize, end as usize, MemoryKind::from_e820(area_type));
+}
+
+/*
+ * Find out what the bootloader knows about the system, it may speak
+ * either Multiboot 1 or 2.
+ */
+pub unsafe fn discover_boot_info() -> BootInfo
+{
+    /* External variables, where we saved the multiboot data.
+     * These are defined in arch/<arch>/start.S
+     */
+    extern {
+        static mboot_sig: u32;
+        static mboot_ptr: u32;
+    }
+
+    log!("Multiboot pointer: 0x{:08x}, Signature: 0x{:08x}", mboot_ptr, mboot_sig);
+
+    let boot_info = match mboot_sig {
+        MULTIBOOT_BOOTLOADER_MAGIC => discover_multiboot(mboot_ptr as usize),
+        multiboot2::BOOTLOADER_MAGIC => discover_multiboot2(mboot_ptr as usize),
+        _ => panic!("Invalid Multiboot signature: 0x{:x}", mboot_sig),
+    };
+
+    if let Some(name) = boot_info.bootloader_name {
+        log!("Booted by {}", name);
+    }
+    log!("Command line: {}", boot_info.cmdline);
+    for module in boot_info.modules() {
+        log!("Module '{}' at [0x{:x} - 0x{:x}]", module.name, module.start, module.end);
+    }
+    for region in boot_info.memory_map() {
+        log!("[0x{:x} - 0x{:x}] (length: {} Kb): {:?}", region.start, region.end,
+             (region.end - region.start) / 1024, region.kind);
+    }
+    if let Some(fb) = boot_info.framebuffer {
+        log!("Framebuffer at 0x{:x}: {}x{}, {} bpp", fb.addr, fb.width, fb.height, fb.bpp);
+    }
+
+    boot_info
+}
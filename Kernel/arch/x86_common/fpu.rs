@@ -0,0 +1,195 @@
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+
+use super::cpu::{self, Features};
+use super::percpu;
+
+/* CR0 and CR4 bits */
+const CR0_MP: usize = 1 << 1;
+const CR0_EM: usize = 1 << 2;
+const CR0_TS: usize = 1 << 3;
+const CR0_NE: usize = 1 << 5;
+const CR4_OSFXSR: usize = 1 << 9;
+const CR4_OSXMMEXCPT: usize = 1 << 10;
+const CR4_OSXSAVE: usize = 1 << 18;
+
+/* XCR0 state components */
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/* Size of the legacy FXSAVE area */
+pub const FXSAVE_SIZE: usize = 512;
+
+/* Where MXCSR lives in the FXSAVE/XSAVE area, and its reset value */
+pub const MXCSR_OFFSET: usize = 24;
+pub const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/* The state components enabled in XCR0 on every CPU, 0 without XSAVE */
+static XCR0: AtomicU64 = AtomicU64::new(0);
+
+/* Size of the save area every task needs */
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+unsafe fn xsetbv(xcr: u32, value: u64)
+{
+    asm!("xsetbv" :: "{ecx}"(xcr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+         :: "volatile");
+}
+
+/*
+ * Enable the FPU, SSE and, if the CPU has XSAVE, AVX and AVX-512 on the
+ * current CPU. The BSP decides what is enabled, the APs follow it.
+ */
+pub fn init_cpu(bsp: bool)
+{
+    /*
+     * Part of the x86_64 baseline, and required of i686 CPUs too. The kernel
+     * relies on them from now on.
+     */
+    cpu::mark_used(Features::FXSR | Features::SSE | Features::SSE2);
+
+    unsafe {
+        /* Native #MF error reporting, no emulation and no lazy switching */
+        cpu::write_cr0((cpu::read_cr0() | CR0_MP | CR0_NE) & !(CR0_EM | CR0_TS));
+
+        let mut cr4 = cpu::read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+        if bsp && cpu::has(Features::XSAVE) {
+            cpu::mark_used(Features::XSAVE);
+            let mut xcr0 = XCR0_X87 | XCR0_SSE;
+            if cpu::has(Features::AVX) {
+                cpu::mark_used(Features::AVX);
+                xcr0 |= XCR0_AVX;
+            }
+            if cpu::has(Features::AVX512F) {
+                cpu::mark_used(Features::AVX512F);
+                xcr0 |= XCR0_AVX512;
+            }
+            XCR0.store(xcr0, Ordering::SeqCst);
+        }
+
+        let xcr0 = XCR0.load(Ordering::SeqCst);
+        if xcr0 != 0 {
+            cr4 |= CR4_OSXSAVE;
+        }
+        cpu::write_cr4(cr4);
+
+        if xcr0 != 0 {
+            xsetbv(0, xcr0);
+            if bsp {
+                /* Size of the area for the components enabled in XCR0 */
+                AREA_SIZE.store(cpu::cpuid(0xD, 0).ebx as usize, Ordering::SeqCst);
+            }
+        }
+
+        asm!("fninit" :::: "volatile");
+    }
+
+    if bsp {
+        log!("FPU enabled, {} with a {} byte save area",
+             if uses_xsave() { "XSAVE" } else { "FXSAVE" }, area_size());
+    }
+}
+
+pub fn uses_xsave() -> bool
+{
+    XCR0.load(Ordering::Relaxed) != 0
+}
+
+/* Size of the area `save` needs, valid once the BSP has called init_cpu */
+pub fn area_size() -> usize
+{
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/* Save the FPU and vector registers into `area`, 64-byte aligned */
+#[cfg(target_arch="x86_64")]
+pub unsafe fn save(area: *mut u8)
+{
+    if uses_xsave() {
+        asm!("xsave64 ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "volatile");
+    } else {
+        asm!("fxsave64 ($0)" :: "r"(area) : "memory" : "volatile");
+    }
+}
+
+#[cfg(target_arch="x86")]
+pub unsafe fn save(area: *mut u8)
+{
+    if uses_xsave() {
+        asm!("xsave ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "volatile");
+    } else {
+        asm!("fxsave ($0)" :: "r"(area) : "memory" : "volatile");
+    }
+}
+
+/* Load the registers saved by `save` */
+#[cfg(target_arch="x86_64")]
+pub unsafe fn restore(area: *const u8)
+{
+    if uses_xsave() {
+        asm!("xrstor64 ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "volatile");
+    } else {
+        asm!("fxrstor64 ($0)" :: "r"(area) : "memory" : "volatile");
+    }
+}
+
+#[cfg(target_arch="x86")]
+pub unsafe fn restore(area: *const u8)
+{
+    if uses_xsave() {
+        asm!("xrstor ($0)" :: "r"(area), "{eax}"(!0u32), "{edx}"(!0u32) : "memory" : "volatile");
+    } else {
+        asm!("fxrstor ($0)" :: "r"(area) : "memory" : "volatile");
+    }
+}
+
+/*
+ * Lets kernel code use SIMD registers until dropped, with preemption
+ * disabled. The kernel is built without SSE, so such code has to enable
+ * it with #[target_feature]. Not usable in interrupt context, and it
+ * doesn't nest.
+ */
+pub struct KernelFpuGuard {
+    /* Must be dropped on the CPU it was taken on */
+    _not_send: PhantomData<*const ()>,
+}
+
+pub fn kernel_fpu_begin() -> KernelFpuGuard
+{
+    assert!(!percpu::in_interrupt(), "kernel_fpu_begin in interrupt context");
+    ::sched::preempt_disable();
+
+    let area = unsafe { percpu::this_cpu_area() };
+    assert!(!area.kernel_fpu, "kernel_fpu_begin doesn't nest");
+    area.kernel_fpu = true;
+
+    /* The task's registers are live, keep them safe from the kernel code */
+    let task = ::sched::current();
+    if !task.is_null() {
+        unsafe { save((*task).fpu.area_mut()); }
+    }
+    KernelFpuGuard { _not_send: PhantomData }
+}
+
+impl Drop for KernelFpuGuard {
+    fn drop(&mut self) {
+        let task = ::sched::current();
+        unsafe {
+            if !task.is_null() {
+                restore((*task).fpu.area());
+            } else {
+                asm!("fninit" :::: "volatile");
+            }
+            percpu::this_cpu_area().kernel_fpu = false;
+        }
+        ::sched::preempt_enable();
+    }
+}
+
+/* Explicit form of dropping the guard */
+pub fn kernel_fpu_end(guard: KernelFpuGuard)
+{
+    drop(guard);
+}
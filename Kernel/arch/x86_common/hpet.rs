@@ -0,0 +1,96 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use mm::pmm::FrameAllocator;
+use mm::vmm;
+
+/* HPET registers */
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_MAIN_COUNTER: usize = 0xF0;
+
+/* The spec caps the counter period at 100ns */
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+static HPET_BASE: AtomicUsize = AtomicUsize::new(0);
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(target_arch="x86_64")]
+fn read_u64(register: usize) -> u64
+{
+    let base = HPET_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::read_volatile((base + register) as *const u64) }
+}
+
+/*
+ * Without 64-bit accesses the halves are read separately, the high one is
+ * read again to catch the low one wrapping in between.
+ */
+#[cfg(target_arch="x86")]
+fn read_u64(register: usize) -> u64
+{
+    let reg = (HPET_BASE.load(Ordering::Relaxed) + register) as *const u32;
+    unsafe {
+        loop {
+            let high = ptr::read_volatile(reg.offset(1));
+            let low = ptr::read_volatile(reg);
+            if ptr::read_volatile(reg.offset(1)) == high {
+                return ((high as u64) << 32) | low as u64;
+            }
+        }
+    }
+}
+
+#[cfg(target_arch="x86_64")]
+fn write_u64(register: usize, value: u64)
+{
+    let base = HPET_BASE.load(Ordering::Relaxed);
+    unsafe { ptr::write_volatile((base + register) as *mut u64, value) }
+}
+
+#[cfg(target_arch="x86")]
+fn write_u64(register: usize, value: u64)
+{
+    let reg = (HPET_BASE.load(Ordering::Relaxed) + register) as *mut u32;
+    unsafe {
+        ptr::write_volatile(reg, value as u32);
+        ptr::write_volatile(reg.offset(1), (value >> 32) as u32);
+    }
+}
+
+/*
+ * Set up the HPET described by the ACPI table at `table` and start its
+ * main counter. Returns false if it's unusable.
+ */
+pub fn init(fma: &mut FrameAllocator, table: usize) -> bool
+{
+    /* The base address is in the generic address structure at offset 40 */
+    let base = unsafe { ptr::read_unaligned((table + 44) as *const u64) } as usize;
+    HPET_BASE.store(vmm::map_io(fma, base, 0x400), Ordering::Relaxed);
+
+    let period = read_u64(HPET_CAPABILITIES) >> 32;
+    if period == 0 || period > HPET_MAX_PERIOD_FS {
+        log!("HPET at 0x{:x} reports a bogus period of {} fs", base, period);
+        return false;
+    }
+    HPET_PERIOD_FS.store(period, Ordering::Relaxed);
+
+    /* Enable the main counter */
+    write_u64(HPET_CONFIG, read_u64(HPET_CONFIG) | 1);
+
+    log!("HPET at 0x{:x}, {} fs per tick", base, period);
+    true
+}
+
+/* Whether `init` found a usable HPET */
+pub fn is_present() -> bool
+{
+    HPET_PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/* The HPET main counter in nanoseconds */
+pub fn read_ns() -> u64
+{
+    let period = HPET_PERIOD_FS.load(Ordering::Relaxed);
+    (read_u64(HPET_MAIN_COUNTER) as u128 * period as u128 / 1_000_000) as u64
+}
@@ -0,0 +1,67 @@
+/* RFLAGS.IF, set when maskable interrupts are enabled */
+const RFLAGS_IF: usize = 1 << 9;
+
+#[cfg(target_arch="x86_64")]
+fn read_flags() -> usize
+{
+    let flags: usize;
+    unsafe { asm!("pushfq; popq $0" : "=r"(flags) :: "memory" : "volatile"); }
+    flags
+}
+
+#[cfg(target_arch="x86")]
+fn read_flags() -> usize
+{
+    let flags: usize;
+    unsafe { asm!("pushfl; popl $0" : "=r"(flags) :: "memory" : "volatile"); }
+    flags
+}
+
+/* Mask interrupts on the current CPU */
+pub fn disable()
+{
+    unsafe { asm!("cli" ::: "memory" : "volatile"); }
+}
+
+/*
+ * Unmask interrupts on the current CPU.
+ *
+ * This is unsafe, since an interrupt arriving before an IDT is installed
+ * will triple fault the machine.
+ */
+pub unsafe fn enable()
+{
+    asm!("sti" :::: "volatile");
+}
+
+/*
+ * Enable interrupts and halt until the next one arrives. sti only takes
+ * effect after the following instruction, so no interrupt can sneak in
+ * between the two and leave us halted.
+ */
+pub unsafe fn enable_and_halt()
+{
+    asm!("sti; hlt" :::: "volatile");
+}
+
+/* Are interrupts currently enabled on this CPU? */
+pub fn enabled() -> bool
+{
+    read_flags() & RFLAGS_IF != 0
+}
+
+/* Disable interrupts and return the previous RFLAGS for `restore` */
+pub fn save_and_disable() -> usize
+{
+    let flags = read_flags();
+    disable();
+    flags
+}
+
+/* Restore the interrupt state saved by `save_and_disable` */
+pub fn restore(flags: usize)
+{
+    if flags & RFLAGS_IF != 0 {
+        unsafe { enable(); }
+    }
+}
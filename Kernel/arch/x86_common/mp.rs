@@ -0,0 +1,334 @@
+/*
+ * Finding the processors in the MP tables and waking the APs with
+ * INIT/SIPI through the LAPIC. The APs start in the trampoline of
+ * arch/<arch>/start.S and end up in the arch's `new_cpu_init`.
+ */
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::Vec;
+use alloc::boxed::Box;
+
+use sync::rcu::{self, Rcu};
+
+use x86_mp::{MPEntryCode, MPFloatingPointer, MPConfigurationTableHeader};
+
+use mm::layout::phys_to_virt;
+use mm::pmm::FrameAllocator;
+use super::{apic, gdt, irq, msr, percpu};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Processor {
+    pub id: usize,
+    pub apic_id: usize,
+    pub stack_frame: usize,
+    pub percpu_area: usize,
+    pub cpu_tables: usize,
+}
+
+/*
+ * The processors found at boot. Read locklessly on hot paths such as
+ * send_ipi, so it is RCU protected and only ever replaced as a whole.
+ */
+static PROCESSORS: Rcu<Vec<Processor>> = Rcu::empty();
+
+/* The processor start_aps is currently waking, handed over to new_cpu_init */
+static mut AP_BOOT_PROCESSOR: Processor = Processor {
+    id: 0,
+    apic_id: 0,
+    stack_frame: 0,
+    percpu_area: 0,
+    cpu_tables: 0,
+};
+
+/* "maxcpus=<n>" only brings up the first n processors, "nosmp" only the BSP */
+static MAX_CPUS: AtomicUsize = AtomicUsize::new(usize::max_value());
+
+kernel_param!(PARAM_NOSMP, flag "nosmp", disable_smp);
+kernel_param!(PARAM_MAXCPUS, "maxcpus", set_max_cpus);
+
+fn disable_smp()
+{
+    MAX_CPUS.store(1, Ordering::Relaxed);
+}
+
+fn set_max_cpus(value: &'static str) -> Result<(), ::cmdline::ParamError>
+{
+    match ::cmdline::parse_number(value)? {
+        0 => Err(::cmdline::ParamError::Invalid),
+        n => {
+            MAX_CPUS.store(n, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/* Physical address of the LAPIC registers, identity mapped */
+static mut LAPIC_ADDR: usize = 0;
+
+pub fn lapic_addr() -> usize
+{
+    unsafe { LAPIC_ADDR }
+}
+
+/* The processor being woken up, for the AP itself to find out who it is */
+pub unsafe fn ap_boot_processor() -> Processor
+{
+    AP_BOOT_PROCESSOR
+}
+
+/* Run `f` on the list of processors, inside an RCU read-side section */
+pub fn with_processors<R, F: FnOnce(&[Processor]) -> R>(f: F) -> R
+{
+    let guard = rcu::read_lock();
+    match PROCESSORS.read(&guard) {
+        Some(list) => f(list),
+        None => f(&[]),
+    }
+}
+
+/* The processor with id `cpu` */
+pub fn processor(cpu: usize) -> Option<Processor>
+{
+    with_processors(|list| list.get(cpu).cloned())
+}
+
+/* Number of processors found at boot */
+pub fn cpu_count() -> usize
+{
+    with_processors(|list| list.len())
+}
+
+fn copy_smp_into_to(target_addr: usize, start: usize, end: usize)
+{
+    /* verify that the code there is correct */
+    unsafe {
+        let verify_ptr: *const u32 = phys_to_virt(target_addr) as *const u32;
+        let data: u32 = *verify_ptr;
+        log!("Data at 0x{:016x} is 0x{:08x}", target_addr, data);
+    }
+}
+
+unsafe fn find_mp_tables() -> usize
+{
+    let base_mem_location: *const u16 = phys_to_virt(0x413) as *const u16;
+    let base_mem_size: u16 = *base_mem_location;
+    let base_mem_end: usize = (base_mem_size as usize) << 10;
+    let search_mem_start: usize = (base_mem_end - (2 << 10));
+    log!("Base memory size: {} KiB => [0x0 - 0x{:x}]", base_mem_size, base_mem_end);
+
+    let mut search_now: *const u32 = phys_to_virt(search_mem_start) as *const u32;
+    loop {
+        if (search_now as usize) >= base_mem_end {
+            log!("Didn't find MP tables in base memory");
+            break;
+        }
+
+        if *search_now == 0x5F504D5F {
+            log!("found MP tables at 0x{:016x}", search_now as usize);
+            return 0;
+        }
+
+        search_now = ((search_now as usize) + 16) as *const u32;
+    }
+
+    search_now = phys_to_virt(0x9fc00) as *const u32;
+    loop {
+        if (search_now as usize) >= phys_to_virt(0x9ffff) {
+            log!("Didn't find MP tables in EBDA memory");
+            break;
+        }
+
+        if *search_now == 0x5F504D5F {
+            log!("found MP tables at 0x{:016x} in EBDA", search_now as usize);
+            return 0;
+        }
+
+        search_now = ((search_now as usize) + 16) as *const u32;
+    }
+
+    extern {
+        static SMP_AP_START: u32;
+        static SMP_AP_END: u32;
+    }
+    let smp_ap_start_addr: usize = unsafe { mem::transmute(&SMP_AP_START) };
+    let smp_ap_end_addr: usize = unsafe { mem::transmute(&SMP_AP_END) };
+    log!("SMP_AP_START: [0x{:016x} - 0x{:016x}]",
+         smp_ap_start_addr, smp_ap_end_addr);
+
+    /* FIXME: this is not needed */
+    copy_smp_into_to(0xA000, smp_ap_start_addr, smp_ap_end_addr);
+
+    search_now = phys_to_virt(0xa000) as *const u32;
+    loop {
+        if (search_now as usize) >= phys_to_virt(0xfffff) {
+            log!("Didn't find MP tables in ROM memory");
+            break;
+        }
+
+        if *search_now == 0x5F504D5F {
+            log!("found MP tables at 0x{:016x} in ROM", search_now as usize);
+            return search_now as usize;
+        }
+
+        search_now = ((search_now as usize) + 16) as *const u32;
+    }
+    0
+}
+
+/* Register the BSP as the only processor */
+fn add_bsp_only(fma: &mut FrameAllocator)
+{
+    unsafe {
+        /* Without MP tables, the LAPIC is wherever IA32_APIC_BASE says */
+        LAPIC_ADDR = (msr::rdmsr(msr::IA32_APIC_BASE) & !0xFFF) as usize;
+        percpu::this_cpu_area().lapic = Some(apic::LAPIC::new(fma, LAPIC_ADDR, 0));
+
+        PROCESSORS.publish(Box::new(vec![Processor {
+            id: 0,
+            apic_id: 0,
+            stack_frame: 0,
+            percpu_area: percpu::bsp_area(),
+            cpu_tables: gdt::allocate(fma),
+        }]));
+    }
+}
+
+pub fn enumerate_processors(fma: &mut FrameAllocator) -> usize
+{
+    let mp_ptr_location = unsafe { find_mp_tables() as *const MPFloatingPointer };
+    let mp_ptr: MPFloatingPointer;
+
+    if mp_ptr_location as usize != 0 {
+        unsafe {
+            mp_ptr = *mp_ptr_location;
+        }
+    } else {
+        log!("MP Table not found, assuming 1 CPU");
+        add_bsp_only(fma);
+        return 1;
+    }
+
+    if (mp_ptr.is_valid()) {
+        log!("Enumerating available processors...");
+        let processors;
+        unsafe {
+            let mp_hdr_loc = phys_to_virt(mp_ptr.physical_address_pointer as usize);
+            let mp_hdr: MPConfigurationTableHeader = *(mp_hdr_loc as *const MPConfigurationTableHeader);
+            log!("MP header has {} entries, at 0x{:016x}, LAPIC at 0x{:016x}",
+                 mp_hdr.entry_count, mp_hdr_loc, mp_hdr.local_apic_addr);
+
+            LAPIC_ADDR = mp_hdr.local_apic_addr as usize;
+            let mut list = Vec::new();
+            let lapic: apic::LAPIC = apic::LAPIC::new(fma, LAPIC_ADDR, 0);
+
+            /*
+             * Allocate the stack frames, GDT/TSS and the per-CPU areas for
+             * the APs, the BSP (id 0) keeps using its static area.
+             */
+            for entry in mp_hdr.iter(mp_hdr_loc) {
+                if entry.code != MPEntryCode::Processor {
+                    continue;
+                }
+                let proc = entry.get_processor_entry().unwrap();
+                let id = list.len();
+                if id == MAX_CPUS.load(Ordering::Relaxed) {
+                    log!("Not using the processor with APIC id {}", proc.lapic_id);
+                    continue;
+                }
+                let percpu_area = if id == 0 {
+                    percpu::bsp_area()
+                } else {
+                    phys_to_virt(fma.allocate_frame().frame_addr())
+                };
+                list.push(Processor {
+                    stack_frame: fma.allocate_frame().frame_addr(),
+                    id: id,
+                    apic_id: proc.lapic_id as usize,
+                    percpu_area: percpu_area,
+                    cpu_tables: gdt::allocate(fma),
+                });
+            }
+            processors = list.len();
+            PROCESSORS.publish(Box::new(list));
+
+            percpu::this_cpu_area().lapic = Some(lapic);
+        };
+        log!("Found {} processors in total", processors);
+        return processors;
+    } else {
+        log!("MP table was invalid, assuming 1 CPU");
+        add_bsp_only(fma);
+        return 1;
+    }
+}
+
+/*
+ * Wake up the APs found by enumerate_processors, one at a time, since they
+ * share the boot stack until they have switched to their own.
+ */
+pub fn start_aps()
+{
+    let lapic = match unsafe { percpu::this_cpu_area() }.lapic {
+        Some(ref lapic) => lapic,
+        None => return,
+    };
+    let mut b_c: u32 = 0;
+    let mut __did_an_ap_boot: u32;
+
+    let list = with_processors(|list| list.to_vec());
+    unsafe {
+        for proc in list.iter() {
+            if proc.apic_id == 0 {
+                continue;
+            }
+
+            asm!("mfence; movl did_an_ap_boot, $0":"=r"(__did_an_ap_boot));
+            if __did_an_ap_boot != b_c {
+                log!("Reached maximum parallel boot, waiting...");
+                loop {
+                    asm!("mfence; movl did_an_ap_boot, $0":"=r"(__did_an_ap_boot));
+                    if __did_an_ap_boot == b_c {
+                        log!("Parallel boot hang done.");
+                        break;
+                    }
+                }
+            }
+
+            /* The previous AP is done with it, see new_cpu_init */
+            AP_BOOT_PROCESSOR = *proc;
+            asm!("mfence" :::: "volatile");
+
+            lapic.send_init_to(proc.apic_id as u8);
+            let mut wait = 400000;
+            loop {
+                wait = wait - 1;
+                if wait == 0 {
+                    break;
+                }
+            }
+            lapic.send_sipi_to(proc.apic_id as u8, 0xA);
+            let mut wait = 400000;
+            loop {
+                wait = wait - 1;
+                if wait == 0 {
+                    break;
+                }
+            }
+            b_c += 1;
+        }
+    }
+}
+
+/* Send the interrupt `vector` to the CPU with id `cpu` */
+pub fn send_ipi(cpu: usize, vector: u8)
+{
+    let apic_id = match processor(cpu) {
+        Some(proc) => proc.apic_id as u8,
+        None => panic!("IPI to CPU {}, which doesn't exist", cpu),
+    };
+    let flags = irq::save_and_disable();
+    if let Some(ref lapic) = unsafe { percpu::this_cpu_area() }.lapic {
+        lapic.send_ipi_to(apic_id, vector);
+    }
+    irq::restore(flags);
+}
@@ -0,0 +1,27 @@
+/* Model specific registers used by the kernel */
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+pub const IA32_EFER: u32 = 0xC0000080;
+pub const IA32_STAR: u32 = 0xC0000081;
+pub const IA32_LSTAR: u32 = 0xC0000082;
+pub const IA32_FMASK: u32 = 0xC0000084;
+pub const IA32_FS_BASE: u32 = 0xC0000100;
+pub const IA32_GS_BASE: u32 = 0xC0000101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+pub const IA32_TSC_AUX: u32 = 0xC0000103;
+
+/* Read the model specific register `msr` */
+pub unsafe fn rdmsr(msr: u32) -> u64
+{
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    ((high as u64) << 32) | (low as u64)
+}
+
+/* Write `value` into the model specific register `msr` */
+pub unsafe fn wrmsr(msr: u32, value: u64)
+{
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+         :: "volatile");
+}
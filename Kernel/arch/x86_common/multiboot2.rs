@@ -0,0 +1,229 @@
+/*
+ * The Multiboot2 boot information. It is a list of tags after an 8 byte
+ * header, each tag starts with its type and size and is padded to 8 bytes,
+ * the list ends with a tag of type 0.
+ *
+ * See the Multiboot2 specification, section 3.6.
+ */
+use core::mem;
+use core::ptr;
+use core::slice;
+use core::str;
+
+use boot::Framebuffer;
+use mm::layout::phys_to_virt;
+
+/* What the bootloader leaves in %eax */
+pub const BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+/* Tag types */
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ELF_SECTIONS: u32 = 9;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+/* The fixed part of every tag */
+const TAG_HEADER_SIZE: usize = 8;
+
+/* The boot information, at a physical address the bootloader picked */
+pub struct Info {
+    addr: usize,
+    size: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct Tag {
+    pub tag_type: u32,
+    /* Physical address of the tag, including the header */
+    pub addr: usize,
+    /* The tag without the header */
+    pub data: &'static [u8],
+}
+
+impl Tag {
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + mem::size_of::<T>() <= self.data.len(), "multiboot2 tag too short");
+        unsafe { ptr::read_unaligned(self.data[offset..].as_ptr() as *const T) }
+    }
+
+    /* A NUL terminated string starting at `offset` */
+    fn string(&self, offset: usize) -> &'static str {
+        let bytes = &self.data[offset..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..end]).unwrap_or("")
+    }
+}
+
+pub struct Tags {
+    next: usize,
+    end: usize,
+}
+
+impl Iterator for Tags {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.next + TAG_HEADER_SIZE > self.end {
+            return None;
+        }
+        let (tag_type, size) = unsafe {
+            let header = phys_to_virt(self.next) as *const u32;
+            (*header, *header.offset(1) as usize)
+        };
+        if tag_type == TAG_END || size < TAG_HEADER_SIZE || self.next + size > self.end {
+            return None;
+        }
+        let tag = Tag {
+            tag_type: tag_type,
+            addr: self.next,
+            data: unsafe {
+                slice::from_raw_parts((phys_to_virt(self.next) + TAG_HEADER_SIZE) as *const u8,
+                                      size - TAG_HEADER_SIZE)
+            },
+        };
+        self.next += (size + 7) & !7;
+        Some(tag)
+    }
+}
+
+/* A region of the memory map, `area_type` 1 is usable RAM */
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub length: u64,
+    pub area_type: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    pub start: usize,
+    pub end: usize,
+    pub string: &'static str,
+}
+
+/* A section header of the kernel image, which may be ELF32 or ELF64 */
+#[derive(Clone, Copy, Debug)]
+pub struct ElfSection {
+    pub name: u32,
+    pub section_type: u32,
+    pub addr: u64,
+    pub size: u64,
+}
+
+impl Info {
+    /* The information at physical address `addr` */
+    pub unsafe fn new(addr: usize) -> Info {
+        let size = *(phys_to_virt(addr) as *const u32) as usize;
+        Info { addr: addr, size: size }
+    }
+
+    /* The physical range the information occupies */
+    pub fn range(&self) -> (usize, usize) {
+        (self.addr, self.addr + self.size)
+    }
+
+    pub fn tags(&self) -> Tags {
+        Tags { next: self.addr + 8, end: self.addr + self.size }
+    }
+
+    fn tag(&self, tag_type: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.tag_type == tag_type)
+    }
+
+    pub fn command_line(&self) -> Option<&'static str> {
+        self.tag(TAG_CMDLINE).map(|tag| tag.string(0))
+    }
+
+    pub fn bootloader_name(&self) -> Option<&'static str> {
+        self.tag(TAG_BOOTLOADER_NAME).map(|tag| tag.string(0))
+    }
+
+    /* KiB of memory below 1MiB and from 1MiB up to the first hole */
+    pub fn basic_meminfo(&self) -> Option<(usize, usize)> {
+        self.tag(TAG_BASIC_MEMINFO)
+            .map(|tag| (tag.read::<u32>(0) as usize, tag.read::<u32>(4) as usize))
+    }
+
+    pub fn memory_map<'a>(&'a self) -> impl Iterator<Item = MemoryArea> + 'a {
+        /* entry_size, entry_version, then the entries */
+        let tag = self.tag(TAG_MMAP);
+        let entry_size = tag.map_or(0, |tag| tag.read::<u32>(0) as usize);
+        let count = match tag {
+            Some(tag) if entry_size >= 24 => (tag.data.len() - 8) / entry_size,
+            _ => 0,
+        };
+        (0..count).map(move |idx| {
+            let tag = tag.unwrap();
+            let offset = 8 + idx * entry_size;
+            MemoryArea {
+                base: tag.read(offset),
+                length: tag.read(offset + 8),
+                area_type: tag.read(offset + 16),
+            }
+        })
+    }
+
+    pub fn modules<'a>(&'a self) -> impl Iterator<Item = Module> + 'a {
+        self.tags().filter(|tag| tag.tag_type == TAG_MODULE).map(|tag| Module {
+            start: tag.read::<u32>(0) as usize,
+            end: tag.read::<u32>(4) as usize,
+            string: tag.string(8),
+        })
+    }
+
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.tag(TAG_FRAMEBUFFER).map(|tag| Framebuffer {
+            addr: tag.read(0),
+            pitch: tag.read(8),
+            width: tag.read(12),
+            height: tag.read(16),
+            bpp: tag.read(20),
+            fb_type: tag.read(21),
+        })
+    }
+
+    /*
+     * Physical address of the copy of the RSDP the bootloader made,
+     * preferring the ACPI 2.0 one.
+     */
+    pub fn rsdp(&self) -> Option<usize> {
+        self.tag(TAG_ACPI_NEW).or_else(|| self.tag(TAG_ACPI_OLD))
+            .map(|tag| tag.addr + TAG_HEADER_SIZE)
+    }
+
+    pub fn elf_sections<'a>(&'a self) -> impl Iterator<Item = ElfSection> + 'a {
+        /* num, entsize and shndx, a u32 each as GRUB writes them */
+        let tag = self.tag(TAG_ELF_SECTIONS);
+        let (count, entry_size) = match tag {
+            Some(tag) => (tag.read::<u32>(0) as usize, tag.read::<u32>(4) as usize),
+            None => (0, 0),
+        };
+        (0..count).filter_map(move |idx| {
+            let tag = tag.unwrap();
+            let offset = 12 + idx * entry_size;
+            match entry_size {
+                /* Elf32_Shdr */
+                40 => Some(ElfSection {
+                    name: tag.read(offset),
+                    section_type: tag.read(offset + 4),
+                    addr: tag.read::<u32>(offset + 12) as u64,
+                    size: tag.read::<u32>(offset + 20) as u64,
+                }),
+                /* Elf64_Shdr */
+                64 => Some(ElfSection {
+                    name: tag.read(offset),
+                    section_type: tag.read(offset + 4),
+                    addr: tag.read(offset + 16),
+                    size: tag.read(offset + 32),
+                }),
+                _ => None,
+            }
+        })
+    }
+}
@@ -0,0 +1,261 @@
+/*
+ * PCI configuration space access for pci/mod.rs. The memory mapped ECAM
+ * areas listed in the ACPI MCFG table are used where there are any, the
+ * legacy ports 0xCF8/0xCFC otherwise. Only ECAM reaches the extended
+ * configuration space above offset 0x100, and segments other than 0.
+ */
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::Vec;
+use alloc::boxed::Box;
+
+use mm::layout;
+use mm::pmm;
+use mm::vmm;
+use pci::Address;
+use sync::IrqSpinLock;
+use sync::rcu::{self, Rcu};
+use super::acpi::{self, SdtHeader};
+use super::x86_io::{inb, inw, inl, outb, outw, outl};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/* The MCFG table, a reserved quadword and then one entry per ECAM area */
+#[repr(C, packed)]
+struct McfgEntry {
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    reserved: u32,
+}
+
+const MCFG_ENTRIES: usize = 44;
+
+/*
+ * How much of the vmalloc area ECAM may take, a MiB per bus. That's all
+ * 256 buses of a segment on x86_64 but only 16 on i686, whose other buses
+ * are left to the ports.
+ */
+const ECAM_MAX_SIZE: usize = layout::VMALLOC_SIZE / 4;
+
+/* The configuration space of buses start_bus to end_bus of a segment */
+struct EcamArea {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    /* Where start_bus is mapped, the area is mapped whole by find_ecam */
+    virt: usize,
+}
+
+static ECAM: Rcu<Vec<EcamArea>> = Rcu::empty();
+
+/* "pci=noecam" sticks to the legacy ports */
+static NO_ECAM: AtomicBool = AtomicBool::new(false);
+
+/* Whether the legacy ports work, checked by init */
+static HAVE_PORTS: AtomicBool = AtomicBool::new(false);
+
+/* The address and data ports are used in pairs, from interrupt handlers as well */
+static PORTS_LOCK: IrqSpinLock<()> = IrqSpinLock::named("pci config ports", ());
+
+kernel_param!(PARAM_PCI, "pci", set_pci_option);
+
+fn set_pci_option(value: &'static str) -> Result<(), ::cmdline::ParamError>
+{
+    match value {
+        "noecam" => NO_ECAM.store(true, Ordering::Relaxed),
+        _ => return Err(::cmdline::ParamError::Invalid),
+    }
+    Ok(())
+}
+
+/*
+ * Read the ECAM areas from the MCFG table, if there's one, and map them:
+ * configuration space is accessed from interrupt handlers too, which can't
+ * allocate page tables.
+ */
+fn find_ecam() -> Vec<EcamArea>
+{
+    let mut areas = Vec::new();
+    let mut mapped = 0;
+    let table = match pmm::with_frames(|fma| {
+        if acpi::init() { acpi::find_table(fma, b"MCFG") } else { None }
+    }) {
+        Some(table) => table,
+        None => return areas,
+    };
+
+    let length = unsafe { (*(table as *const SdtHeader)).length as usize };
+    let mut entry = table + MCFG_ENTRIES;
+    while entry + mem::size_of::<McfgEntry>() <= table + length {
+        let mcfg = unsafe { ptr::read_unaligned(entry as *const McfgEntry) };
+        entry += mem::size_of::<McfgEntry>();
+
+        let (base, segment) = (mcfg.base, mcfg.segment);
+        if mcfg.end_bus < mcfg.start_bus {
+            warn!("Ignoring the ECAM area of segment {} with buses {} to {}",
+                  segment, mcfg.start_bus, mcfg.end_bus);
+            continue;
+        }
+        /* Without PAE an i686 kernel can't map anything above 4GiB */
+        if base + ((mcfg.end_bus as u64 + 1) << 20) - 1 > usize::max_value() as u64 {
+            warn!("Ignoring the ECAM area of segment {} at 0x{:x}, out of reach",
+                  segment, base);
+            continue;
+        }
+        let buses = ::core::cmp::min(mcfg.end_bus as usize - mcfg.start_bus as usize + 1,
+                                     (ECAM_MAX_SIZE - mapped) >> 20);
+        if buses == 0 {
+            warn!("Ignoring the ECAM area of segment {}, no room to map it", segment);
+            continue;
+        }
+        let end_bus = mcfg.start_bus + (buses - 1) as u8;
+        if end_bus != mcfg.end_bus {
+            warn!("Only mapping buses {} to {} of the ECAM area of segment {}",
+                  mcfg.start_bus, end_bus, segment);
+        }
+
+        log!("ECAM for segment {} buses {} to {} at 0x{:x}",
+             segment, mcfg.start_bus, end_bus, base);
+        let phys = base as usize + ((mcfg.start_bus as usize) << 20);
+        mapped += buses << 20;
+        areas.push(EcamArea {
+            segment: segment,
+            start_bus: mcfg.start_bus,
+            end_bus: end_bus,
+            virt: pmm::with_frames(|fma| vmm::map_io(fma, phys, buses << 20)),
+        });
+    }
+    areas
+}
+
+/*
+ * Check that the ports decode configuration mechanism #1: the address port
+ * holds what was written to it.
+ */
+fn probe_ports() -> bool
+{
+    let _guard = PORTS_LOCK.lock();
+    unsafe {
+        let old = inl(CONFIG_ADDRESS);
+        outl(CONFIG_ADDRESS, 0x8000_0000);
+        let works = inl(CONFIG_ADDRESS) == 0x8000_0000;
+        outl(CONFIG_ADDRESS, old);
+        works
+    }
+}
+
+pub fn init()
+{
+    HAVE_PORTS.store(probe_ports(), Ordering::Relaxed);
+    if !NO_ECAM.load(Ordering::Relaxed) {
+        ECAM.publish(Box::new(find_ecam()));
+    }
+    if !HAVE_PORTS.load(Ordering::Relaxed) {
+        log!("No PCI configuration ports");
+    }
+}
+
+/*
+ * The segments and buses enumeration starts from: the first bus of every
+ * ECAM area, and bus 0 of segment 0 through the ports if ECAM doesn't
+ * cover it.
+ */
+pub fn root_buses() -> Vec<(u16, u8)>
+{
+    let mut roots = Vec::new();
+    {
+        let guard = rcu::read_lock();
+        if let Some(areas) = ECAM.read(&guard) {
+            for area in areas.iter() {
+                roots.push((area.segment, area.start_bus));
+            }
+        }
+    }
+    if HAVE_PORTS.load(Ordering::Relaxed) && !roots.iter().any(|&(segment, _)| segment == 0) {
+        roots.push((0, 0));
+    }
+    roots
+}
+
+/* Where `offset` of the configuration space of `addr` is mapped, if ECAM covers it */
+fn ecam_address(addr: Address, offset: u16) -> Option<usize>
+{
+    let guard = rcu::read_lock();
+    let area = ECAM.read(&guard)?.iter().find(|area| {
+        area.segment == addr.segment && area.start_bus <= addr.bus && addr.bus <= area.end_bus
+    })?;
+    Some(area.virt + (((addr.bus - area.start_bus) as usize) << 20)
+         + ((addr.device as usize) << 15) + ((addr.function as usize) << 12) + offset as usize)
+}
+
+/* The value of the address port for `offset` of `addr` */
+fn port_address(addr: Address, offset: u16) -> u32
+{
+    0x8000_0000 | (addr.bus as u32) << 16 | (addr.device as u32) << 11
+        | (addr.function as u32) << 8 | (offset as u32 & 0xFC)
+}
+
+/*
+ * Read `size` (1, 2 or 4) bytes at `offset`, which is aligned to them, of
+ * the configuration space of `addr`. Like the hardware, all ones are
+ * returned where there is nothing.
+ */
+pub fn read(addr: Address, offset: u16, size: usize) -> u32
+{
+    if let Some(virt) = ecam_address(addr, offset) {
+        return unsafe {
+            match size {
+                1 => ptr::read_volatile(virt as *const u8) as u32,
+                2 => ptr::read_volatile(virt as *const u16) as u32,
+                _ => ptr::read_volatile(virt as *const u32),
+            }
+        };
+    }
+    if addr.segment != 0 || offset >= 0x100 || !HAVE_PORTS.load(Ordering::Relaxed) {
+        return !0u32 >> (32 - size * 8);
+    }
+
+    let _guard = PORTS_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(addr, offset));
+        let port = CONFIG_DATA + (offset & 3);
+        match size {
+            1 => inb(port) as u32,
+            2 => inw(port) as u32,
+            _ => inl(port),
+        }
+    }
+}
+
+/* Write `size` bytes at `offset`, as for read. Writes to nowhere are dropped. */
+pub fn write(addr: Address, offset: u16, size: usize, value: u32)
+{
+    if let Some(virt) = ecam_address(addr, offset) {
+        unsafe {
+            match size {
+                1 => ptr::write_volatile(virt as *mut u8, value as u8),
+                2 => ptr::write_volatile(virt as *mut u16, value as u16),
+                _ => ptr::write_volatile(virt as *mut u32, value),
+            }
+        }
+        return;
+    }
+    if addr.segment != 0 || offset >= 0x100 || !HAVE_PORTS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let _guard = PORTS_LOCK.lock();
+    unsafe {
+        outl(CONFIG_ADDRESS, port_address(addr, offset));
+        let port = CONFIG_DATA + (offset & 3);
+        match size {
+            1 => outb(port, value as u8),
+            2 => outw(port, value as u16),
+            _ => outl(port, value),
+        }
+    }
+}
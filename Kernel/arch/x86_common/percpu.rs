@@ -0,0 +1,316 @@
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ptr;
+use alloc::Vec;
+
+use super::apic::LAPIC;
+use super::irq;
+#[cfg(target_arch="x86_64")]
+use super::{cpu, msr};
+#[cfg(target_arch="x86")]
+use super::gdt;
+
+/* Number of machine words of scratch space in every per-CPU area */
+pub const PERCPU_SCRATCH_WORDS: usize = 8;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/*
+ * Offsets into the per-CPU area, for %gs relative accesses. On amd64 they
+ * are 0x00, 0x08, 0x10, 0x18, 0x58 and 0x60, as used by syscall.S.
+ */
+pub const PERCPU_SELF: usize = 0 * WORD;
+pub const PERCPU_CPU_ID: usize = 1 * WORD;
+pub const PERCPU_CURRENT_TASK: usize = 2 * WORD;
+pub const PERCPU_SCRATCH: usize = 3 * WORD;
+pub const PERCPU_KERNEL_RSP: usize = (3 + PERCPU_SCRATCH_WORDS) * WORD;
+pub const PERCPU_USER_RSP: usize = (4 + PERCPU_SCRATCH_WORDS) * WORD;
+
+/*
+ * The per-CPU area. The GS base of every CPU points at its own area, so that
+ * the fields can be reached with a single %gs relative instruction, which
+ * can't be torn by an interrupt or a migration. On amd64 the base is set
+ * through an MSR, on x86 %gs holds a segment of the CPU's GDT based there.
+ *
+ * The layout is relied upon by the PERCPU_* offsets, do not reorder.
+ */
+#[repr(C)]
+pub struct PerCpuArea {
+    self_ptr: usize,
+    pub cpu_id: usize,
+    pub current_task: usize,
+    pub scratch: [usize; PERCPU_SCRATCH_WORDS],
+    /* Top of the current task's kernel stack, where syscall_entry switches to */
+    pub kernel_rsp: usize,
+    /* The user stack pointer, only between syscall_entry and it being pushed */
+    pub user_rsp: usize,
+    pub lapic: Option<LAPIC>,
+    pub tss: usize,
+    /* Number of interrupt handlers we are nested in, see interrupt_dispatch */
+    pub irq_depth: usize,
+    /* Set between kernel_fpu_begin and kernel_fpu_end */
+    pub kernel_fpu: bool,
+}
+
+/* The BSP's area, so that it's usable before any allocator is up */
+static mut BSP_AREA: PerCpuArea = PerCpuArea {
+    self_ptr: 0,
+    cpu_id: 0,
+    current_task: 0,
+    scratch: [0; PERCPU_SCRATCH_WORDS],
+    kernel_rsp: 0,
+    user_rsp: 0,
+    lapic: None,
+    tss: 0,
+    irq_depth: 0,
+    kernel_fpu: false,
+};
+
+/*
+ * Initialize the per-CPU area at `area` for `cpu_id` and point the GS base
+ * of the current CPU at it.
+ *
+ * On amd64 KERNEL_GS_BASE is cleared, so that a later swapgs on the way to
+ * user mode hands user space a null GS base rather than the kernel's area.
+ * The CPU features must have been detected.
+ */
+pub unsafe fn init_area(area: *mut PerCpuArea, cpu_id: usize)
+{
+    ptr::write(area, PerCpuArea {
+        self_ptr: area as usize,
+        cpu_id: cpu_id,
+        current_task: 0,
+        scratch: [0; PERCPU_SCRATCH_WORDS],
+        kernel_rsp: 0,
+        user_rsp: 0,
+        lapic: None,
+        tss: 0,
+        irq_depth: 0,
+        kernel_fpu: false,
+    });
+
+    set_base(area as usize, cpu_id);
+}
+
+#[cfg(target_arch="x86_64")]
+unsafe fn set_base(area: usize, cpu_id: usize)
+{
+    msr::wrmsr(msr::IA32_GS_BASE, area as u64);
+    msr::wrmsr(msr::IA32_KERNEL_GS_BASE, 0);
+
+    /* rdtscp returns this, so it can tell which CPU the TSC was read on */
+    if cpu::has(cpu::Features::RDTSCP) {
+        msr::wrmsr(msr::IA32_TSC_AUX, cpu_id as u64);
+    }
+}
+
+#[cfg(target_arch="x86")]
+unsafe fn set_base(area: usize, _cpu_id: usize)
+{
+    gdt::set_percpu_base(area);
+}
+
+/* Read the word at `offset` into the current CPU's area */
+#[cfg(target_arch="x86_64")]
+fn read_word(offset: usize) -> usize
+{
+    let value: usize;
+    unsafe { asm!("movq %gs:($1), $0" : "=r"(value) : "r"(offset) :: "volatile"); }
+    value
+}
+
+#[cfg(target_arch="x86")]
+fn read_word(offset: usize) -> usize
+{
+    let value: usize;
+    unsafe { asm!("movl %gs:($1), $0" : "=r"(value) : "r"(offset) :: "volatile"); }
+    value
+}
+
+#[cfg(target_arch="x86_64")]
+fn write_word(offset: usize, value: usize)
+{
+    unsafe { asm!("movq $0, %gs:($1)" :: "r"(value), "r"(offset) : "memory" : "volatile"); }
+}
+
+#[cfg(target_arch="x86")]
+fn write_word(offset: usize, value: usize)
+{
+    unsafe { asm!("movl $0, %gs:($1)" :: "r"(value), "r"(offset) : "memory" : "volatile"); }
+}
+
+/* Install the statically allocated per-CPU area on the BSP */
+pub unsafe fn init_bsp()
+{
+    init_area(&mut BSP_AREA, 0);
+}
+
+/* Address of the BSP's per-CPU area */
+pub fn bsp_area() -> usize
+{
+    unsafe { &BSP_AREA as *const PerCpuArea as usize }
+}
+
+/*
+ * Get the per-CPU area of the current CPU.
+ *
+ * This is unsafe, the caller must make sure that it doesn't hand out more
+ * than one mutable reference, e.g. by disabling interrupts.
+ */
+pub unsafe fn this_cpu_area<'a>() -> &'a mut PerCpuArea
+{
+    &mut *(read_word(PERCPU_SELF) as *mut PerCpuArea)
+}
+
+/* The id of the CPU we are running on */
+pub fn cpu_id() -> usize
+{
+    read_word(PERCPU_CPU_ID)
+}
+
+/*
+ * The id of the CPU we are running on, or None if its per-CPU area hasn't
+ * been set up yet. Slower than `cpu_id`, but safe to call at any time.
+ */
+pub fn try_cpu_id() -> Option<usize>
+{
+    if has_area() {
+        Some(cpu_id())
+    } else {
+        None
+    }
+}
+
+#[cfg(target_arch="x86_64")]
+fn has_area() -> bool
+{
+    unsafe { msr::rdmsr(msr::IA32_GS_BASE) != 0 }
+}
+
+/* %gs holds the null selector until `init_area` loads the per-CPU segment */
+#[cfg(target_arch="x86")]
+fn has_area() -> bool
+{
+    let selector: u16;
+    unsafe { asm!("movw %gs, $0" : "=r"(selector) ::: "volatile"); }
+    selector == gdt::PERCPU_SELECTOR
+}
+
+/* The task running on the current CPU, or 0 if there's none yet */
+pub fn current_task() -> usize
+{
+    read_word(PERCPU_CURRENT_TASK)
+}
+
+pub fn set_current_task(task: usize)
+{
+    write_word(PERCPU_CURRENT_TASK, task);
+}
+
+/* Is the current CPU running an interrupt handler? */
+pub fn in_interrupt() -> bool
+{
+    irq_depth() != 0
+}
+
+pub fn irq_depth() -> usize
+{
+    unsafe { this_cpu_area().irq_depth }
+}
+
+/*
+ * Task switches can happen inside interrupt handlers, so the scheduler
+ * saves and restores the depth along with the rest of the context.
+ */
+pub fn set_irq_depth(depth: usize)
+{
+    unsafe { this_cpu_area().irq_depth = depth; }
+}
+
+/* Read the `idx`th scratch word of the current CPU */
+pub fn scratch(idx: usize) -> usize
+{
+    assert!(idx < PERCPU_SCRATCH_WORDS);
+    read_word(PERCPU_SCRATCH + idx * WORD)
+}
+
+pub fn set_scratch(idx: usize, value: usize)
+{
+    assert!(idx < PERCPU_SCRATCH_WORDS);
+    write_word(PERCPU_SCRATCH + idx * WORD, value);
+}
+
+/*
+ * A per-CPU variable: one instance of T for every CPU in processor_list.
+ *
+ * The slots are created by `init` before the APs are started and are
+ * never moved afterwards. `this_cpu` and `on_cpu` hand out shared
+ * references, so fields that other CPUs touch must be atomics. Mutable
+ * access to the local slot goes through `with_this_cpu`, which keeps
+ * interrupts disabled for the duration of the closure, so that it is
+ * also safe to use from interrupt context.
+ */
+pub struct PerCpu<T> {
+    values: UnsafeCell<Vec<T>>,
+}
+
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new() -> PerCpu<T> {
+        PerCpu { values: UnsafeCell::new(Vec::new()) }
+    }
+
+    /*
+     * Create the slot of every CPU by calling `f` with its id.
+     *
+     * This must be called once, before the APs are started.
+     */
+    pub unsafe fn init<F: FnMut(usize) -> T>(&self, mut f: F) {
+        let values = &mut *self.values.get();
+        assert!(values.is_empty(), "per-CPU variable initialized twice");
+        for cpu in 0..::arch::cpu_count() {
+            values.push(f(cpu));
+        }
+    }
+
+    /* Has `init` been called yet? */
+    pub fn is_initialized(&self) -> bool {
+        unsafe { !(*self.values.get()).is_empty() }
+    }
+
+    /* The slot of the current CPU */
+    pub fn this_cpu(&self) -> &T {
+        self.on_cpu(cpu_id())
+    }
+
+    /* The slot of CPU `cpu` */
+    pub fn on_cpu(&self, cpu: usize) -> &T {
+        unsafe { &(*self.values.get())[cpu] }
+    }
+
+    /*
+     * Mutable access to the current CPU's slot, for code that already runs
+     * with interrupts disabled and doesn't hold another reference to it.
+     */
+    pub unsafe fn this_cpu_mut(&self) -> &mut T {
+        &mut (*self.values.get())[cpu_id()]
+    }
+
+    /*
+     * Run `f` on the current CPU's slot with interrupts disabled.
+     *
+     * `f` must not call `with_this_cpu` on the same variable.
+     */
+    pub fn with_this_cpu<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let flags = irq::save_and_disable();
+        let ret = f(unsafe { &mut (*self.values.get())[cpu_id()] });
+        irq::restore(flags);
+        ret
+    }
+
+    /* Number of CPUs that have a slot */
+    pub fn len(&self) -> usize {
+        unsafe { (*self.values.get()).len() }
+    }
+}
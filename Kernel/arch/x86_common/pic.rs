@@ -0,0 +1,35 @@
+use super::x86_io::{inb, outb};
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+/* Where the legacy IRQs end up, out of the way of exceptions and our vectors */
+pub const PIC_VECTOR_BASE: u8 = 0xE0;
+
+/*
+ * Remap the 8259 PICs away from the exception vectors and mask every line.
+ * The BIOS leaves IRQ0 at vector 8, which would look like a double fault
+ * as soon as interrupts are enabled. All interrupts go through the LAPIC.
+ */
+pub unsafe fn disable()
+{
+    /* ICW1: initialize, expect ICW4 */
+    outb(PIC1_COMMAND, 0x11);
+    outb(PIC2_COMMAND, 0x11);
+    /* ICW2: vector offsets */
+    outb(PIC1_DATA, PIC_VECTOR_BASE);
+    outb(PIC2_DATA, PIC_VECTOR_BASE + 8);
+    /* ICW3: slave on IRQ2 */
+    outb(PIC1_DATA, 4);
+    outb(PIC2_DATA, 2);
+    /* ICW4: 8086 mode */
+    outb(PIC1_DATA, 0x01);
+    outb(PIC2_DATA, 0x01);
+
+    /* Mask everything */
+    outb(PIC1_DATA, 0xFF);
+    outb(PIC2_DATA, 0xFF);
+    let _ = inb(PIC1_DATA);
+}
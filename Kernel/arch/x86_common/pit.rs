@@ -0,0 +1,87 @@
+use sync::IrqSpinLock;
+use super::x86_io::{inb, outb};
+
+/* Input frequency of the PIT, in Hz */
+pub const PIT_FREQUENCY: u64 = 1193182;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/* Gate of channel 2 (bit 0) and its output (bit 5), also the PC speaker */
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+
+/*
+ * Busy wait for `ms` milliseconds using channel 2 of the PIT in one-shot
+ * mode. Used to calibrate the other timers, at most 54ms can be waited.
+ *
+ * The caller must make sure that nobody else uses channel 2 meanwhile.
+ */
+pub unsafe fn wait_ms(ms: u64)
+{
+    let count = PIT_FREQUENCY * ms / 1000;
+    assert!(count <= 0xFFFF, "PIT can't wait {} ms", ms);
+
+    /* Gate low, speaker off */
+    let gate = inb(PIT_CHANNEL2_GATE) & !0x03;
+    outb(PIT_CHANNEL2_GATE, gate);
+
+    /* Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count) */
+    outb(PIT_COMMAND, 0xB0);
+    outb(PIT_CHANNEL2, count as u8);
+    outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+    /* Raising the gate starts the countdown */
+    outb(PIT_CHANNEL2_GATE, gate | 0x01);
+    while inb(PIT_CHANNEL2_GATE) & 0x20 == 0 {
+        /* Do nothing */
+    }
+    outb(PIT_CHANNEL2_GATE, gate);
+}
+
+/* State of the channel 0 counter, see `read_ns` */
+struct Counter {
+    last: u16,
+    total: u64,
+}
+
+static COUNTER: IrqSpinLock<Counter> = IrqSpinLock::named("pit counter", Counter { last: 0, total: 0 });
+
+/*
+ * Start channel 0 as a free running counter, wrapping every 65536 PIT
+ * ticks (~55ms). Its IRQ is masked at the PIC, it is only ever read.
+ */
+pub unsafe fn start_counter()
+{
+    /* Channel 0, lobyte/hibyte, mode 2 (rate generator), reload 65536 */
+    outb(PIT_COMMAND, 0x34);
+    outb(PIT_CHANNEL0, 0);
+    outb(PIT_CHANNEL0, 0);
+}
+
+/* Latch and read the current count of channel 0 */
+unsafe fn read_counter() -> u16
+{
+    outb(PIT_COMMAND, 0x00);
+    let low = inb(PIT_CHANNEL0) as u16;
+    let high = inb(PIT_CHANNEL0) as u16;
+    (high << 8) | low
+}
+
+/*
+ * Nanoseconds since `start_counter`. The counter wraps every ~55ms, so
+ * this has to be called more often than that, which the tick takes care of.
+ */
+pub fn read_ns() -> u64
+{
+    let total = {
+        let mut counter = COUNTER.lock();
+        let count = unsafe { read_counter() };
+        /* The counter counts down */
+        let elapsed = (counter.last as u64 + 0x10000 - count as u64) & 0xFFFF;
+        counter.total += elapsed;
+        counter.last = count;
+        counter.total
+    };
+
+    (total as u128 * 1_000_000_000 / PIT_FREQUENCY as u128) as u64
+}
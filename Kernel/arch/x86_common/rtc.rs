@@ -0,0 +1,123 @@
+use core::fmt;
+
+use super::x86_io::{inb, outb};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+/* A calendar date and time, as kept by the RTC (UTC) */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /* Seconds since the Unix epoch */
+    pub fn to_unix(&self) -> u64 {
+        /* Days from civil, with March as the first month of the year */
+        let year = (if self.month <= 2 { self.year - 1 } else { self.year }) as u64;
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let mp = (self.month as u64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as u64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+               self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn read_register(register: u8) -> u8
+{
+    unsafe {
+        /* Bit 7 of the index port masks NMIs, leave it clear */
+        outb(CMOS_ADDRESS, register & 0x7F);
+        inb(CMOS_DATA)
+    }
+}
+
+fn update_in_progress() -> bool
+{
+    read_register(RTC_STATUS_A) & 0x80 != 0
+}
+
+fn read_raw() -> [u8; 6]
+{
+    while update_in_progress() {
+        /* Do nothing */
+    }
+    [read_register(RTC_SECONDS), read_register(RTC_MINUTES), read_register(RTC_HOURS),
+     read_register(RTC_DAY), read_register(RTC_MONTH), read_register(RTC_YEAR)]
+}
+
+fn from_bcd(value: u8) -> u8
+{
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/*
+ * Read the current date and time from the CMOS RTC. The registers are read
+ * until two reads agree, so that an update can't tear the result.
+ */
+pub fn read() -> DateTime
+{
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(RTC_STATUS_B);
+    let binary = status_b & 0x04 != 0;
+    let hour_24 = status_b & 0x02 != 0;
+
+    let pm = raw[2] & 0x80 != 0;
+    raw[2] = raw[2] & 0x7F;
+    if !binary {
+        for value in raw.iter_mut() {
+            *value = from_bcd(*value);
+        }
+    }
+
+    let mut hour = raw[2] as u32;
+    if !hour_24 {
+        hour = hour % 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        /* There's no reliable century register, assume the 21st */
+        year: 2000 + raw[5] as u32,
+        month: raw[4] as u32,
+        day: raw[3] as u32,
+        hour: hour,
+        minute: raw[1] as u32,
+        second: raw[0] as u32,
+    }
+}
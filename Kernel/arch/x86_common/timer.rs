@@ -0,0 +1,142 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::apic::{LAPIC, TimerMode};
+use super::idt::{self, InterruptFrame, TIMER_VECTOR};
+use super::percpu::{self, PerCpu};
+use super::{clock, cpu, msr};
+
+/* How long the reference clock is watched when calibrating */
+const CALIBRATION_MS: u64 = 10;
+
+/* LAPIC timer frequency (after the divide by 16) in ticks per ms */
+static LAPIC_TICKS_PER_MS: PerCpu<AtomicU64> = PerCpu::new();
+
+/* The TimerMode every CPU runs its LAPIC timer in */
+static MODE: AtomicUsize = AtomicUsize::new(TimerMode::Periodic as usize);
+
+/*
+ * Set up the timer state of every CPU and pick the timer mode. Called on
+ * the BSP once the processors have been enumerated.
+ */
+pub fn init()
+{
+    unsafe {
+        LAPIC_TICKS_PER_MS.init(|_| AtomicU64::new(0));
+    }
+    idt::register_handler(TIMER_VECTOR, timer_interrupt);
+
+    if cpu::has(cpu::Features::TSC_DEADLINE) {
+        set_mode(TimerMode::TscDeadline);
+    } else {
+        set_mode(TimerMode::Periodic);
+    }
+}
+
+pub fn mode() -> TimerMode
+{
+    match MODE.load(Ordering::Relaxed) {
+        0b00 => TimerMode::OneShot,
+        0b01 => TimerMode::Periodic,
+        _ => TimerMode::TscDeadline,
+    }
+}
+
+/* Override the timer mode, before any CPU has called `init_cpu` */
+pub fn set_mode(mode: TimerMode)
+{
+    if mode == TimerMode::TscDeadline {
+        if !cpu::has(cpu::Features::TSC_DEADLINE) {
+            panic!("TSC-deadline timer mode is not supported by this CPU");
+        }
+        cpu::mark_used(cpu::Features::TSC_DEADLINE);
+    }
+    MODE.store(mode as usize, Ordering::Relaxed);
+}
+
+/*
+ * Measure the LAPIC timer against the HPET, or the PIT without one. This
+ * is done on every CPU, since the frequency isn't guaranteed to be the
+ * same everywhere.
+ */
+fn calibrate(lapic: &LAPIC)
+{
+    lapic.set_timer_divide_16();
+    lapic.set_timer(TIMER_VECTOR, TimerMode::OneShot, true);
+    lapic.set_timer_initial_count(0xFFFFFFFF);
+    clock::calibration_wait_ms(CALIBRATION_MS);
+    let remaining = lapic.timer_current_count();
+    lapic.set_timer_initial_count(0);
+
+    let lapic_per_ms = (0xFFFFFFFF - remaining) as u64 / CALIBRATION_MS;
+    LAPIC_TICKS_PER_MS.this_cpu().store(lapic_per_ms, Ordering::Relaxed);
+
+    log!("CPU {}: LAPIC timer at {} kHz", percpu::cpu_id(), lapic_per_ms);
+}
+
+/*
+ * Calibrate and start the LAPIC timer of the current CPU. In periodic mode
+ * it interrupts every `tick_ns` nanoseconds, otherwise it is armed by the
+ * first call to `program`.
+ */
+pub fn init_cpu(tick_ns: u64)
+{
+    let lapic = match unsafe { percpu::this_cpu_area() }.lapic {
+        Some(ref lapic) => lapic,
+        None => {
+            log!("CPU {} has no LAPIC, not starting its timer", percpu::cpu_id());
+            return;
+        }
+    };
+
+    calibrate(lapic);
+
+    let mode = mode();
+    lapic.set_timer(TIMER_VECTOR, mode, false);
+    match mode {
+        TimerMode::Periodic => {
+            let per_ms = LAPIC_TICKS_PER_MS.this_cpu().load(Ordering::Relaxed);
+            let count = per_ms * tick_ns / 1_000_000;
+            lapic.set_timer_initial_count(if count == 0 { 1 } else { count as u32 });
+        }
+        TimerMode::OneShot | TimerMode::TscDeadline => {}
+    }
+}
+
+/*
+ * Make the current CPU's timer fire at `deadline` (in ::time::now()
+ * nanoseconds). In periodic mode the timer keeps ticking and this does
+ * nothing.
+ */
+pub fn program(deadline: u64)
+{
+    let now = ::time::now();
+    let delta = if deadline > now { deadline - now } else { 0 };
+
+    match mode() {
+        TimerMode::Periodic => {}
+        TimerMode::TscDeadline => unsafe {
+            msr::wrmsr(msr::IA32_TSC_DEADLINE, cpu::rdtsc() + clock::ns_to_tsc(delta) + 1);
+        },
+        TimerMode::OneShot => {
+            let lapic = match unsafe { percpu::this_cpu_area() }.lapic {
+                Some(ref lapic) => lapic,
+                None => return,
+            };
+            let per_ms = LAPIC_TICKS_PER_MS.this_cpu().load(Ordering::Relaxed);
+            let count = (delta as u128 * per_ms as u128 / 1_000_000) as u64;
+            let count = if count == 0 {
+                1
+            } else if count > 0xFFFFFFFF {
+                0xFFFFFFFF
+            } else {
+                count
+            };
+            lapic.set_timer_initial_count(count as u32);
+        }
+    }
+}
+
+fn timer_interrupt(_frame: &mut InterruptFrame)
+{
+    ::timer::interrupt();
+}
@@ -0,0 +1,96 @@
+use errno::Errno;
+use super::cpu::{self, Features};
+
+/* User space is the lower half of the address space */
+#[cfg(target_arch="x86_64")]
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/* User space is everything below the kernel at 3GiB */
+#[cfg(target_arch="x86")]
+pub const USER_END: usize = ::arch::KERNEL_BASE;
+
+/*
+ * An entry of the exception table: if the instruction at `insn` faults,
+ * execution resumes at `fixup` instead of panicking. Built by the linker
+ * from the .ex_table sections, see arch/<arch>/link.ld.
+ */
+#[repr(C)]
+struct ExTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    /* Defined in arch/<arch>/uaccess.S */
+    fn copy_user_raw(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    /* Defined by arch/<arch>/link.ld */
+    static __start_ex_table: ExTableEntry;
+    static __stop_ex_table: ExTableEntry;
+}
+
+/* Where to resume after a fault at `rip`, if it was expected */
+pub fn fixup(rip: usize) -> Option<usize>
+{
+    unsafe {
+        let mut entry = &__start_ex_table as *const ExTableEntry;
+        let end = &__stop_ex_table as *const ExTableEntry;
+        while entry < end {
+            if (*entry).insn == rip {
+                return Some((*entry).fixup);
+            }
+            entry = entry.offset(1);
+        }
+    }
+    None
+}
+
+/* Is [addr, addr + len) entirely in user space? */
+pub fn is_user_range(addr: usize, len: usize) -> bool
+{
+    match addr.checked_add(len) {
+        Some(end) => end <= USER_END,
+        None => false,
+    }
+}
+
+/* Let the kernel touch user pages, with SMAP enabled they fault otherwise */
+fn user_access_begin()
+{
+    if cpu::has(Features::SMAP) {
+        unsafe { asm!("stac" ::: "memory" : "volatile"); }
+    }
+}
+
+fn user_access_end()
+{
+    if cpu::has(Features::SMAP) {
+        unsafe { asm!("clac" ::: "memory" : "volatile"); }
+    }
+}
+
+unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), Errno>
+{
+    user_access_begin();
+    let left = copy_user_raw(dst, src, len);
+    user_access_end();
+    if left == 0 { Ok(()) } else { Err(Errno::EFAULT) }
+}
+
+/* Fill `dst` from the user address `src`, EFAULT if it isn't all readable */
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), Errno>
+{
+    if !is_user_range(src, dst.len()) {
+        return Err(Errno::EFAULT);
+    }
+    unsafe { copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) }
+}
+
+/* Copy `src` to the user address `dst`, EFAULT if it isn't all writable */
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), Errno>
+{
+    if !is_user_range(dst, src.len()) {
+        return Err(Errno::EFAULT);
+    }
+    unsafe { copy_user(dst as *mut u8, src.as_ptr(), src.len()) }
+}
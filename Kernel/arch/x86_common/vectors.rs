@@ -0,0 +1,209 @@
+/*
+ * Vectors for device interrupts, such as MSIs. The IDT is shared, but the
+ * handlers of these vectors are kept per CPU, so every CPU has the whole
+ * range to hand out and a device interrupt is identified by the CPU it is
+ * delivered to and its vector there.
+ */
+use sync::IrqSpinLock;
+use sync::rcu;
+use super::percpu::PerCpu;
+use super::processor;
+
+/* The range handed out, above the kernel's own vectors */
+pub const FIRST_DEVICE_VECTOR: u8 = 0x30;
+pub const LAST_DEVICE_VECTOR: u8 = 0xEF;
+
+const DEVICE_VECTORS: usize = (LAST_DEVICE_VECTOR - FIRST_DEVICE_VECTOR) as usize + 1;
+
+/* Called with the data given to `bind` */
+pub type IrqHandler = fn(usize);
+
+#[derive(Clone, Copy)]
+enum Slot {
+    Free,
+    /* In use by something else than a device, such as system calls */
+    Reserved,
+    Allocated,
+    Bound(IrqHandler, usize),
+}
+
+struct VectorTable {
+    slots: [Slot; DEVICE_VECTORS],
+    used: usize,
+}
+
+static TABLES: PerCpu<IrqSpinLock<VectorTable>> = PerCpu::new();
+
+/* Set up the tables, once the processors are known */
+pub fn init()
+{
+    unsafe {
+        TABLES.init(|_| {
+            let mut table = VectorTable { slots: [Slot::Free; DEVICE_VECTORS], used: 0 };
+            reserve_system_vectors(&mut table);
+            IrqSpinLock::named("device vectors", table)
+        });
+    }
+}
+
+#[cfg(target_arch="x86_64")]
+fn reserve_system_vectors(_table: &mut VectorTable)
+{
+}
+
+/* int $0x80 sits in the middle of the range */
+#[cfg(target_arch="x86")]
+fn reserve_system_vectors(table: &mut VectorTable)
+{
+    table.slots[(super::idt::SYSCALL_VECTOR - FIRST_DEVICE_VECTOR) as usize] = Slot::Reserved;
+}
+
+pub fn is_device_vector(vector: u8) -> bool
+{
+    vector >= FIRST_DEVICE_VECTOR && vector <= LAST_DEVICE_VECTOR
+}
+
+/* The CPU with the fewest vectors in use, to spread the interrupts */
+fn least_loaded_cpu() -> Option<usize>
+{
+    (0..TABLES.len()).filter(|&cpu| msi_can_reach(cpu))
+        .min_by_key(|&cpu| TABLES.on_cpu(cpu).lock().used)
+}
+
+/*
+ * Allocate `count` consecutive vectors on `cpu`, or on the least loaded CPU
+ * if it is None, and return the CPU and the first vector. `count` must be
+ * a power of two and the first vector is aligned to it, as multiple
+ * message MSI needs. CPUs an MSI can't reach get none.
+ */
+pub fn allocate(cpu: Option<usize>, count: usize) -> Option<(usize, u8)>
+{
+    assert!(count.is_power_of_two() && count <= 32, "can't allocate {} vectors", count);
+    let cpu = match cpu {
+        Some(cpu) => cpu,
+        None => least_loaded_cpu()?,
+    };
+    if cpu >= TABLES.len() || !msi_can_reach(cpu) {
+        return None;
+    }
+
+    let mut table = TABLES.on_cpu(cpu).lock();
+    let first = (FIRST_DEVICE_VECTOR as usize + count - 1) & !(count - 1);
+    let mut vector = first;
+    while vector + count - 1 <= LAST_DEVICE_VECTOR as usize {
+        let start = vector - FIRST_DEVICE_VECTOR as usize;
+        let free = table.slots[start..start + count].iter().all(|slot| match *slot {
+            Slot::Free => true,
+            _ => false,
+        });
+        if free {
+            for slot in table.slots[start..start + count].iter_mut() {
+                *slot = Slot::Allocated;
+            }
+            table.used += count;
+            return Some((cpu, vector as u8));
+        }
+        vector += count;
+    }
+    None
+}
+
+fn slot_index(vector: u8) -> usize
+{
+    assert!(is_device_vector(vector), "vector 0x{:x} isn't a device vector", vector);
+    (vector - FIRST_DEVICE_VECTOR) as usize
+}
+
+/* Give back a vector from `allocate`, unbinding it, see `unbind` */
+pub fn free(cpu: usize, vector: u8)
+{
+    let mut table = TABLES.on_cpu(cpu).lock();
+    let index = slot_index(vector);
+    match table.slots[index] {
+        Slot::Allocated | Slot::Bound(..) => {
+            table.slots[index] = Slot::Free;
+            table.used -= 1;
+        }
+        _ => panic!("freeing vector 0x{:x} on CPU {}, which isn't allocated", vector, cpu),
+    }
+}
+
+/* Run `handler` with `data` when `vector` arrives on `cpu`. False if it already has a handler. */
+pub fn bind(cpu: usize, vector: u8, handler: IrqHandler, data: usize) -> bool
+{
+    let mut table = TABLES.on_cpu(cpu).lock();
+    let index = slot_index(vector);
+    match table.slots[index] {
+        Slot::Allocated => {
+            table.slots[index] = Slot::Bound(handler, data);
+            true
+        }
+        Slot::Bound(..) => false,
+        _ => panic!("binding vector 0x{:x} on CPU {}, which isn't allocated", vector, cpu),
+    }
+}
+
+/*
+ * Remove the handler of `vector` on `cpu`. It may still be running there
+ * when this returns, until an RCU grace period has passed, so the caller
+ * must wait for one (rcu::synchronize_rcu) before freeing its data.
+ */
+pub fn unbind(cpu: usize, vector: u8)
+{
+    let mut table = TABLES.on_cpu(cpu).lock();
+    let index = slot_index(vector);
+    if let Slot::Bound(..) = table.slots[index] {
+        table.slots[index] = Slot::Allocated;
+    }
+}
+
+/*
+ * Called by interrupt_dispatch for a vector without a kernel handler, false
+ * if it isn't a bound device vector. The handler runs in an RCU read-side
+ * critical section, which `unbind` relies on.
+ */
+pub fn dispatch(vector: u8) -> bool
+{
+    if !is_device_vector(vector) || !TABLES.is_initialized() {
+        return false;
+    }
+    let _guard = rcu::read_lock();
+    let slot = TABLES.this_cpu().lock().slots[slot_index(vector)];
+    match slot {
+        Slot::Bound(handler, data) => {
+            handler(data);
+            true
+        }
+        _ => false,
+    }
+}
+
+/* MSI address register: the LAPIC window, the destination in bits 12 to 19 */
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/*
+ * The highest APIC ID the destination field holds. Without interrupt
+ * remapping, CPUs with bigger x2APIC IDs can't take MSIs at all.
+ */
+const MSI_MAX_APIC_ID: usize = 0xFF;
+
+fn msi_can_reach(cpu: usize) -> bool
+{
+    processor(cpu).map_or(false, |proc| proc.apic_id <= MSI_MAX_APIC_ID)
+}
+
+/*
+ * The address and data an MSI must write to raise `vector` on `cpu`:
+ * physical destination mode, fixed delivery, edge triggered.
+ */
+pub fn msi_message(cpu: usize, vector: u8) -> (u64, u32)
+{
+    let apic_id = match processor(cpu) {
+        Some(proc) => proc.apic_id,
+        None => panic!("MSI to CPU {}, which doesn't exist", cpu),
+    };
+    if apic_id > MSI_MAX_APIC_ID {
+        panic!("MSI to CPU {}, whose APIC ID {} doesn't fit in the message", cpu, apic_id);
+    }
+    (MSI_ADDRESS_BASE | (apic_id as u64) << 12, vector as u32)
+}
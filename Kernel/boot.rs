@@ -0,0 +1,192 @@
+/*
+ * What the bootloader told us about the machine. The arch layer fills in a
+ * BootInfo from whatever protocol it was booted with (Multiboot 1 or 2 on
+ * x86), generic code only ever sees this.
+ *
+ * It is built before there is a heap, so the lists have a fixed capacity.
+ */
+use core::fmt;
+
+const MAX_MEMORY_REGIONS: usize = 32;
+const MAX_MODULES: usize = 8;
+/* The information, its cmdline and bootloader name and a name per module */
+const MAX_BOOT_DATA: usize = 3 + MAX_MODULES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryKind {
+    Usable,
+    Reserved,
+    /* Holds ACPI tables, usable once they are parsed */
+    AcpiReclaimable,
+    /* Must be preserved across sleep states */
+    AcpiNvs,
+    Defective,
+}
+
+impl MemoryKind {
+    /* The memory types of the Multiboot (and BIOS e820) memory maps */
+    pub fn from_e820(area_type: u32) -> MemoryKind {
+        match area_type {
+            1 => MemoryKind::Usable,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::Defective,
+            _ => MemoryKind::Reserved,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub kind: MemoryKind,
+}
+
+/* A file the bootloader loaded along with the kernel, e.g. an initramfs */
+#[derive(Clone, Copy, Debug)]
+pub struct Module {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+}
+
+/* The video mode the bootloader set up */
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    /* 0 for indexed colours, 1 for direct RGB, 2 for EGA text */
+    pub fb_type: u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct BootInfo {
+    memory_map: [MemoryRegion; MAX_MEMORY_REGIONS],
+    nr_regions: usize,
+    modules: [Module; MAX_MODULES],
+    nr_modules: usize,
+    /* Physical ranges of the bootloader's own data, page aligned */
+    boot_data: [(usize, usize); MAX_BOOT_DATA],
+    nr_boot_data: usize,
+    /* Empty if the bootloader passed none */
+    pub cmdline: &'static str,
+    pub bootloader_name: Option<&'static str>,
+    /* Physical address of the ACPI RSDP, if the bootloader knows it */
+    pub rsdp: Option<usize>,
+    pub framebuffer: Option<Framebuffer>,
+}
+
+impl BootInfo {
+    pub const fn empty() -> BootInfo {
+        BootInfo {
+            memory_map: [MemoryRegion { start: 0, end: 0, kind: MemoryKind::Reserved };
+                         MAX_MEMORY_REGIONS],
+            nr_regions: 0,
+            modules: [Module { start: 0, end: 0, name: "" }; MAX_MODULES],
+            nr_modules: 0,
+            boot_data: [(0, 0); MAX_BOOT_DATA],
+            nr_boot_data: 0,
+            cmdline: "",
+            bootloader_name: None,
+            rsdp: None,
+            framebuffer: None,
+        }
+    }
+
+    pub fn add_memory_region(&mut self, start: usize, end: usize, kind: MemoryKind) {
+        if self.nr_regions == MAX_MEMORY_REGIONS {
+            log!("Too many memory regions, ignoring [0x{:x} - 0x{:x}]", start, end);
+            return;
+        }
+        self.memory_map[self.nr_regions] = MemoryRegion { start: start, end: end, kind: kind };
+        self.nr_regions += 1;
+    }
+
+    pub fn add_module(&mut self, start: usize, end: usize, name: &'static str) {
+        if self.nr_modules == MAX_MODULES {
+            log!("Too many modules, ignoring '{}'", name);
+            return;
+        }
+        self.modules[self.nr_modules] = Module { start: start, end: end, name: name };
+        self.nr_modules += 1;
+    }
+
+    /*
+     * Record that the bootloader keeps data the fields here point into,
+     * such as the strings, at [start, end). Ranges sharing a page are merged.
+     */
+    pub fn add_boot_data(&mut self, start: usize, end: usize) {
+        let page_size = ::arch::PAGE_SIZE;
+        let start = start & !(page_size - 1);
+        let end = (end + page_size - 1) & !(page_size - 1);
+        for range in self.boot_data[..self.nr_boot_data].iter_mut() {
+            if start <= range.1 && range.0 <= end {
+                *range = (::core::cmp::min(start, range.0), ::core::cmp::max(end, range.1));
+                return;
+            }
+        }
+        if self.nr_boot_data == MAX_BOOT_DATA {
+            panic!("too much boot data, can't keep [0x{:x} - 0x{:x}]", start, end);
+        }
+        self.boot_data[self.nr_boot_data] = (start, end);
+        self.nr_boot_data += 1;
+    }
+
+    pub fn memory_map(&self) -> &[MemoryRegion] {
+        &self.memory_map[..self.nr_regions]
+    }
+
+    /* The modules, their frames must not be reused */
+    pub fn modules(&self) -> &[Module] {
+        &self.modules[..self.nr_modules]
+    }
+
+    /* Where the bootloader's data is, the frames must not be reused either */
+    pub fn boot_data(&self) -> &[(usize, usize)] {
+        &self.boot_data[..self.nr_boot_data]
+    }
+
+    /* Bytes of usable memory from 1MiB up to the first hole */
+    pub fn upper_memory(&self) -> usize {
+        const ONE_MB: usize = 1024 * 1024;
+        self.memory_map().iter()
+            .find(|region| region.kind == MemoryKind::Usable
+                  && region.start <= ONE_MB && ONE_MB < region.end)
+            .map_or(0, |region| region.end - ONE_MB)
+    }
+}
+
+impl fmt::Debug for BootInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BootInfo")
+            .field("memory_map", &self.memory_map())
+            .field("modules", &self.modules())
+            .field("boot_data", &self.boot_data())
+            .field("cmdline", &self.cmdline)
+            .field("bootloader_name", &self.bootloader_name)
+            .field("rsdp", &self.rsdp)
+            .field("framebuffer", &self.framebuffer)
+            .finish()
+    }
+}
+
+/* Set once by `init`, before the APs are started, then only read */
+static mut INFO: BootInfo = BootInfo::empty();
+
+/* Keep what the arch layer found, for the rest of the kernel to read */
+pub fn init(info: BootInfo) -> &'static BootInfo
+{
+    unsafe {
+        INFO = info;
+        &INFO
+    }
+}
+
+pub fn info() -> &'static BootInfo
+{
+    unsafe { &INFO }
+}
@@ -0,0 +1,141 @@
+/*
+ * The kernel command line. Options are separated by whitespace, and are
+ * either flags ("nosmp") or take a value ("maxcpus=4").
+ *
+ * Subsystems declare the options they understand with `kernel_param!`,
+ * next to the code they affect. The linker collects the declarations in
+ * the .kernel_params section, see arch/<arch>/link.ld, and `parse` looks
+ * every option up there.
+ */
+use core::fmt;
+
+use logging::{self, Level};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamError {
+    MissingValue,
+    UnexpectedValue,
+    Invalid,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ParamError::MissingValue => "needs a value",
+            ParamError::UnexpectedValue => "doesn't take a value",
+            ParamError::Invalid => "invalid value",
+        })
+    }
+}
+
+pub enum ParamKind {
+    /* Called when the option is present */
+    Flag(fn()),
+    /* Called with what follows the '=' */
+    Value(fn(&'static str) -> Result<(), ParamError>),
+}
+
+/* An option, declared with `kernel_param!` */
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamKind,
+}
+
+extern "C" {
+    /* Defined by arch/<arch>/link.ld */
+    static __start_kernel_params: Param;
+    static __stop_kernel_params: Param;
+}
+
+fn find(name: &str) -> Option<&'static Param>
+{
+    unsafe {
+        let mut param = &__start_kernel_params as *const Param;
+        let end = &__stop_kernel_params as *const Param;
+        while param < end {
+            if (*param).name == name {
+                return Some(&*param);
+            }
+            param = param.offset(1);
+        }
+    }
+    None
+}
+
+fn apply(param: &Param, value: Option<&'static str>) -> Result<(), ParamError>
+{
+    match (&param.kind, value) {
+        (&ParamKind::Flag(set), None) => {
+            set();
+            Ok(())
+        }
+        (&ParamKind::Flag(_), Some(_)) => Err(ParamError::UnexpectedValue),
+        (&ParamKind::Value(set), Some(value)) => set(value),
+        (&ParamKind::Value(_), None) => Err(ParamError::MissingValue),
+    }
+}
+
+/*
+ * Apply the options in `cmdline`. Unknown and malformed options are
+ * warned about and otherwise ignored, booting with a typo beats not
+ * booting at all.
+ */
+pub fn parse(cmdline: &'static str)
+{
+    for option in cmdline.split_whitespace() {
+        let (name, value) = match option.find('=') {
+            Some(idx) => (&option[..idx], Some(&option[idx + 1..])),
+            None => (option, None),
+        };
+        match find(name) {
+            Some(param) => {
+                if let Err(error) = apply(param, value) {
+                    warn!("Ignoring command line option '{}': {}", option, error);
+                }
+            }
+            None => warn!("Unknown command line option '{}'", option),
+        }
+    }
+}
+
+/*
+ * logging.rs is included into several modules with #[path], declaring the
+ * option there would declare it once per copy.
+ */
+kernel_param!(PARAM_LOGLEVEL, "loglevel", set_log_level);
+
+fn set_log_level(value: &'static str) -> Result<(), ParamError>
+{
+    let level = match value {
+        "error" | "0" => Level::Error,
+        "warn" | "warning" | "1" => Level::Warning,
+        "info" | "2" => Level::Info,
+        "debug" | "3" => Level::Debug,
+        _ => return Err(ParamError::Invalid),
+    };
+    logging::set_level(level);
+    Ok(())
+}
+
+/* A decimal number, or a hexadecimal one with a "0x" prefix */
+pub fn parse_number(value: &str) -> Result<usize, ParamError>
+{
+    let result = if value.starts_with("0x") {
+        usize::from_str_radix(&value[2..], 16)
+    } else {
+        usize::from_str_radix(value, 10)
+    };
+    result.map_err(|_| ParamError::Invalid)
+}
+
+/* A number of bytes, optionally with a K, M or G suffix, e.g. "64M" */
+pub fn parse_size(value: &str) -> Result<usize, ParamError>
+{
+    let (number, shift) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_number(number)?.checked_mul(1 << shift).ok_or(ParamError::Invalid)
+}
@@ -0,0 +1,355 @@
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+/* e_ident */
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const EI_NIDENT: usize = 16;
+#[cfg(target_arch="x86")]
+const ELFCLASS32: u8 = 1;
+#[cfg(target_arch="x86_64")]
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+/* e_type */
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+#[cfg(target_arch="x86")]
+const EM_386: u16 = 3;
+#[cfg(target_arch="x86_64")]
+const EM_X86_64: u16 = 62;
+
+/* What the executables we can run look like */
+#[cfg(target_arch="x86_64")]
+const ELF_CLASS: u8 = ELFCLASS64;
+#[cfg(target_arch="x86_64")]
+const ELF_MACHINE: u16 = EM_X86_64;
+#[cfg(target_arch="x86_64")]
+const WRONG_CLASS: &str = "not a 64-bit ELF file";
+#[cfg(target_arch="x86_64")]
+const WRONG_MACHINE: &str = "not an x86-64 executable";
+
+#[cfg(target_arch="x86")]
+const ELF_CLASS: u8 = ELFCLASS32;
+#[cfg(target_arch="x86")]
+const ELF_MACHINE: u16 = EM_386;
+#[cfg(target_arch="x86")]
+const WRONG_CLASS: &str = "not a 32-bit ELF file";
+#[cfg(target_arch="x86")]
+const WRONG_MACHINE: &str = "not an i386 executable";
+
+/* p_type */
+pub const PT_LOAD: u32 = 1;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+/* p_flags */
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+
+/*
+ * The headers of an ELF64 file. ELF32 ones are read into these as well,
+ * see `read_file_header` and `read_program_header`.
+ */
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub file_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+#[cfg(target_arch="x86")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileHeader32 {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u32,
+    phoff: u32,
+    shoff: u32,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[cfg(target_arch="x86")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader32 {
+    segment_type: u32,
+    offset: u32,
+    vaddr: u32,
+    paddr: u32,
+    filesz: u32,
+    memsz: u32,
+    flags: u32,
+    align: u32,
+}
+
+/* Sizes of the headers as they are in the file */
+#[cfg(target_arch="x86_64")]
+const FILE_HEADER_SIZE: usize = mem::size_of::<FileHeader>();
+#[cfg(target_arch="x86_64")]
+const PROGRAM_HEADER_SIZE: usize = mem::size_of::<ProgramHeader>();
+#[cfg(target_arch="x86")]
+const FILE_HEADER_SIZE: usize = mem::size_of::<FileHeader32>();
+#[cfg(target_arch="x86")]
+const PROGRAM_HEADER_SIZE: usize = mem::size_of::<ProgramHeader32>();
+
+/* The file header at the start of `data`, which is long enough */
+#[cfg(target_arch="x86_64")]
+fn read_file_header(data: &[u8]) -> FileHeader
+{
+    unsafe { ptr::read_unaligned(data.as_ptr() as *const FileHeader) }
+}
+
+#[cfg(target_arch="x86")]
+fn read_file_header(data: &[u8]) -> FileHeader
+{
+    let header: FileHeader32 = unsafe { ptr::read_unaligned(data.as_ptr() as *const FileHeader32) };
+    FileHeader {
+        ident: header.ident,
+        file_type: header.file_type,
+        machine: header.machine,
+        version: header.version,
+        entry: header.entry as u64,
+        phoff: header.phoff as u64,
+        shoff: header.shoff as u64,
+        flags: header.flags,
+        ehsize: header.ehsize,
+        phentsize: header.phentsize,
+        phnum: header.phnum,
+        shentsize: header.shentsize,
+        shnum: header.shnum,
+        shstrndx: header.shstrndx,
+    }
+}
+
+/* The program header at the start of `data`, which is long enough */
+#[cfg(target_arch="x86_64")]
+fn read_program_header(data: &[u8]) -> ProgramHeader
+{
+    unsafe { ptr::read_unaligned(data.as_ptr() as *const ProgramHeader) }
+}
+
+#[cfg(target_arch="x86")]
+fn read_program_header(data: &[u8]) -> ProgramHeader
+{
+    let header: ProgramHeader32 =
+        unsafe { ptr::read_unaligned(data.as_ptr() as *const ProgramHeader32) };
+    ProgramHeader {
+        segment_type: header.segment_type,
+        flags: header.flags,
+        offset: header.offset as u64,
+        vaddr: header.vaddr as u64,
+        paddr: header.paddr as u64,
+        filesz: header.filesz as u64,
+        memsz: header.memsz as u64,
+        align: header.align as u64,
+    }
+}
+
+/* Why a file isn't an executable we can load */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    WrongClass,
+    NotLittleEndian,
+    BadVersion,
+    PositionIndependent,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaders,
+    SegmentOutsideFile,
+    SegmentTooSmall,
+    SegmentMisaligned,
+    SegmentNotInUserSpace,
+    SegmentsOverlap,
+    NoLoadableSegments,
+    EntryNotExecutable,
+    NeedsInterpreter,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ElfError::TooShort => "file is too short for an ELF header",
+            ElfError::BadMagic => "not an ELF file",
+            ElfError::WrongClass => WRONG_CLASS,
+            ElfError::NotLittleEndian => "not a little-endian ELF file",
+            ElfError::BadVersion => "unknown ELF version",
+            ElfError::PositionIndependent => "position independent executables are not supported",
+            ElfError::NotExecutable => "not an executable",
+            ElfError::WrongMachine => WRONG_MACHINE,
+            ElfError::BadProgramHeaders => "program header table is malformed",
+            ElfError::SegmentOutsideFile => "segment extends past the end of the file",
+            ElfError::SegmentTooSmall => "segment is smaller in memory than in the file",
+            ElfError::SegmentMisaligned => "segment address and file offset disagree modulo the page size",
+            ElfError::SegmentNotInUserSpace => "segment is not in the user half of the address space",
+            ElfError::SegmentsOverlap => "loadable segments overlap",
+            ElfError::NoLoadableSegments => "no loadable segments",
+            ElfError::EntryNotExecutable => "entry point is not in an executable segment",
+            ElfError::NeedsInterpreter => "dynamically linked executables are not supported",
+        })
+    }
+}
+
+/* A validated executable */
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub header: FileHeader,
+}
+
+impl<'a> Elf<'a> {
+    /*
+     * Check that `data` is a statically linked executable for this CPU and that
+     * every loadable segment fits in the file and in user space.
+     */
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < EI_NIDENT {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELF_CLASS {
+            return Err(ElfError::WrongClass);
+        }
+        if data.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        let header = read_file_header(data);
+
+        if header.ident[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if header.ident[6] != EV_CURRENT || header.version != EV_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        match header.file_type {
+            ET_EXEC => {}
+            ET_DYN => return Err(ElfError::PositionIndependent),
+            _ => return Err(ElfError::NotExecutable),
+        }
+        if header.machine != ELF_MACHINE {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let table_size = (header.phnum as u64).checked_mul(header.phentsize as u64);
+        let table_end = table_size.and_then(|size| size.checked_add(header.phoff));
+        if header.phentsize as usize != PROGRAM_HEADER_SIZE
+            || table_end.map_or(true, |end| end > data.len() as u64) {
+            return Err(ElfError::BadProgramHeaders);
+        }
+
+        let elf = Elf { data: data, header: header };
+        elf.check_segments()?;
+        Ok(elf)
+    }
+
+    /* The `idx`th program header */
+    pub fn program_header(&self, idx: usize) -> ProgramHeader {
+        assert!(idx < self.header.phnum as usize);
+        let offset = self.header.phoff as usize + idx * PROGRAM_HEADER_SIZE;
+        read_program_header(&self.data[offset..])
+    }
+
+    pub fn program_headers<'b>(&'b self) -> impl Iterator<Item = ProgramHeader> + 'b {
+        (0..self.header.phnum as usize).map(move |idx| self.program_header(idx))
+    }
+
+    /* The bytes of `segment` that come from the file */
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.filesz as usize]
+    }
+
+    fn check_segments(&self) -> Result<(), ElfError> {
+        let page_mask = ::arch::PAGE_SIZE as u64 - 1;
+        let mut loadable = 0;
+        let mut entry_ok = false;
+
+        for (idx, segment) in self.program_headers().enumerate() {
+            if segment.segment_type == PT_INTERP {
+                return Err(ElfError::NeedsInterpreter);
+            }
+            if segment.segment_type != PT_LOAD {
+                continue;
+            }
+            loadable += 1;
+
+            let file_end = segment.offset.checked_add(segment.filesz);
+            if file_end.map_or(true, |end| end > self.data.len() as u64) {
+                return Err(ElfError::SegmentOutsideFile);
+            }
+            if segment.filesz > segment.memsz {
+                return Err(ElfError::SegmentTooSmall);
+            }
+            if segment.vaddr & page_mask != segment.offset & page_mask {
+                return Err(ElfError::SegmentMisaligned);
+            }
+            /* Keep page zero unmapped, so that null pointers fault */
+            let mem_end = segment.vaddr.checked_add(segment.memsz);
+            if segment.vaddr < ::arch::PAGE_SIZE as u64
+                || mem_end.map_or(true, |end| end > ::arch::uaccess::USER_END as u64) {
+                return Err(ElfError::SegmentNotInUserSpace);
+            }
+
+            /* Segments may share a page at their ends, but not overlap */
+            for other in self.program_headers().skip(idx + 1) {
+                if other.segment_type == PT_LOAD
+                    && other.vaddr < segment.vaddr + segment.memsz
+                    && segment.vaddr < other.vaddr.saturating_add(other.memsz) {
+                    return Err(ElfError::SegmentsOverlap);
+                }
+            }
+
+            if segment.flags & PF_X != 0 && segment.vaddr <= self.header.entry
+                && self.header.entry < segment.vaddr + segment.memsz {
+                entry_ok = true;
+            }
+        }
+
+        if loadable == 0 {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !entry_ok {
+            return Err(ElfError::EntryNotExecutable);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * Loading user programs. An ELF64 executable is mapped into a fresh
+ * address space, a stack is set up the way the System V ABI wants it
+ * (argc, argv, envp and the auxiliary vector), and the current task
+ * drops to ring 3 at the entry point.
+ */
+use core::cmp;
+use core::fmt;
+use core::mem;
+use core::ptr;
+use alloc::Vec;
+
+use arch::PAGE_SIZE;
+use arch::uaccess::USER_END;
+use errno::Errno;
+use mm::layout::phys_to_virt;
+use mm::pmm::FrameAllocator;
+use mm::vmm::{AddressSpace, MapFlags, VirtAddr};
+
+pub mod elf;
+
+use self::elf::{Elf, ElfError, ProgramHeader, PT_LOAD, PT_PHDR, PF_W, PF_X};
+
+/* The user stack ends one page below the end of user space */
+const USER_STACK_TOP: usize = USER_END - PAGE_SIZE;
+const USER_STACK_SIZE: usize = 64 * 1024;
+
+/* At most this much of the stack goes to arguments and the environment */
+const MAX_ARGS_SIZE: usize = USER_STACK_SIZE / 4;
+
+/* Auxiliary vector entry types */
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecError {
+    Elf(ElfError),
+    ArgumentsTooLong,
+}
+
+impl ExecError {
+    /* What an execve() would return */
+    pub fn errno(&self) -> Errno {
+        match *self {
+            ExecError::Elf(_) => Errno::ENOEXEC,
+            ExecError::ArgumentsTooLong => Errno::E2BIG,
+        }
+    }
+}
+
+impl From<ElfError> for ExecError {
+    fn from(error: ElfError) -> ExecError {
+        ExecError::Elf(error)
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecError::Elf(ref error) => error.fmt(f),
+            ExecError::ArgumentsTooLong => f.write_str("arguments and environment are too long"),
+        }
+    }
+}
+
+/* A program loaded into its address space, ready to run */
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/* Get the frame `virt` is mapped to in `space`, mapping a zeroed one if none */
+fn populate(space: &mut AddressSpace, fma: &mut FrameAllocator, virt: VirtAddr,
+            flags: MapFlags)
+{
+    match space.translate(virt) {
+        Some((phys, old_flags)) => {
+            /* Another segment ends in this page, it gets the rights of both */
+            if !old_flags.contains(flags) {
+                space.map(fma, virt, phys & !(PAGE_SIZE - 1), old_flags | flags);
+            }
+        }
+        None => {
+            let frame = fma.allocate_frame();
+            unsafe {
+                ptr::write_bytes(phys_to_virt(frame.frame_addr()) as *mut u8, 0, PAGE_SIZE);
+            }
+            space.map(fma, virt, frame.frame_addr(), flags);
+        }
+    }
+}
+
+/* Map zeroed pages over [start, end) in `space` */
+fn populate_range(space: &mut AddressSpace, fma: &mut FrameAllocator, start: VirtAddr,
+                  end: VirtAddr, flags: MapFlags)
+{
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        populate(space, fma, page, flags);
+        page += PAGE_SIZE;
+    }
+}
+
+/* Copy `data` to `virt` in `space`, which must be mapped already */
+fn copy_to(space: &AddressSpace, mut virt: VirtAddr, mut data: &[u8])
+{
+    while !data.is_empty() {
+        let (phys, _) = space.translate(virt).expect("copying to an unmapped user page");
+        let len = cmp::min(data.len(), PAGE_SIZE - (virt & (PAGE_SIZE - 1)));
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), phys_to_virt(phys) as *mut u8, len);
+        }
+        virt += len;
+        data = &data[len..];
+    }
+}
+
+fn load_segment(space: &mut AddressSpace, fma: &mut FrameAllocator, elf: &Elf,
+                segment: &ProgramHeader)
+{
+    let mut flags = MapFlags::empty();
+    flags.set(MapFlags::WRITABLE, segment.flags & PF_W != 0);
+    flags.set(MapFlags::EXECUTABLE, segment.flags & PF_X != 0);
+
+    let start = segment.vaddr as usize;
+    populate_range(space, fma, start, start + segment.memsz as usize, flags);
+    /* The rest up to memsz, i.e. .bss, stays zero */
+    copy_to(space, start, elf.segment_data(segment));
+}
+
+/* Where the program headers end up in memory, for AT_PHDR */
+fn program_headers_addr(elf: &Elf) -> Option<usize>
+{
+    let phoff = elf.header.phoff;
+    for segment in elf.program_headers() {
+        if segment.segment_type == PT_PHDR {
+            return Some(segment.vaddr as usize);
+        }
+    }
+    elf.program_headers()
+        .find(|segment| segment.segment_type == PT_LOAD && segment.offset <= phoff
+              && phoff < segment.offset + segment.filesz)
+        .map(|segment| (segment.vaddr + phoff - segment.offset) as usize)
+}
+
+/*
+ * Append the strings in `list` to `strings`, NUL terminated, and return
+ * their user addresses given that `strings` will be copied to `base`.
+ */
+fn push_strings(strings: &mut Vec<u8>, base: VirtAddr, list: &[&str]) -> Vec<VirtAddr>
+{
+    let mut ptrs = Vec::with_capacity(list.len());
+    for s in list.iter() {
+        ptrs.push(base + strings.len());
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    ptrs
+}
+
+/*
+ * Build the initial stack: argc at the stack pointer, then the argv and
+ * envp arrays, each ending with a null pointer, then the auxiliary vector.
+ * The strings and the AT_RANDOM bytes go above that.
+ */
+fn setup_stack(space: &mut AddressSpace, fma: &mut FrameAllocator, elf: &Elf,
+               argv: &[&str], envp: &[&str]) -> Result<VirtAddr, ExecError>
+{
+    let strings_size = argv.iter().chain(envp.iter()).map(|s| s.len() + 1).sum::<usize>() + 16;
+    let strings_start = (USER_STACK_TOP - strings_size) & !0xF;
+
+    let mut strings: Vec<u8> = Vec::with_capacity(strings_size);
+    let argv_ptrs = push_strings(&mut strings, strings_start, argv);
+    let envp_ptrs = push_strings(&mut strings, strings_start, envp);
+    let random = strings_start + strings.len();
+    for _ in 0..2 {
+        let value = ::arch::cpu::random_u64();
+        strings.extend_from_slice(&unsafe { mem::transmute::<u64, [u8; 8]>(value) });
+    }
+
+    let mut words: Vec<usize> = Vec::new();
+    words.push(argv.len());
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    if let Some(phdr) = program_headers_addr(elf) {
+        words.extend_from_slice(&[AT_PHDR, phdr]);
+    }
+    words.extend_from_slice(&[AT_PHENT, elf.header.phentsize as usize,
+                              AT_PHNUM, elf.header.phnum as usize,
+                              AT_PAGESZ, PAGE_SIZE,
+                              AT_ENTRY, elf.header.entry as usize,
+                              AT_RANDOM, random,
+                              AT_NULL, 0]);
+
+    let stack_pointer = (strings_start - words.len() * mem::size_of::<usize>()) & !0xF;
+    if USER_STACK_TOP - stack_pointer > MAX_ARGS_SIZE {
+        return Err(ExecError::ArgumentsTooLong);
+    }
+
+    populate_range(space, fma, USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_TOP,
+                   MapFlags::WRITABLE);
+    copy_to(space, strings_start, &strings);
+    let words_bytes = unsafe {
+        ::core::slice::from_raw_parts(words.as_ptr() as *const u8,
+                                      words.len() * mem::size_of::<usize>())
+    };
+    copy_to(space, stack_pointer, words_bytes);
+    Ok(stack_pointer)
+}
+
+/* Load the executable `image` into a new address space */
+pub fn load(fma: &mut FrameAllocator, image: &[u8], argv: &[&str], envp: &[&str])
+    -> Result<Program, ExecError>
+{
+    let elf = Elf::parse(image)?;
+    let mut space = AddressSpace::new(fma);
+
+    for segment in elf.program_headers() {
+        if segment.segment_type == PT_LOAD {
+            load_segment(&mut space, fma, &elf, &segment);
+        }
+    }
+    /* Dropping `space` would take the frame allocator, which we hold */
+    let stack_pointer = match setup_stack(&mut space, fma, &elf, argv, envp) {
+        Ok(stack_pointer) => stack_pointer,
+        Err(error) => {
+            space.free(fma);
+            return Err(error);
+        }
+    };
+
+    Ok(Program {
+        space: space,
+        entry: elf.header.entry as usize,
+        stack_pointer: stack_pointer,
+    })
+}
+
+/*
+ * Replace the user space of the current task with the program in `image`
+ * and run it. Only returns if the program couldn't be loaded, in which
+ * case the task is left as it was.
+ */
+pub fn exec(image: &[u8], argv: &[&str], envp: &[&str]) -> ExecError
+{
+    let program = match ::mm::pmm::with_frames(|fma| load(fma, image, argv, envp)) {
+        Ok(program) => program,
+        Err(error) => return error,
+    };
+
+    ::arch::irq::disable();
+    let task = ::sched::current();
+    unsafe {
+        let old = mem::replace(&mut (*task).address_space, Some(program.space));
+        ::mm::vmm::switch_to((*task).address_space.as_ref());
+        drop(old);
+    }
+    ::arch::syscall::jump_to_user(program.entry, program.stack_pointer)
+}
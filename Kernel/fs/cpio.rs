@@ -0,0 +1,115 @@
+/*
+ * The cpio "newc" format, as made by `cpio -H newc` and used for Linux
+ * initramfs images. Every entry is a 110 byte ASCII header, the name and
+ * the data, the latter two padded to 4 bytes.
+ */
+use core::str;
+
+use super::{ArchiveError, Node, NodeKind, RamFs};
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/* Header fields, each 8 hex digits after the magic */
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+/* File types in the mode */
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+pub fn is_cpio(archive: &[u8]) -> bool
+{
+    archive.len() >= HEADER_SIZE && &archive[..MAGIC.len()] == MAGIC
+}
+
+fn field(header: &[u8], idx: usize) -> Result<usize, ArchiveError>
+{
+    let start = MAGIC.len() + idx * 8;
+    let digits = str::from_utf8(&header[start..start + 8]).map_err(|_| ArchiveError::BadHeader)?;
+    usize::from_str_radix(digits, 16).map_err(|_| ArchiveError::BadHeader)
+}
+
+/* None if rounding up overflows, sizes come from the archive */
+fn align4(offset: usize) -> Option<usize>
+{
+    offset.checked_add(3).map(|offset| offset & !3)
+}
+
+pub fn unpack(fs: &mut RamFs, archive: &'static [u8]) -> Result<(), ArchiveError>
+{
+    let mut offset = 0;
+    loop {
+        if offset + HEADER_SIZE > archive.len() {
+            return Err(ArchiveError::Truncated);
+        }
+        let header = &archive[offset..offset + HEADER_SIZE];
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(ArchiveError::BadHeader);
+        }
+        let mode = field(header, FIELD_MODE)? as u32;
+        let filesize = field(header, FIELD_FILESIZE)?;
+        let namesize = field(header, FIELD_NAMESIZE)?;
+
+        /* The name size counts the terminating NUL */
+        let name_start = offset + HEADER_SIZE;
+        let data_start = name_start.checked_add(namesize).and_then(align4)
+            .ok_or(ArchiveError::Truncated)?;
+        let data_end = data_start.checked_add(filesize).ok_or(ArchiveError::Truncated)?;
+        if namesize == 0 || data_end > archive.len() {
+            return Err(ArchiveError::Truncated);
+        }
+        let name = str::from_utf8(&archive[name_start..name_start + namesize - 1])
+            .map_err(|_| ArchiveError::BadName)?;
+        if name == TRAILER {
+            return Ok(());
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => Some(NodeKind::File),
+            S_IFDIR => Some(NodeKind::Directory),
+            S_IFLNK => Some(NodeKind::Symlink),
+            /* Device nodes and the like mean nothing to us */
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            fs.insert(name, Node {
+                kind: kind,
+                mode: mode & 0o7777,
+                data: &archive[data_start..data_end],
+            });
+        }
+
+        offset = align4(data_end).ok_or(ArchiveError::Truncated)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A regular file "a" with the given size and name size fields, and no data */
+    macro_rules! entry {
+        ($filesize:tt, $namesize:tt) => {
+            concat!("070701", "00000000", "000081a4", "00000000", "00000000", "00000001",
+                    "00000000", $filesize, "00000000", "00000000", "00000000", "00000000",
+                    $namesize, "00000000", "a\0\0\0")
+        }
+    }
+
+    #[test]
+    fn oversized_filesize() {
+        let archive = entry!("ffffffff", "00000002").as_bytes();
+        assert_eq!(unpack(&mut RamFs::new(), archive), Err(ArchiveError::Truncated));
+    }
+
+    #[test]
+    fn oversized_namesize() {
+        let archive = entry!("00000000", "ffffffff").as_bytes();
+        assert_eq!(unpack(&mut RamFs::new(), archive), Err(ArchiveError::Truncated));
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * The in-memory filesystem unpacked from the initramfs. The bootloader
+ * loads one or more cpio (newc) or ustar archives as Multiboot modules,
+ * at boot their contents are indexed here. File data isn't copied, it
+ * stays where the bootloader put it, in frames the PMM never hands out.
+ *
+ * The filesystem is built once and only read afterwards, so it is
+ * published through RCU and lookups take no locks.
+ */
+use core::fmt;
+use alloc::{String, Vec};
+use alloc::boxed::Box;
+
+use mm::layout::phys_to_virt;
+use sync::rcu::{self, Rcu};
+
+mod cpio;
+mod tar;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/* A file, directory or symlink. A symlink's data is its target. */
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub kind: NodeKind,
+    /* Permission bits */
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+/* Why an archive couldn't be unpacked */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveError {
+    UnknownFormat,
+    Truncated,
+    BadHeader,
+    BadName,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ArchiveError::UnknownFormat => "neither a cpio (newc) nor a ustar archive",
+            ArchiveError::Truncated => "archive is truncated",
+            ArchiveError::BadHeader => "malformed archive header",
+            ArchiveError::BadName => "file name is not valid UTF-8",
+        })
+    }
+}
+
+pub struct RamFs {
+    /* Absolute, normalised paths, "/" is always there */
+    entries: Vec<(String, Node)>,
+}
+
+impl RamFs {
+    pub fn new() -> RamFs {
+        let root = Node { kind: NodeKind::Directory, mode: 0o755, data: &[] };
+        RamFs { entries: vec![(String::from("/"), root)] }
+    }
+
+    fn find(&self, path: &str) -> Option<usize> {
+        self.entries.iter().position(|&(ref name, _)| name == path)
+    }
+
+    /*
+     * Add `node` at `path`, relative to the root whether or not it starts
+     * with a slash. Missing parent directories are created, an existing
+     * entry is replaced, so later archives override earlier ones.
+     */
+    pub fn insert(&mut self, path: &str, node: Node) {
+        let path = normalise(path);
+        if path == "/" {
+            return;
+        }
+
+        let mut end = 0;
+        while let Some(slash) = path[end + 1..].find('/') {
+            end += slash + 1;
+            let parent = &path[..end];
+            if self.find(parent).is_none() {
+                let dir = Node { kind: NodeKind::Directory, mode: 0o755, data: &[] };
+                self.entries.push((String::from(parent), dir));
+            }
+        }
+
+        match self.find(&path) {
+            Some(idx) => self.entries[idx].1 = node,
+            None => self.entries.push((path, node)),
+        }
+    }
+
+    pub fn lookup(&self, path: &str) -> Option<Node> {
+        self.find(&normalise(path)).map(|idx| self.entries[idx].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/* Turn "./a//b/" or "a/b" into "/a/b" */
+fn normalise(path: &str) -> String {
+    let mut result = String::new();
+    for component in path.split('/') {
+        if component.is_empty() || component == "." {
+            continue;
+        }
+        result.push('/');
+        result.push_str(component);
+    }
+    if result.is_empty() {
+        result.push('/');
+    }
+    result
+}
+
+static ROOT: Rcu<RamFs> = Rcu::empty();
+
+/* Unpack `archive` into `fs`, whichever format it is */
+pub fn unpack(fs: &mut RamFs, archive: &'static [u8]) -> Result<(), ArchiveError>
+{
+    if cpio::is_cpio(archive) {
+        cpio::unpack(fs, archive)
+    } else if tar::is_tar(archive) {
+        tar::unpack(fs, archive)
+    } else {
+        Err(ArchiveError::UnknownFormat)
+    }
+}
+
+/* Build the filesystem from the modules the bootloader loaded */
+pub fn init()
+{
+    let mut fs = RamFs::new();
+    for module in ::boot::info().modules() {
+        let archive = unsafe {
+            ::core::slice::from_raw_parts(phys_to_virt(module.start) as *const u8,
+                                          module.end - module.start)
+        };
+        match unpack(&mut fs, archive) {
+            Ok(()) => log!("Unpacked module '{}' into the initramfs", module.name),
+            Err(error) => log!("Can't unpack module '{}': {}", module.name, error),
+        }
+    }
+    log!("The initramfs has {} entries", fs.len());
+    ROOT.publish(Box::new(fs));
+}
+
+/* Look up `path` in the initramfs */
+pub fn lookup(path: &str) -> Option<Node>
+{
+    let guard = rcu::read_lock();
+    ROOT.read(&guard).and_then(|fs| fs.lookup(path))
+}
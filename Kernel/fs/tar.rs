@@ -0,0 +1,99 @@
+/*
+ * POSIX ustar archives. Every entry is a 512 byte header followed by the
+ * data padded to 512 bytes, the archive ends with zeroed blocks.
+ */
+use core::str;
+
+use super::{ArchiveError, Node, NodeKind, RamFs};
+
+const BLOCK_SIZE: usize = 512;
+
+/* Header layout */
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const SIZE: (usize, usize) = (124, 12);
+const TYPEFLAG: usize = 156;
+const LINKNAME: (usize, usize) = (157, 100);
+const MAGIC: (usize, usize) = (257, 5);
+const PREFIX: (usize, usize) = (345, 155);
+
+/* Type flags */
+const REGTYPE: u8 = b'0';
+const AREGTYPE: u8 = 0;
+const SYMTYPE: u8 = b'2';
+const DIRTYPE: u8 = b'5';
+
+pub fn is_tar(archive: &[u8]) -> bool
+{
+    archive.len() >= BLOCK_SIZE && &archive[MAGIC.0..MAGIC.0 + MAGIC.1] == b"ustar"
+}
+
+/* A NUL padded string field */
+fn string(header: &'static [u8], (start, len): (usize, usize)) -> Result<&'static str, ArchiveError>
+{
+    let bytes = &header[start..start + len];
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+    str::from_utf8(&bytes[..end]).map_err(|_| ArchiveError::BadName)
+}
+
+/* An octal number field, padded with spaces or NULs */
+fn number(header: &[u8], (start, len): (usize, usize)) -> Result<usize, ArchiveError>
+{
+    let mut value: usize = 0;
+    for &b in header[start..start + len].iter() {
+        match b {
+            b'0'...b'7' => {
+                value = value.checked_mul(8).ok_or(ArchiveError::BadHeader)?
+                    + (b - b'0') as usize;
+            }
+            b' ' | 0 => {}
+            _ => return Err(ArchiveError::BadHeader),
+        }
+    }
+    Ok(value)
+}
+
+pub fn unpack(fs: &mut RamFs, archive: &'static [u8]) -> Result<(), ArchiveError>
+{
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= archive.len() {
+        let header = &archive[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if &header[MAGIC.0..MAGIC.0 + MAGIC.1] != b"ustar" {
+            return Err(ArchiveError::BadHeader);
+        }
+
+        let size = number(header, SIZE)?;
+        let mode = number(header, MODE)? as u32;
+        let data_start = offset + BLOCK_SIZE;
+        let data_end = data_start.checked_add(size).ok_or(ArchiveError::BadHeader)?;
+        if data_end > archive.len() {
+            return Err(ArchiveError::Truncated);
+        }
+
+        /* Long names are split into a prefix and the name proper */
+        let prefix = string(header, PREFIX)?;
+        let name = string(header, NAME)?;
+        let mut path = ::alloc::String::from(prefix);
+        if !prefix.is_empty() {
+            path.push('/');
+        }
+        path.push_str(name);
+
+        let node = match header[TYPEFLAG] {
+            REGTYPE | AREGTYPE => Some((NodeKind::File, &archive[data_start..data_end])),
+            DIRTYPE => Some((NodeKind::Directory, &archive[data_start..data_start])),
+            SYMTYPE => Some((NodeKind::Symlink, string(header, LINKNAME)?.as_bytes())),
+            /* Hard links, devices and extended headers are skipped */
+            _ => None,
+        };
+        if let Some((kind, data)) = node {
+            fs.insert(&path, Node { kind: kind, mode: mode & 0o7777, data: data });
+        }
+
+        offset = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+    Err(ArchiveError::Truncated)
+}
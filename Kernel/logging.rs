@@ -8,34 +8,73 @@
  * This code has been put into the public domain, there are no restrictions on
  * its use, and the author takes no liability.
  */
-use core::sync::atomic::{AtomicUsize, Ordering};
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use sync::{IrqSpinLock, IrqSpinLockGuard};
 
-/// A formatter object
-pub struct Writer(usize);
+/// A formatter object, holds the logging lock until dropped
+pub struct Writer(IrqSpinLockGuard<'static, ()>);
 
-/// A primitive lock for the logging output
+/// Serializes the logging output
 ///
-/// This is not really a lock. Since there is no threading at the moment, all
-/// it does is prevent writing when a collision would occur.
-static LOGGING_LOCK: AtomicUsize = AtomicUsize::new(0);
+/// Interrupt handlers log too, so this has to disable interrupts while held.
+static LOGGING_LOCK: IrqSpinLock<()> = IrqSpinLock::named("logging", ());
+
+/// Set once the kernel has panicked, see `enter_panic_mode`
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// How important a message is
+///
+/// Messages less important than the current level, set with `loglevel=` on
+/// the command line, are dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level
+{
+	Error,
+	Warning,
+	Info,
+	Debug,
+}
+
+/// The least important level that is still printed
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Drop messages less important than `level` from now on
+pub fn set_level(level: Level)
+{
+	LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Whether messages of `level` are printed
+pub fn enabled(level: Level) -> bool
+{
+	level as usize <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// How many times to retry the lock before breaking it while panicking
+const PANIC_LOCK_RETRIES: usize = 10_000_000;
 
 impl Writer
 {
 	/// Obtain a logger for the specified module
 	pub fn get(module: &str) -> Writer {
-		// This "acquires" the lock (actually just disables output if paralel writes are attempted
-                loop {
-                    if (LOGGING_LOCK.compare_and_swap(0, 1, Ordering::SeqCst) == 0) {
-                        break;
-                    }
-                }
-
-		let mut ret = Writer(1);
+		let guard = if PANICKING.load(Ordering::Relaxed) {
+			panic_lock()
+		} else {
+			LOGGING_LOCK.lock()
+		};
+		let mut ret = Writer(guard);
 		
-		// Print the module name before returning (prefixes all messages)
+		// Print the uptime, CPU and module name before returning (prefixes all messages)
 		{
 			use core::fmt::Write;
+			let now = ::time::now();
+			let _ = write!(&mut ret, "[{:5}.{:06}] ",
+			               now / 1_000_000_000, (now % 1_000_000_000) / 1000);
+			let _ = match ::arch::percpu::try_cpu_id() {
+				Some(cpu) => write!(&mut ret, "[CPU{}] ", cpu),
+				None => write!(&mut ret, "[CPU?] "),
+			};
 			let _ = write!(&mut ret, "[{}] ", module);
 		}
 		
@@ -43,17 +82,56 @@ impl Writer
 	}
 }
 
+/// Make the logger usable from the panic handler
+///
+/// The panicking code may hold the logging lock itself, or another CPU may
+/// have died holding it, so from now on it is broken after a while rather
+/// than waited on forever.
+pub fn enter_panic_mode()
+{
+	PANICKING.store(true, Ordering::SeqCst);
+}
+
+/// Write `bytes` to the log as they are, without any prefix
+///
+/// Used for output of user programs, which brings its own newlines.
+pub fn write_raw(bytes: &[u8])
+{
+	let _guard = LOGGING_LOCK.lock();
+	for &b in bytes
+	{
+		unsafe {
+			::arch::debug::putb(b);
+		}
+	}
+}
+
+fn panic_lock() -> IrqSpinLockGuard<'static, ()>
+{
+	loop
+	{
+		for _ in 0 .. PANIC_LOCK_RETRIES
+		{
+			if let Some(guard) = LOGGING_LOCK.try_lock() {
+				return guard;
+			}
+		}
+		// Whoever holds it is not going to give it back
+		unsafe {
+			LOGGING_LOCK.force_unlock();
+		}
+	}
+}
+
 impl ::core::ops::Drop for Writer
 {
 	fn drop(&mut self)
 	{
-		// Write a terminating newline before releasing the lock
+		// Write a terminating newline, the lock is released with the guard
 		{
 			use core::fmt::Write;
 			let _ = write!(self, "\n");
 		}
-		// On drop, "release" the lock
-                LOGGING_LOCK.store(0, Ordering::SeqCst);
 	}
 }
 
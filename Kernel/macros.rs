@@ -14,10 +14,58 @@
 /// Obtaines a logger instance (locking the log channel) with the current module name passed
 /// then passes the standard format! arguments to it
 macro_rules! log{
-	( $($arg:tt)* ) => ({
-		// Import the Writer trait (required by write!)
-		use core::fmt::Write;
-		let _ = write!(&mut ::logging::Writer::get(module_path!()), $($arg)*);
+	( $($arg:tt)* ) => ( log_at!(::logging::Level::Info, "", $($arg)*) )
+}
+
+/// Log a message about something that went wrong, printed at any log level
+macro_rules! error{
+	( $($arg:tt)* ) => ( log_at!(::logging::Level::Error, "ERROR: ", $($arg)*) )
+}
+
+/// Log a message about something that is probably a mistake
+macro_rules! warn{
+	( $($arg:tt)* ) => ( log_at!(::logging::Level::Warning, "WARNING: ", $($arg)*) )
+}
+
+/// Log a message only of interest when debugging, i.e. with `loglevel=debug`
+macro_rules! debug{
+	( $($arg:tt)* ) => ( log_at!(::logging::Level::Debug, "", $($arg)*) )
+}
+
+/// Log a message if `$level` is enabled, used by the macros above
+macro_rules! log_at{
+	( $level:expr, $prefix:expr, $($arg:tt)* ) => ({
+		if ::logging::enabled($level) {
+			// Import the Writer trait (required by write!)
+			use core::fmt::Write;
+			let mut writer = ::logging::Writer::get(module_path!());
+			let _ = writer.write_str($prefix);
+			let _ = write!(&mut writer, $($arg)*);
+		}
 	})
 }
 
+/// Declare a kernel command line option, see cmdline.rs
+///
+/// `kernel_param!(NAME, flag "option", handler)` declares a flag, `handler`
+/// is called if it is present. `kernel_param!(NAME, "option", handler)`
+/// declares an option with a value, `handler` is given the value.
+macro_rules! kernel_param{
+	( $id:ident, flag $name:expr, $handler:expr ) => (
+		#[used]
+		#[link_section = ".kernel_params"]
+		static $id: ::cmdline::Param = ::cmdline::Param {
+			name: $name,
+			kind: ::cmdline::ParamKind::Flag($handler),
+		};
+	);
+	( $id:ident, $name:expr, $handler:expr ) => (
+		#[used]
+		#[link_section = ".kernel_params"]
+		static $id: ::cmdline::Param = ::cmdline::Param {
+			name: $name,
+			kind: ::cmdline::ParamKind::Value($handler),
+		};
+	);
+}
+
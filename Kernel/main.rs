@@ -17,6 +17,7 @@
 #![feature(lang_items)]	// Language items!
 #![feature(integer_atomics)]	// Atomics for integers
 #![feature(const_vec_new)] // CTFE for Vec::new()
+#![feature(used)]	// Keeping command line options, see kernel_param!
 #![no_std]	//< Kernels can't use std
 #![crate_name="kernel"]
 
@@ -39,18 +40,57 @@ pub mod arch;
 #[cfg(target_arch="x86")] #[path="arch/x86/mod.rs"]
 pub mod arch;
 
+// What generic code may use of the arch module, checked for every port.
+#[path="arch/contract.rs"]
+mod arch_contract;
+
 // Exception handling (panic).
 pub mod unwind;
 
+// Error numbers.
+mod errno;
+
+// Locking primitives.
+mod sync;
+
 // Logging code.
 mod logging;
 
+// What the bootloader told us.
+mod boot;
+
+// Kernel command line options.
+mod cmdline;
+
 // Memory management.
 mod mm;
-use mm::alloc::{SimpleBumpAllocator, HEAP_START, HEAP_SIZE};
+
+// Timers and the tick.
+mod timer;
+
+// Clocksources and wall-clock time.
+mod time;
+
+// Kernel threads and the scheduler.
+mod sched;
+
+// Running functions on other CPUs.
+mod smp;
+
+// System calls from user space.
+mod syscall;
+
+// Loading and running user programs.
+mod exec;
+
+// PCI bus enumeration and drivers.
+mod pci;
+
+// The initramfs.
+mod fs;
+use mm::alloc::{HeapAllocator, HEAP_SIZE};
 #[global_allocator]
-static HEAP_ALLOCATOR: SimpleBumpAllocator
-    = SimpleBumpAllocator::new(HEAP_START, HEAP_START + HEAP_SIZE);
+static HEAP_ALLOCATOR: HeapAllocator = HeapAllocator::empty();
 
 use alloc::boxed::Box;
 use alloc::Vec;
@@ -65,20 +105,30 @@ pub fn kmain()
 	log!("Graddadwy Research Kernel version {}.{}.{}-git", 0, 0, 1);
 
     /* Initialize the early architecture. */
-    let (mem_sz, page_sz): (usize, usize) = arch::early_init();
+    let boot_info = boot::init(arch::early_init());
+
+    /* Apply the command line, everything from here on can depend on it. */
+    cmdline::parse(boot_info.cmdline);
 
-    log!("Available memory: {} bytes (~{} MB)", mem_sz, mem_sz / 1024 / 1024);
-    log!("Default page size: {} bytes", page_sz);
+    /* Decide where things go in the kernel's address space. */
+    mm::layout::init();
 
     /* Initialize the physical memory manager. */
-    let mut fma = mm::pmm::init(mem_sz as u32, page_sz as u32);
+    let mut fma = mm::pmm::init(boot_info);
 
     /* Remap the kernel, so we take control of the paging structures. */
-    mm::vmm::remap_kernel(&mut fma);
-
-    /* Initialize the allocator, so that we can use Boxed types */
-    let test_fr = fma.allocate_frame();
-    mm::vmm::map_addr_current(&mut fma, test_fr.frame_addr(), HEAP_START);
+    let memory_end = fma.memory_end();
+    mm::vmm::remap_kernel(&mut fma, memory_end);
+
+    /* Map the whole heap, so that we can use Boxed types */
+    let heap_start = mm::layout::heap_base();
+    let mut heap_page = heap_start;
+    while heap_page < heap_start + HEAP_SIZE {
+        let heap_fr = fma.allocate_frame();
+        mm::vmm::map_addr_current(&mut fma, heap_fr.frame_addr(), heap_page);
+        heap_page += arch::PAGE_SIZE;
+    }
+    HEAP_ALLOCATOR.init(heap_start, heap_start + HEAP_SIZE);
 
     //let box_test = Box::new(42);
     //let mut vec_test: Vec<usize> = vec![1, 2, 3, 4];
@@ -88,8 +138,52 @@ pub fn kmain()
 
     arch::late_init(&mut fma);
 
-    log!("Looping... ");
-    loop {}
+    time::init(&mut fma);
+
+    /* Boot is done with the frame allocator, share it with everyone. */
+    mm::pmm::install(fma);
+
+    timer::init();
+    sched::init();
+    sync::rcu::init();
+    smp::init();
+    timer::init_cpu();
+
+    pci::init();
+    fs::init();
+    sched::spawn("init", run_init);
+
+    arch::start_aps();
+
+    sched::start();
+}
+
+/* Where user space starts, run in the first task, "init=" overrides it */
+static mut INIT_PATH: &'static str = "/init";
+
+kernel_param!(PARAM_INIT, "init", set_init_path);
+
+fn set_init_path(value: &'static str) -> Result<(), cmdline::ParamError>
+{
+    if !value.starts_with('/') {
+        return Err(cmdline::ParamError::Invalid);
+    }
+    unsafe {
+        INIT_PATH = value;
+    }
+    Ok(())
+}
+
+fn run_init()
+{
+    let path = unsafe { INIT_PATH };
+    match fs::lookup(path) {
+        Some(ref node) if node.kind == fs::NodeKind::File => {
+            let error = exec::exec(node.data, &[path], &[]);
+            log!("Can't run {}: {}", path, error);
+        }
+        _ => log!("No {} in the initramfs, not starting user space", path),
+    }
 }
 
 /* Entry point of APs */
@@ -97,7 +191,7 @@ pub fn kmain()
 pub unsafe fn kmain_ap()
 {
     let cpu_id = cpus_booted.fetch_add(1, Ordering::SeqCst);
-    log!("An AP with id {} has come online, looping.", cpu_id);
+    log!("An AP with id {} has come online", cpu_id);
     ::arch::new_cpu_init(cpu_id);
-    loop {}
+    unreachable!();
 }
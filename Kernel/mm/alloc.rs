@@ -1,24 +1,66 @@
 #[path="../logging.rs"]
 mod logging;
 
-use core::sync::atomic::{AtomicUsize, Ordering};
-use core::ptr::NonNull;
+use core::mem;
+use core::ptr::{self, NonNull};
 use alloc::alloc::{Alloc, AllocErr, Layout, GlobalAlloc};
 
-pub const HEAP_START: usize = 2 * 1024 * 1024 + ::arch::KERNEL_BASE;
-pub const HEAP_SIZE: usize = 128 * 1024; /* 128KiB */
+use sync::IrqSpinLock;
 
-#[derive(Debug)]
-pub struct SimpleBumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: AtomicUsize,
+/* The heap lives at mm::layout::heap_base(), which is only known at boot */
+pub const HEAP_SIZE: usize = 8 * 1024 * 1024; /* 8MiB */
+
+/*
+ * A free range of the heap, the header sits at its start. Blocks are kept
+ * sorted by address, so a freed one can be merged with its neighbours.
+ */
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/*
+ * Every block starts and ends on this, so whatever is left over around an
+ * allocation is either nothing or big enough for a FreeBlock header.
+ */
+const BLOCK_ALIGN: usize = 2 * mem::size_of::<usize>();
+
+struct FreeList {
+    head: *mut FreeBlock,
+}
+
+unsafe impl Send for FreeList {}
+
+/*
+ * A first fit allocator over a free list. Tasks, their stacks and timer
+ * and RCU callbacks come and go all the time, so the heap has to take
+ * memory back. Interrupt handlers allocate and free too, hence the
+ * IrqSpinLock.
+ */
+pub struct HeapAllocator {
+    free: IrqSpinLock<FreeList>,
 }
 
-impl SimpleBumpAllocator {
-    pub const fn new(heap_start: usize, heap_end: usize) -> Self {
-        Self { heap_start, heap_end, next: AtomicUsize::new(heap_start), }
+impl HeapAllocator {
+    /* An allocator that fails every allocation until `init` is called */
+    pub const fn empty() -> Self {
+        HeapAllocator { free: IrqSpinLock::named("heap", FreeList { head: 0 as *mut FreeBlock }) }
     }
+
+    /* Start handing out [heap_start, heap_end), which must be mapped */
+    pub fn init(&self, heap_start: usize, heap_end: usize) {
+        let start = align_up(heap_start, BLOCK_ALIGN);
+        let end = align_down(heap_end, BLOCK_ALIGN);
+        let block = start as *mut FreeBlock;
+        unsafe { ptr::write(block, FreeBlock { size: end - start, next: ptr::null_mut() }); }
+        self.free.lock().head = block;
+    }
+}
+
+/* How much of the heap an allocation of `layout` takes */
+fn block_size(layout: &Layout) -> usize
+{
+    align_up(layout.size().max(1), BLOCK_ALIGN)
 }
 
 #[lang = "oom"]
@@ -41,27 +83,66 @@ pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
 
-unsafe impl GlobalAlloc for SimpleBumpAllocator {
+unsafe impl GlobalAlloc for HeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        loop {
-            let curr_next = self.next.load(Ordering::Relaxed);
-            let alloc_start = align_up(curr_next, layout.align());
-            let alloc_end = alloc_start.saturating_add(layout.size());
-
-            if alloc_end <= self.heap_end {
-                let next_now = self.next.compare_and_swap(curr_next, alloc_end,
-                                                          Ordering::Relaxed);
-                if next_now == curr_next {
-                    log!("allocated {} bytes", alloc_end - alloc_start);
-                    return alloc_start as *mut u8;
-                }
+        let size = block_size(&layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+
+        let mut list = self.free.lock();
+        let mut link: *mut *mut FreeBlock = &mut list.head;
+        while !(*link).is_null() {
+            let block = *link;
+            let start = block as usize;
+            let end = start + (*block).size;
+            let alloc_start = align_up(start, align);
+            let alloc_end = alloc_start.saturating_add(size);
+            if alloc_end > end {
+                link = &mut (*block).next;
+                continue;
+            }
+
+            /* Give back what is left on either side */
+            let mut rest = (*block).next;
+            if alloc_end < end {
+                let tail = alloc_end as *mut FreeBlock;
+                ptr::write(tail, FreeBlock { size: end - alloc_end, next: rest });
+                rest = tail;
+            }
+            if alloc_start > start {
+                (*block).size = alloc_start - start;
+                (*block).next = rest;
             } else {
-                return 0 as *mut u8;
+                *link = rest;
             }
+            return alloc_start as *mut u8;
         }
+        0 as *mut u8
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        log!("WARNING: leaking memory: {:?}", layout);
+        let start = ptr as usize;
+        let size = block_size(&layout);
+
+        let mut list = self.free.lock();
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut link: *mut *mut FreeBlock = &mut list.head;
+        while !(*link).is_null() && (*link as usize) < start {
+            prev = *link;
+            link = &mut (*prev).next;
+        }
+
+        let next = *link;
+        let block = start as *mut FreeBlock;
+        ptr::write(block, FreeBlock { size: size, next: next });
+        if !next.is_null() && start + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+        if !prev.is_null() && prev as usize + (*prev).size == start {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            *link = block;
+        }
     }
 }
@@ -0,0 +1,180 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/*
+ * The layout of the kernel half of the address space. The direct map of
+ * physical memory, the heap and the vmalloc area each get a region, and
+ * at boot are placed at a random, aligned offset inside it (KASLR), so an
+ * attacker can't hardcode where kernel data lives.
+ *
+ * On x86_64 the image moves too, before any Rust code runs: link.ld puts
+ * it at KERNEL_BASE, and start.S adds a random multiple of 2MiB to every
+ * address in it using the relocations the build keeps (relocs.awk) and
+ * records that in kernel_slide. It checks for "nokaslr" itself.
+ */
+
+/* Where the regions are, none of them overlaps the image's PML4 slot */
+#[cfg(target_arch="x86_64")]
+const DIRECT_MAP_REGION: Region = Region {
+    start: 0xFFFF_8800_0000_0000,
+    size: 64 << 40,
+    used: DIRECT_MAP_SIZE,
+    align: 1 << 30,
+};
+#[cfg(target_arch="x86_64")]
+const VMALLOC_REGION: Region = Region {
+    start: 0xFFFF_C900_0000_0000,
+    size: 32 << 40,
+    used: VMALLOC_SIZE,
+    align: 1 << 30,
+};
+#[cfg(target_arch="x86_64")]
+const HEAP_REGION: Region = Region {
+    start: 0xFFFF_E000_0000_0000,
+    size: 16 << 40,
+    used: 1 << 30,
+    align: 2 << 20,
+};
+
+/* How much physical memory the direct map can cover */
+#[cfg(target_arch="x86_64")]
+pub const DIRECT_MAP_SIZE: usize = 1 << 40;
+
+/* How much address space there is for mapping MMIO and the like */
+#[cfg(target_arch="x86_64")]
+pub const VMALLOC_SIZE: usize = 1 << 40;
+
+/*
+ * The 1GiB kernel half of a 32-bit address space has no room to spare:
+ * the direct map fills the bottom 768MiB of it, so only the heap and
+ * vmalloc area move. The image is inside the direct map, which maps it
+ * the same way, so it stays at KERNEL_BASE too.
+ */
+#[cfg(target_arch="x86")]
+const DIRECT_MAP_REGION: Region = Region {
+    start: 0xC000_0000,
+    size: DIRECT_MAP_SIZE,
+    used: DIRECT_MAP_SIZE,
+    align: 4 << 20,
+};
+#[cfg(target_arch="x86")]
+const HEAP_REGION: Region = Region {
+    start: 0xF000_0000,
+    size: 64 << 20,
+    used: 16 << 20,
+    align: 2 << 20,
+};
+#[cfg(target_arch="x86")]
+const VMALLOC_REGION: Region = Region {
+    start: 0xF400_0000,
+    size: 128 << 20,
+    used: VMALLOC_SIZE,
+    align: 4 << 20,
+};
+
+#[cfg(target_arch="x86")]
+pub const DIRECT_MAP_SIZE: usize = 768 << 20;
+
+#[cfg(target_arch="x86")]
+pub const VMALLOC_SIZE: usize = 64 << 20;
+
+struct Region {
+    start: usize,
+    size: usize,
+    /* How much of the region is actually used from the base */
+    used: usize,
+    align: usize,
+}
+
+impl Region {
+    fn place(&self, random: u64) -> usize {
+        let slots = (self.size - self.used) / self.align + 1;
+        self.start + (random as usize % slots) * self.align
+    }
+}
+
+/*
+ * Until the kernel page tables are up physical memory is reached through
+ * the boot mapping of the image, 0 here, vmm::remap_kernel switches to the
+ * randomised base.
+ */
+static DIRECT_MAP_BASE: AtomicUsize = AtomicUsize::new(0);
+static DIRECT_MAP_TARGET: AtomicUsize = AtomicUsize::new(DIRECT_MAP_REGION.start);
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(HEAP_REGION.start);
+static VMALLOC_BASE: AtomicUsize = AtomicUsize::new(VMALLOC_REGION.start);
+
+/* "nokaslr" keeps the regions at their start, "kaslr_debug" logs the bases */
+static KASLR: AtomicBool = AtomicBool::new(true);
+static KASLR_DEBUG: AtomicBool = AtomicBool::new(false);
+
+kernel_param!(PARAM_NOKASLR, flag "nokaslr", disable_kaslr);
+kernel_param!(PARAM_KASLR_DEBUG, flag "kaslr_debug", enable_kaslr_debug);
+
+fn disable_kaslr()
+{
+    KASLR.store(false, Ordering::Relaxed);
+}
+
+fn enable_kaslr_debug()
+{
+    KASLR_DEBUG.store(true, Ordering::Relaxed);
+}
+
+/* Pick the bases of the regions, after the command line is parsed */
+pub fn init()
+{
+    let enabled = KASLR.load(Ordering::Relaxed);
+    if enabled {
+        DIRECT_MAP_TARGET.store(DIRECT_MAP_REGION.place(::arch::cpu::random_u64()),
+                                Ordering::SeqCst);
+        HEAP_BASE.store(HEAP_REGION.place(::arch::cpu::random_u64()), Ordering::SeqCst);
+        VMALLOC_BASE.store(VMALLOC_REGION.place(::arch::cpu::random_u64()), Ordering::SeqCst);
+    }
+
+    if KASLR_DEBUG.load(Ordering::Relaxed) {
+        log!("KASLR {}: image at 0x{:x}, direct map at 0x{:x}, heap at 0x{:x}, \
+              vmalloc at 0x{:x}",
+             if enabled { "enabled" } else { "disabled" },
+             image_base(), direct_map_target(), heap_base(), vmalloc_base());
+    }
+}
+
+/* Where physical address 0 is in the image's mapping, KERNEL_BASE before KASLR */
+pub fn image_base() -> usize
+{
+    extern "C" {
+        /* Set by start.S */
+        static kernel_slide: usize;
+    }
+    ::arch::KERNEL_BASE + unsafe { kernel_slide }
+}
+
+/* The kernel virtual address of physical address `addr` */
+pub fn phys_to_virt(addr: usize) -> usize
+{
+    match DIRECT_MAP_BASE.load(Ordering::Relaxed) {
+        0 => image_base() + addr,
+        base => base + addr,
+    }
+}
+
+/* Where vmm::remap_kernel puts the direct map */
+pub fn direct_map_target() -> usize
+{
+    DIRECT_MAP_TARGET.load(Ordering::Relaxed)
+}
+
+/* Called once the direct map at `direct_map_target()` is live */
+pub fn direct_map_ready()
+{
+    DIRECT_MAP_BASE.store(direct_map_target(), Ordering::SeqCst);
+}
+
+pub fn heap_base() -> usize
+{
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+pub fn vmalloc_base() -> usize
+{
+    VMALLOC_BASE.load(Ordering::Relaxed)
+}
@@ -1,5 +1,6 @@
 pub mod pmm;
 pub mod vmm;
+pub mod layout;
 
 #[path = "alloc.rs"]
 pub mod alloc;
@@ -3,6 +3,11 @@ mod logging;
 
 use core::mem;
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use boot::BootInfo;
+use mm::layout;
+use sync::SpinLock;
 
 #[derive(Clone, Copy)]
 pub struct Frame {
@@ -33,29 +38,114 @@ impl Frame {
     }
 }
 
+/* How many physical ranges can be kept from the allocator */
+const MAX_RESERVED: usize = 24;
+
 pub struct FrameAllocator {
     next_free_frame: Frame,
+    /* The first frame past the end of usable memory */
+    end_frame: usize,
     pub page_size: usize,
+    /* Ranges of frame ids never to hand out, [start, end) */
+    reserved: [(usize, usize); MAX_RESERVED],
+    nr_reserved: usize,
+    /*
+     * Freed frames, each holding the address of the next one in its first
+     * word, 0 if there are none
+     */
+    free_list: usize,
 }
 
 impl FrameAllocator {
     pub fn allocate_frame(&mut self) -> Frame {
+        if self.free_list != 0 {
+            let addr = self.free_list;
+            self.free_list = unsafe { *(layout::phys_to_virt(addr) as *const usize) };
+            return Frame::get_frame_for(addr, self.page_size);
+        }
+
+        /* Skip over reserved frames */
+        let mut id = self.next_free_frame.frame_id;
+        while let Some(&(_, end)) = self.reserved[..self.nr_reserved].iter()
+                .find(|&&(start, end)| start <= id && id < end) {
+            id = end;
+        }
+        if id >= self.end_frame {
+            panic!("out of physical memory");
+        }
+
         /* Return the next_free_frame, then increment to the next */
-        let ret = self.next_free_frame.clone();
+        let ret = Frame::get_frame_by_id(id);
         self.next_free_frame =
             Frame::get_frame_by_id(ret.frame_id + 1);
-        log!("Allocated frame id {} addr 0x{:x}", ret,
-             ret.frame_addr());
         ret
     }
 
+    /*
+     * Never hand out the frames of the physical range [start, end), e.g.
+     * because the bootloader put something there.
+     */
+    pub fn reserve(&mut self, start: usize, end: usize) {
+        if self.nr_reserved == MAX_RESERVED {
+            panic!("too many reserved ranges, can't reserve [0x{:x} - 0x{:x}]", start, end);
+        }
+        let first = start / self.page_size;
+        let last = (end + self.page_size - 1) / self.page_size;
+        self.reserved[self.nr_reserved] = (first, last);
+        self.nr_reserved += 1;
+        log!("Reserved frames [0x{:x} - 0x{:x}]", first * self.page_size, last * self.page_size);
+    }
+
+    /* The end of the physical memory frames are allocated from */
+    pub fn memory_end(&self) -> usize {
+        self.end_frame * self.page_size
+    }
+
+    /* Give back a frame from allocate_frame, nothing may use it any more */
     pub fn free_frame(&mut self, frame: Frame) {
-        /* FIXME: no-op */
+        let addr = frame.frame_addr();
+        unsafe { *(layout::phys_to_virt(addr) as *mut usize) = self.free_list; }
+        self.free_list = addr;
+    }
+}
+
+/* "mem=64M" limits the memory used to the first 64MiB */
+static MEM_LIMIT: AtomicUsize = AtomicUsize::new(usize::max_value());
+
+kernel_param!(PARAM_MEM, "mem", set_mem_limit);
+
+fn set_mem_limit(value: &'static str) -> Result<(), ::cmdline::ParamError>
+{
+    MEM_LIMIT.store(::cmdline::parse_size(value)?, Ordering::Relaxed);
+    Ok(())
+}
+
+/*
+ * How much of the `mem_size` bytes of memory above 1MiB to use. All of it
+ * must fit in the direct map, which is small on 32-bit.
+ */
+fn usable_memory(mem_size: usize) -> usize
+{
+    let limit = ::core::cmp::min(MEM_LIMIT.load(Ordering::Relaxed), layout::DIRECT_MAP_SIZE)
+        .saturating_sub(1024 * 1024);
+    if limit < mem_size {
+        log!("Limiting memory to {} of {} bytes", limit, mem_size);
+        limit
+    } else {
+        mem_size
     }
 }
 
-pub fn init(mem_size: u32, page_size: u32) -> FrameAllocator
+/*
+ * Set up the allocator for the memory from 1MiB to the first hole, keeping
+ * the kernel image, the boot modules and the bootloader's data out of it.
+ */
+pub fn init(boot_info: &BootInfo) -> FrameAllocator
 {
+    let mem_size = usable_memory(boot_info.upper_memory());
+    let page_size = ::arch::PAGE_SIZE;
+    log!("Available memory: {} bytes (~{} MB)", mem_size, mem_size / 1024 / 1024);
+
     /* Determine the end of the kernel */
     extern "C" {
         /* Defined in the linker script */
@@ -73,17 +163,49 @@ pub fn init(mem_size: u32, page_size: u32) -> FrameAllocator
     /* The free frames start right after the kernel */
     let next_free_frame = Frame::get_frame_for(
                       _kernel_end
-                    - (::arch::KERNEL_BASE as usize)
-                    + 2 * page_size as usize,
-                    page_size as usize);
+                    - layout::image_base()
+                    + 2 * page_size,
+                    page_size);
     log!("Kernel ends at 0x{:x}, so the next free frame is at 0x{:x}",
          _kernel_end, next_free_frame.frame_addr());
 
     /* Start a basic frame allocation algorithm. */
-    let ret = FrameAllocator {
-        next_free_frame: Frame::get_frame_for(next_free_frame.frame_addr(),
-                                              page_size as usize),
-        page_size: page_size as usize,
+    let mut ret = FrameAllocator {
+        next_free_frame: Frame::get_frame_for(next_free_frame.frame_addr(), page_size),
+        end_frame: (1024 * 1024 + mem_size) / page_size,
+        page_size: page_size,
+        reserved: [(0, 0); MAX_RESERVED],
+        nr_reserved: 0,
+        free_list: 0,
     };
+    for module in boot_info.modules() {
+        ret.reserve(module.start, module.end);
+    }
+    for &(start, end) in boot_info.boot_data() {
+        ret.reserve(start, end);
+    }
     ret
 }
+
+/* The frame allocator once boot code is done with it, see `install` */
+static FRAMES: SpinLock<Option<FrameAllocator>> = SpinLock::named("frame allocator", None);
+
+/*
+ * Hand the allocator set up by `init` over to the rest of the kernel. Boot
+ * code passes it around by reference, later code uses `with_frames`.
+ */
+pub fn install(fma: FrameAllocator)
+{
+    let mut frames = FRAMES.lock();
+    assert!(frames.is_none(), "frame allocator installed twice");
+    *frames = Some(fma);
+}
+
+/* Run `f` with the frame allocator locked */
+pub fn with_frames<R, F: FnOnce(&mut FrameAllocator) -> R>(f: F) -> R
+{
+    match *FRAMES.lock() {
+        Some(ref mut fma) => f(fma),
+        None => panic!("frame allocator used before it was installed"),
+    }
+}
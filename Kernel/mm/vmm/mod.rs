@@ -2,278 +2,382 @@
 mod logging;
 
 use core::slice;
-use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use ::mm::pmm::{Frame, FrameAllocator};
 use ::arch::PAGE_SHIFT;
+use ::arch::paging::{self, Entry};
+use ::arch::uaccess::USER_END;
+use ::mm::layout::{self, phys_to_virt};
 
 pub type PhysAddr = usize;
 pub type VirtAddr = usize;
 
 pub static mut KERNEL_PAGE_DIRECTORY: usize = 0;
 
-/* Generic trait that describes all the tables that are used in Paging */
-trait PagingTable {
-    fn set_address(&mut self, addr: usize);
-    fn get_address(&self) -> usize;
-    fn clear(&mut self);
-}
+/*
+ * How much of low physical memory is mapped at the image base, which
+ * covers the image. The direct map covers all of memory, and at least this.
+ */
+pub const KERNEL_MAP_SIZE: usize = 32 * 1024 * 1024;
 
-struct MyType<'a, T: 'a>(&'a mut [T]);
+/*
+ * The page tables are walked the same way whatever the paging scheme,
+ * arch::paging describes its levels and the bits of an entry.
+ */
 
-impl<'a, T: 'a> From<Frame> for MyType<'a, T>
-    where T: PagingTable
+/* The table at physical address `addr`, through the direct map */
+fn table<'a>(addr: PhysAddr) -> &'a mut [Entry]
 {
-    fn from(fr: Frame) -> MyType<'a, T>
-    {
-        unsafe {
-            let ptr = mem::transmute(::arch::KERNEL_BASE + (fr.frame_id << PAGE_SHIFT));
-            MyType(slice::from_raw_parts_mut(ptr, 512))
-        }
-    }
+    unsafe { slice::from_raw_parts_mut(phys_to_virt(addr) as *mut Entry, paging::ENTRIES) }
 }
 
-/* Structure describing the PML4E */
-bitflags! {
-    pub struct Pml4Entry : u64 {
-        const PRESENT          = (1 << 0);
-        const READWRITE        = (1 << 1);
-        const USERSUPERVISOR   = (1 << 2);
-        const PAGEWT           = (1 << 3);
-        const PAGECACHEDISABLE = (1 << 4);
-        const ACCESSED         = (1 << 5);
-        const IGNORED          = (1 << 6);
-        const MUSTBEZERO       = (3 << 7);
-        const AVAILTOSOFTWARE  = (7 << 9);
-    }
-}
-impl Pml4Entry {
-    fn new_table<'a>(fr: Frame) -> &'a mut [Pml4Entry] {
-        match MyType::from(fr) { MyType(a) => a }
+/* A fresh table, whatever the frame held before would be taken for entries */
+fn new_table(fma: &mut FrameAllocator) -> PhysAddr
+{
+    let frame = fma.allocate_frame();
+    for entry in table(frame.frame_addr()).iter_mut() {
+        *entry = 0;
     }
+    frame.frame_addr()
 }
-impl PagingTable for Pml4Entry {
-    fn set_address(&mut self, addr: usize) {
-        let clean_addr = addr as u64 & !((1 << ::arch::PAGE_SHIFT) - 1);
-        self.bits = self.bits | clean_addr;
-    }
 
-    fn get_address(&self) -> usize {
-        (self.bits as u64 & !((1 << ::arch::PAGE_SHIFT) - 1)) as usize
+/* An entry of a table at `level` pointing to the table at `addr` */
+fn table_entry(level: usize, addr: PhysAddr, user: bool) -> Entry
+{
+    let mut flags = paging::PRESENT | paging::WRITABLE;
+    if user {
+        flags |= paging::USER;
     }
+    (addr as Entry & paging::ADDRESS_MASK) | (flags & (paging::PRESENT | paging::table_flags(level)))
+}
 
-    fn clear(&mut self) {
-        self.bits = 0;
-    }
+fn entry_address(entry: Entry) -> PhysAddr
+{
+    (entry & paging::ADDRESS_MASK) as PhysAddr
 }
 
-/* Structure describing the PDPE */
-bitflags! {
-    struct PdpEntry : u64 {
-        const PRESENT          = (1 << 0);
-        const READWRITE        = (1 << 1);
-        const USERSUPERVISOR   = (1 << 2);
-        const PAGEWT           = (1 << 3);
-        const PAGECACHEDISABLE = (1 << 4);
-        const ACCESSED         = (1 << 5);
-        const IGNORED          = (1 << 6);
-        const MUSTBEZERO       = (3 << 7);
-        const AVAILTOSOFTWARE  = (7 << 9);
-    }
+fn free_frame(fma: &mut FrameAllocator, addr: PhysAddr)
+{
+    fma.free_frame(Frame { frame_id: addr >> PAGE_SHIFT });
 }
-impl PdpEntry {
-    fn new_table<'a>(fr: Frame) -> &'a mut [PdpEntry] {
-        match MyType::from(fr) { MyType(a) => a }
+
+/* Free the table at `level`, everything below it and the pages it maps */
+fn free_table(fma: &mut FrameAllocator, level: usize, addr: PhysAddr)
+{
+    for &entry in table(addr).iter() {
+        if entry & paging::PRESENT == 0 {
+            continue;
+        }
+        if level == paging::LEVELS - 1 {
+            free_frame(fma, entry_address(entry));
+        } else if entry & paging::HUGE == 0 {
+            free_table(fma, level + 1, entry_address(entry));
+        }
     }
+    free_frame(fma, addr);
 }
-impl PagingTable for PdpEntry {
-    fn set_address(&mut self, addr: usize) {
-        let clean_addr = addr as u64 & !((1 << ::arch::PAGE_SHIFT) - 1);
-        self.bits = self.bits | clean_addr;
-    }
 
-    fn get_address(&self) -> usize {
-        (self.bits as u64 & !((1 << ::arch::PAGE_SHIFT) - 1)) as usize
-    }
+/* The index into the table at `level` for the address `addr` */
+fn index(level: usize, addr: VirtAddr) -> usize
+{
+    (addr >> paging::LEVEL_SHIFTS[level]) & (paging::ENTRIES - 1)
+}
 
-    fn clear(&mut self) {
-        self.bits = 0;
+/* The index at every level, from the root down */
+pub fn get_address_indices_for(addr: VirtAddr) -> [usize; paging::LEVELS]
+{
+    let mut indices = [0; paging::LEVELS];
+    for level in 0..paging::LEVELS {
+        indices[level] = index(level, addr);
     }
+    indices
 }
 
-/* Structure describing the PDE */
+/* How a page is mapped */
 bitflags! {
-    struct PdEntry : u64 {
-        const PRESENT          = (1 << 0);
-        const READWRITE        = (1 << 1);
-        const USERSUPERVISOR   = (1 << 2);
-        const PAGEWT           = (1 << 3);
-        const PAGECACHEDISABLE = (1 << 4);
-        const ACCESSED         = (1 << 5);
-        const IGNORED          = (1 << 6);
-        const MUSTBEZERO       = (1 << 7);
-        const IGNORED2         = (1 << 8);
-        const AVAILTOSOFTWARE  = (7 << 9);
+    pub struct MapFlags : u32 {
+        const WRITABLE         = (1 << 0);
+        const USER             = (1 << 1);
+        const EXECUTABLE       = (1 << 2);
+        /* Uncached, for memory mapped device registers */
+        const NO_CACHE         = (1 << 3);
     }
 }
-impl PdEntry {
-    fn new_table<'a>(fr: Frame) -> &'a mut [PdEntry] {
-        match MyType::from(fr) { MyType(a) => a }
-    }
+
+/* What the kernel's own mappings get */
+const KERNEL_FLAGS: MapFlags = MapFlags { bits: MapFlags::WRITABLE.bits | MapFlags::EXECUTABLE.bits };
+
+pub fn map_addr_in(root: PhysAddr, fma: &mut FrameAllocator, addr: usize, to: usize)
+{
+    map_page_in(root, fma, addr, to, KERNEL_FLAGS);
 }
-impl PagingTable for PdEntry {
-    fn set_address(&mut self, addr: usize) {
-        let clean_addr = addr as u64 & !((1 << ::arch::PAGE_SHIFT) - 1);
-        self.bits = self.bits | clean_addr;
-    }
 
-    fn get_address(&self) -> usize {
-        (self.bits as u64 & !((1 << ::arch::PAGE_SHIFT) - 1)) as usize
+/*
+ * Map the page at virtual address `to` to the frame at `addr`, in the
+ * tables with the root at `root`. The tables above a user page must allow
+ * user access as well, the page itself decides what is actually allowed.
+ */
+pub fn map_page_in(root: PhysAddr, fma: &mut FrameAllocator,
+                   addr: usize, to: usize, flags: MapFlags)
+{
+    let user = flags.contains(MapFlags::USER);
+    let mut table_addr = root;
+
+    for level in 0..paging::LEVELS - 1 {
+        let entries = table(table_addr);
+        let idx = index(level, to);
+        if entries[idx] & paging::PRESENT == 0 {
+            entries[idx] = table_entry(level, new_table(fma), user);
+        } else if user {
+            entries[idx] |= paging::USER & paging::table_flags(level);
+        }
+        table_addr = entry_address(entries[idx]);
     }
 
-    fn clear(&mut self) {
-        self.bits = 0;
+    let mut entry = (addr as Entry & paging::ADDRESS_MASK) | paging::PRESENT;
+    if flags.contains(MapFlags::WRITABLE) {
+        entry |= paging::WRITABLE;
     }
-}
-
-/* Structure describing the PTE */
-bitflags! {
-    struct PtEntry : u64 {
-        const PRESENT          = (1 << 0);
-        const READWRITE        = (1 << 1);
-        const USERSUPERVISOR   = (1 << 2);
-        const PAGEWT           = (1 << 3);
-        const PAGECACHEDISABLE = (1 << 4);
-        const ACCESSED         = (1 << 5);
-        const DIRTY            = (1 << 6);
-        const PAGEATTRTABLE    = (1 << 7);
-        const GLOBAL           = (1 << 8);
-        const AVAILTOSOFTWARE  = (7 << 9);
+    if user {
+        entry |= paging::USER;
     }
-}
-impl PtEntry {
-    fn new_table<'a>(fr: Frame) -> &'a mut [PtEntry] {
-        match MyType::from(fr) { MyType(a) => a }
+    if !flags.contains(MapFlags::EXECUTABLE) {
+        entry |= paging::no_execute();
     }
-}
-impl PagingTable for PtEntry {
-    fn set_address(&mut self, addr: usize) {
-        let clean_addr = addr as u64 & !((1 << ::arch::PAGE_SHIFT) - 1);
-        self.bits = self.bits | clean_addr;
+    if flags.contains(MapFlags::NO_CACHE) {
+        entry |= paging::NO_CACHE;
     }
+    table(table_addr)[index(paging::LEVELS - 1, to)] = entry;
+}
 
-    fn get_address(&self) -> usize {
-        (self.bits as u64 & !((1 << ::arch::PAGE_SHIFT) - 1)) as usize
+/* The frame and flags the page at `virt` is mapped to, if it is */
+pub fn translate_in(root: PhysAddr, virt: VirtAddr) -> Option<(PhysAddr, MapFlags)>
+{
+    let mut table_addr = root;
+    for level in 0..paging::LEVELS - 1 {
+        let entry = table(table_addr)[index(level, virt)];
+        /* Huge pages, as used by the boot tables, aren't followed */
+        if entry & paging::PRESENT == 0 || entry & paging::HUGE != 0 {
+            return None;
+        }
+        table_addr = entry_address(entry);
     }
-
-    fn clear(&mut self) {
-        self.bits = 0;
+    let pte = table(table_addr)[index(paging::LEVELS - 1, virt)];
+    if pte & paging::PRESENT == 0 {
+        return None;
     }
-}
 
-fn get_pml4_index_for(addr: usize) -> usize { (addr >> 39) & 0x1ff }
-fn get_pdp_index_for(addr: usize) -> usize {  (addr >> 30) & 0x1ff } 
-fn get_pd_index_for(addr: usize) -> usize {   (addr >> 21) & 0x1ff } 
-fn get_pt_index_for(addr: usize) -> usize {   (addr >> 12) & 0x1ff } 
+    let mut flags = MapFlags::empty();
+    flags.set(MapFlags::WRITABLE, pte & paging::WRITABLE != 0);
+    flags.set(MapFlags::USER, pte & paging::USER != 0);
+    flags.set(MapFlags::EXECUTABLE, pte & paging::no_execute() == 0);
+    flags.set(MapFlags::NO_CACHE, pte & paging::NO_CACHE != 0);
+    Some((entry_address(pte) + (virt & ((1 << PAGE_SHIFT) - 1)), flags))
+}
 
-/* (PML4E, PDPE, PDE, PTE) */
-pub fn get_address_indices_for(addr: usize) -> (usize, usize, usize, usize)
+pub fn map_addr_current(fma: &mut FrameAllocator, addr: usize, to: usize)
 {
-    (get_pml4_index_for(addr),
-        get_pdp_index_for(addr),
-        get_pd_index_for(addr),
-        get_pt_index_for(addr)
-        )
+    map_addr_in(::arch::page_directory_addr(), fma, addr, to);
 }
 
-pub fn map_addr_in(pml4_table: &mut [Pml4Entry], fma: &mut FrameAllocator,
-                  addr: usize, to: usize)
+/* Identity map the physical range [addr, addr + len) in the current address space */
+pub fn identity_map_current(fma: &mut FrameAllocator, addr: usize, len: usize)
 {
-    let (pml4_idx, pdp_idx, pd_idx, pt_idx) = get_address_indices_for(to);
-    let pdp_table: &mut [PdpEntry];
-    let pd_table: &mut [PdEntry];
-    let pt_table: &mut [PtEntry];
-
-    if pml4_table[pml4_idx].contains(Pml4Entry::PRESENT) {
-        unsafe {
-            pdp_table = slice::from_raw_parts_mut(
-                    mem::transmute(pml4_table[pml4_idx].get_address() + ::arch::KERNEL_BASE), 512);
-        }
-    } else {
-        let pdp_fr = fma.allocate_frame();
-        pdp_table = PdpEntry::new_table(pdp_fr);
-        pml4_table[pml4_idx].clear();
-        pml4_table[pml4_idx].set_address(pdp_fr.frame_addr());
-        pml4_table[pml4_idx].set(Pml4Entry::PRESENT, true);
-        pml4_table[pml4_idx].set(Pml4Entry::READWRITE, true);
+    let page_size = 1 << PAGE_SHIFT;
+    let mut page = addr & !(page_size - 1);
+    while page < addr + len {
+        map_addr_current(fma, page, page);
+        page += page_size;
     }
+}
 
-    if pdp_table[pdp_idx].contains(PdpEntry::PRESENT) {
-        unsafe {
-            pd_table = slice::from_raw_parts_mut(
-                    mem::transmute(pdp_table[pdp_idx].get_address() + ::arch::KERNEL_BASE), 512);
-        }
-    } else {
-        let pd_fr = fma.allocate_frame();
-        pd_table = PdEntry::new_table(pd_fr);
-        pdp_table[pdp_idx].clear();
-        pdp_table[pdp_idx].set_address(pd_fr.frame_addr());
-        pdp_table[pdp_idx].set(PdpEntry::PRESENT, true);
-        pdp_table[pdp_idx].set(PdpEntry::READWRITE, true);
-    }
+/* Bytes of the vmalloc area handed out so far */
+static VMALLOC_USED: AtomicUsize = AtomicUsize::new(0);
 
-    if pd_table[pd_idx].contains(PdEntry::PRESENT) {
-        unsafe {
-            pt_table = slice::from_raw_parts_mut(
-                    mem::transmute(pd_table[pd_idx].get_address() + ::arch::KERNEL_BASE), 512);
-        }
-    } else {
-        let pt_fr = fma.allocate_frame();
-        pt_table = PtEntry::new_table(pt_fr);
-        pd_table[pd_idx].clear();
-        pd_table[pd_idx].set_address(pt_fr.frame_addr());
-        pd_table[pd_idx].set(PdEntry::PRESENT, true);
-        pd_table[pd_idx].set(PdEntry::READWRITE, true);
+/* Take `size` bytes, a multiple of the page size, of the vmalloc area */
+fn vmalloc_reserve(size: usize) -> VirtAddr
+{
+    let offset = VMALLOC_USED.fetch_add(size, Ordering::SeqCst);
+    if offset + size > layout::VMALLOC_SIZE {
+        panic!("vmalloc area exhausted taking 0x{:x} bytes", size);
     }
+    layout::vmalloc_base() + offset
+}
 
-    pt_table[pt_idx].clear();
-    pt_table[pt_idx].set_address(addr);
-    pt_table[pt_idx].set(PtEntry::PRESENT, true);
-    pt_table[pt_idx].set(PtEntry::READWRITE, true);
+/*
+ * Map the physical range [addr, addr + len) of device registers into the
+ * vmalloc area, uncached and not executable, and return the virtual
+ * address of `addr`.
+ */
+pub fn map_io(fma: &mut FrameAllocator, addr: PhysAddr, len: usize) -> VirtAddr
+{
+    let page_size = 1 << PAGE_SHIFT;
+    let first = addr & !(page_size - 1);
+    let size = (addr + len - first + page_size - 1) & !(page_size - 1);
+
+    let virt = vmalloc_reserve(size);
+    let root = ::arch::page_directory_addr();
+    let mut page = 0;
+    while page < size {
+        map_page_in(root, fma, first + page, virt + page, MapFlags::WRITABLE | MapFlags::NO_CACHE);
+        page += page_size;
+    }
+    virt + (addr - first)
 }
 
-pub fn map_addr_current(fma: &mut FrameAllocator, addr: usize, to: usize)
+/*
+ * Map a kernel stack of `pages` fresh frames into the vmalloc area, with
+ * an unmapped guard page below it so that an overflow faults instead of
+ * running into whatever is there. Returns the top of the stack. Stacks
+ * taken this way are never freed.
+ */
+pub fn map_stack(fma: &mut FrameAllocator, pages: usize) -> VirtAddr
 {
-    unsafe {
-        map_addr_in(::arch::get_page_directory(), fma, addr, to);
+    let page_size = 1 << PAGE_SHIFT;
+    let guard = vmalloc_reserve((pages + 1) * page_size);
+    let root = ::arch::page_directory_addr();
+    for i in 1..pages + 1 {
+        let frame = fma.allocate_frame().frame_addr();
+        map_page_in(root, fma, frame, guard + i * page_size, MapFlags::WRITABLE);
     }
+    guard + (pages + 1) * page_size
 }
 
-pub fn remap_kernel<'a>(allocator: &mut FrameAllocator)
+/*
+ * Build the kernel page tables and switch to them: the image where
+ * start.S moved it and all of the `mem_size` bytes of physical memory in
+ * the direct map, so that any frame can be reached through phys_to_virt.
+ */
+pub fn remap_kernel<'a>(allocator: &mut FrameAllocator, mem_size: usize)
 {
-    let remap_target = ::arch::KERNEL_BASE as usize;
+    let remap_target = layout::image_base();
 
     log!("Attempting to remap the kernel to 0x{:x}, page 0x{:x}",
             remap_target, remap_target >> PAGE_SHIFT);
 
-    /* TODO: This should be moved to architecture specific code. */
-    /* Reserve a page for the PML4. */
-    let pml4: Frame = allocator.allocate_frame();
-    let pml4_table: &mut [Pml4Entry] = Pml4Entry::new_table(pml4);
+    /* Reserve a page for the root table. */
+    let root = new_table(allocator);
 
-    log!("Remap indices: (PML4E, PDPE, PDE, PTE) = {:?}",
-        get_address_indices_for(remap_target));
+    log!("Remap indices: {:?}", get_address_indices_for(remap_target));
 
-    for i in 0..512 {
+    for i in 0..(KERNEL_MAP_SIZE >> PAGE_SHIFT) {
         let offset = i << ::arch::PAGE_SHIFT;
         /* TODO: these frames need to be marked as not free */
-        map_addr_in(pml4_table, allocator, 0 + offset, remap_target + offset);
+        map_addr_in(root, allocator, 0 + offset, remap_target + offset);
+    }
+
+    let direct_map = layout::direct_map_target();
+    let direct_size = ::core::cmp::max(mem_size, KERNEL_MAP_SIZE);
+    for i in 0..(direct_size >> PAGE_SHIFT) {
+        let offset = i << ::arch::PAGE_SHIFT;
+        map_addr_in(root, allocator, 0 + offset, direct_map + offset);
+    }
+
+    /*
+     * Every address space shares the kernel half of the root table, so its
+     * entries must exist now: ones added later wouldn't be seen by
+     * address spaces created before. If the CPU caches the root entries
+     * (PAE), the user half ones can't be added later either.
+     */
+    let first = if paging::ROOT_ENTRIES_CACHED { 0 } else { paging::KERNEL_ROOT_START };
+    for entry in table(root)[first..paging::ROOT_ENTRIES].iter_mut() {
+        if *entry & paging::PRESENT == 0 {
+            *entry = table_entry(0, new_table(allocator), false);
+        }
     }
 
     unsafe {
-        ::arch::set_page_directory(pml4.frame_addr());
-        KERNEL_PAGE_DIRECTORY = pml4.frame_addr();
+        ::arch::set_page_directory(root);
+        KERNEL_PAGE_DIRECTORY = root;
     }
+    layout::direct_map_ready();
 
     log!("Remap successful!");
 }
+
+/*
+ * A user address space: the user half is its own, the kernel half is
+ * shared with the kernel page tables.
+ */
+pub struct AddressSpace {
+    root: PhysAddr,
+}
+
+impl AddressSpace {
+    /* An address space with nothing mapped in the user half */
+    pub fn new(fma: &mut FrameAllocator) -> AddressSpace {
+        let root = new_table(fma);
+        let entries = table(root);
+        if paging::ROOT_ENTRIES_CACHED {
+            for entry in entries[..paging::KERNEL_ROOT_START].iter_mut() {
+                *entry = table_entry(0, new_table(fma), true);
+            }
+        }
+        let kernel = table(unsafe { KERNEL_PAGE_DIRECTORY });
+        for i in paging::KERNEL_ROOT_START..paging::ROOT_ENTRIES {
+            entries[i] = kernel[i];
+        }
+        AddressSpace { root: root }
+    }
+
+    /* Map the user page at `virt` to the frame at `phys` */
+    pub fn map(&mut self, fma: &mut FrameAllocator, virt: VirtAddr, phys: PhysAddr,
+               flags: MapFlags) {
+        assert!(virt < USER_END, "mapping kernel address 0x{:x} as user", virt);
+        map_page_in(self.root, fma, phys, virt, flags | MapFlags::USER);
+    }
+
+    /* The physical address and flags `virt` is mapped to */
+    pub fn translate(&self, virt: VirtAddr) -> Option<(PhysAddr, MapFlags)> {
+        translate_in(self.root, virt)
+    }
+
+    /* Physical address of the root table, for loading into the MMU */
+    pub fn page_directory(&self) -> PhysAddr {
+        self.root
+    }
+
+    /*
+     * Free the user half's pages and tables and the root, for callers
+     * that hold the frame allocator already. It must not be loaded.
+     */
+    pub fn free(mut self, fma: &mut FrameAllocator) {
+        self.release(fma);
+    }
+
+    fn release(&mut self, fma: &mut FrameAllocator) {
+        assert!(self.root != ::arch::page_directory_addr(), "freeing the loaded address space");
+        for &entry in table(self.root)[..paging::KERNEL_ROOT_START].iter() {
+            if entry & paging::PRESENT != 0 {
+                free_table(fma, 1, entry_address(entry));
+            }
+        }
+        free_frame(fma, self.root);
+        self.root = 0;
+    }
+}
+
+/* The kernel half is shared, only the user half goes */
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.root != 0 {
+            ::mm::pmm::with_frames(|fma| self.release(fma));
+        }
+    }
+}
+
+/*
+ * Load the page tables of `space`, or the kernel's for tasks without one.
+ * Does nothing if they are loaded already.
+ */
+pub fn switch_to(space: Option<&AddressSpace>)
+{
+    let root = match space {
+        Some(space) => space.page_directory(),
+        None => unsafe { KERNEL_PAGE_DIRECTORY },
+    };
+    if root != ::arch::page_directory_addr() {
+        unsafe { ::arch::set_page_directory(root); }
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * The capability list of a function's configuration space. The ones the
+ * kernel uses are decoded, MSI, MSI-X and PCI Express, the others are only
+ * recorded.
+ */
+use alloc::Vec;
+
+use super::Address;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+const STATUS: u16 = 0x06;
+const STATUS_CAP_LIST: u16 = 1 << 4;
+
+/* Where the list starts, in type 0 and type 1 headers alike */
+const CAPABILITIES_POINTER: u16 = 0x34;
+
+/* 48 capabilities fill the space after the header, more means a loop */
+const MAX_CAPABILITIES: usize = 48;
+
+/* MSI message control */
+const MSI_CONTROL: u16 = 0x02;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_MASKABLE: u16 = 1 << 8;
+
+/* MSI-X message control and the table and PBA locations */
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_PBA: u16 = 0x08;
+
+/* PCI Express capabilities register */
+const PCIE_CAPABILITIES: u16 = 0x02;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Msi {
+    /* Of the capability in configuration space */
+    pub offset: u16,
+    /* The message address can be above 4GiB */
+    pub is_64bit: bool,
+    /* There's a mask and a pending register */
+    pub maskable: bool,
+    /* How many vectors the function can use, a power of two up to 32 */
+    pub max_vectors: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MsiX {
+    pub offset: u16,
+    /* Number of entries in the table */
+    pub table_size: u16,
+    /* The BAR the table is in and where in it */
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /* Likewise for the pending bit array */
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+/* What a PCI Express function is, from the capabilities register */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamPort,
+    DownstreamPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    IntegratedEndpoint,
+    EventCollector,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciExpress {
+    pub offset: u16,
+    pub version: u8,
+    pub port_type: PortType,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Capability {
+    Msi(Msi),
+    MsiX(MsiX),
+    PciExpress(PciExpress),
+    Other { id: u8, offset: u16 },
+}
+
+fn decode(addr: Address, id: u8, offset: u16) -> Capability
+{
+    match id {
+        CAP_MSI => {
+            let control = addr.read_u16(offset + MSI_CONTROL);
+            Capability::Msi(Msi {
+                offset: offset,
+                is_64bit: control & MSI_CONTROL_64BIT != 0,
+                maskable: control & MSI_CONTROL_MASKABLE != 0,
+                max_vectors: 1 << ((control >> 1) & 0x7).min(5),
+            })
+        }
+        CAP_MSIX => {
+            let control = addr.read_u16(offset + MSIX_CONTROL);
+            let table = addr.read_u32(offset + MSIX_TABLE);
+            let pba = addr.read_u32(offset + MSIX_PBA);
+            Capability::MsiX(MsiX {
+                offset: offset,
+                table_size: (control & 0x7FF) + 1,
+                table_bar: (table & 0x7) as u8,
+                table_offset: table & !0x7,
+                pba_bar: (pba & 0x7) as u8,
+                pba_offset: pba & !0x7,
+            })
+        }
+        CAP_PCIE => {
+            let capabilities = addr.read_u16(offset + PCIE_CAPABILITIES);
+            let port_type = match (capabilities >> 4) & 0xF {
+                0x0 => PortType::Endpoint,
+                0x1 => PortType::LegacyEndpoint,
+                0x4 => PortType::RootPort,
+                0x5 => PortType::UpstreamPort,
+                0x6 => PortType::DownstreamPort,
+                0x7 => PortType::PcieToPciBridge,
+                0x8 => PortType::PciToPcieBridge,
+                0x9 => PortType::IntegratedEndpoint,
+                0xA => PortType::EventCollector,
+                other => PortType::Unknown(other as u8),
+            };
+            Capability::PciExpress(PciExpress {
+                offset: offset,
+                version: (capabilities & 0xF) as u8,
+                port_type: port_type,
+            })
+        }
+        _ => Capability::Other { id: id, offset: offset },
+    }
+}
+
+/* Walk the capability list of the function at `addr` */
+pub fn read_capabilities(addr: Address) -> Vec<Capability>
+{
+    let mut caps = Vec::new();
+    if addr.read_u16(STATUS) & STATUS_CAP_LIST == 0 {
+        return caps;
+    }
+
+    let mut offset = (addr.read_u8(CAPABILITIES_POINTER) & !0x3) as u16;
+    while offset >= 0x40 {
+        if caps.len() == MAX_CAPABILITIES {
+            warn!("{}: the capability list loops, ignoring the rest", addr);
+            break;
+        }
+        let id = addr.read_u8(offset);
+        if id == 0xFF {
+            break;
+        }
+        caps.push(decode(addr, id, offset));
+        offset = (addr.read_u8(offset + 1) & !0x3) as u16;
+    }
+    caps
+}
@@ -0,0 +1,458 @@
+/*
+ * PCI and PCI Express devices. At boot every bus reachable from the root
+ * buses is scanned, through PCI-to-PCI bridges, and the functions found
+ * are kept with their BARs and capabilities. Drivers register what they
+ * handle by vendor and device id or by class, and are probed with every
+ * matching function no other driver has taken yet, whichever of the two
+ * comes first.
+ *
+ * The configuration space itself is reached through arch::pci. Drivers
+ * that want interrupts get MSI or MSI-X vectors through msi.rs.
+ */
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::Vec;
+use alloc::boxed::Box;
+
+use errno::Errno;
+use sync::SpinLock;
+
+mod caps;
+pub mod msi;
+
+pub use self::caps::{Capability, Msi, MsiX, PciExpress, PortType};
+
+/* The header common to every function */
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const REVISION: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0A;
+const CLASS: u16 = 0x0B;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+const INTERRUPT_LINE: u16 = 0x3C;
+const INTERRUPT_PIN: u16 = 0x3D;
+
+/* Type 0 (endpoint) headers */
+const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+const SUBSYSTEM_ID: u16 = 0x2E;
+
+/* Type 1 (PCI-to-PCI bridge) headers */
+const SECONDARY_BUS: u16 = 0x19;
+const SUBORDINATE_BUS: u16 = 0x1A;
+
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_ENDPOINT: u8 = 0;
+const HEADER_BRIDGE: u8 = 1;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+
+/* What a function with no device reads as */
+const NO_VENDOR: u16 = 0xFFFF;
+
+bitflags! {
+    pub struct Command : u16 {
+        const IO_SPACE         = (1 << 0);
+        const MEMORY_SPACE     = (1 << 1);
+        const BUS_MASTER       = (1 << 2);
+        const INTX_DISABLE     = (1 << 10);
+    }
+}
+
+/* A function, "segment:bus:device.function" */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub fn new(segment: u16, bus: u8, device: u8, function: u8) -> Address {
+        Address { segment: segment, bus: bus, device: device, function: function }
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        ::arch::pci::read(*self, offset, 1) as u8
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        ::arch::pci::read(*self, offset, 2) as u16
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        ::arch::pci::read(*self, offset, 4)
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        ::arch::pci::write(*self, offset, 1, value as u32)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        ::arch::pci::write(*self, offset, 2, value as u32)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        ::arch::pci::write(*self, offset, 4, value)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/* A base address register, with the size of the region it decodes */
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    Memory { addr: u64, size: u64, prefetchable: bool, is_64bit: bool },
+    Io { port: u32, size: u32 },
+}
+
+/* What a function is found to be, by enumeration */
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /* Without the multi-function bit */
+    pub header_type: u8,
+    /* Zero for bridges, which don't have them */
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    /* The upper half of a 64-bit BAR is None */
+    pub bars: [Option<Bar>; 6],
+    pub capabilities: Vec<Capability>,
+    /* INTA# to INTD# as 1 to 4, 0 if the function doesn't use INTx */
+    pub interrupt_pin: u8,
+    /* What the firmware routed INTx to, on the legacy PIC */
+    pub interrupt_line: u8,
+    /* Taken by a driver */
+    bound: AtomicBool,
+}
+
+/* The lowest set bit of what a BAR reads back after writing all ones */
+fn bar_size(mask: u64) -> u64
+{
+    mask & !(mask.wrapping_sub(1))
+}
+
+/*
+ * Decode the first `count` BARs of `addr`, sizing each by writing all ones
+ * to it and reading back which bits stick. Decoding is off meanwhile, so
+ * that the function doesn't claim whatever the all-ones pattern overlaps.
+ */
+fn read_bars(addr: Address, count: usize) -> [Option<Bar>; 6]
+{
+    let mut bars = [None; 6];
+    let command = addr.read_u16(COMMAND);
+    addr.write_u16(COMMAND, command & !(Command::IO_SPACE | Command::MEMORY_SPACE).bits());
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + 4 * index as u16;
+        let low = addr.read_u32(offset);
+        addr.write_u32(offset, !0);
+        let low_mask = addr.read_u32(offset);
+        addr.write_u32(offset, low);
+
+        if low & 0x1 != 0 {
+            let size = bar_size((low_mask & !0x3) as u64) as u32;
+            if size != 0 {
+                bars[index] = Some(Bar::Io { port: low & !0x3, size: size });
+            }
+            index += 1;
+            continue;
+        }
+
+        let is_64bit = (low >> 1) & 0x3 == 0x2 && index + 1 < count;
+        let mut base = (low & !0xF) as u64;
+        let mut mask = (low_mask & !0xF) as u64;
+        if is_64bit {
+            let high = addr.read_u32(offset + 4);
+            addr.write_u32(offset + 4, !0);
+            let high_mask = addr.read_u32(offset + 4);
+            addr.write_u32(offset + 4, high);
+            base |= (high as u64) << 32;
+            mask |= (high_mask as u64) << 32;
+        }
+        let size = bar_size(mask);
+        if size != 0 {
+            bars[index] = Some(Bar::Memory {
+                addr: base,
+                size: size,
+                prefetchable: low & 0x8 != 0,
+                is_64bit: is_64bit,
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    addr.write_u16(COMMAND, command);
+    bars
+}
+
+impl Device {
+    fn read(addr: Address) -> Device {
+        let header_type = addr.read_u8(HEADER_TYPE) & HEADER_TYPE_MASK;
+        let (bar_count, subsystem_vendor_id, subsystem_id) = match header_type {
+            HEADER_ENDPOINT => (6, addr.read_u16(SUBSYSTEM_VENDOR_ID), addr.read_u16(SUBSYSTEM_ID)),
+            HEADER_BRIDGE => (2, 0, 0),
+            _ => (0, 0, 0),
+        };
+        Device {
+            address: addr,
+            vendor_id: addr.read_u16(VENDOR_ID),
+            device_id: addr.read_u16(DEVICE_ID),
+            class: addr.read_u8(CLASS),
+            subclass: addr.read_u8(SUBCLASS),
+            prog_if: addr.read_u8(PROG_IF),
+            revision: addr.read_u8(REVISION),
+            header_type: header_type,
+            subsystem_vendor_id: subsystem_vendor_id,
+            subsystem_id: subsystem_id,
+            bars: read_bars(addr, bar_count),
+            capabilities: caps::read_capabilities(addr),
+            interrupt_pin: addr.read_u8(INTERRUPT_PIN),
+            interrupt_line: addr.read_u8(INTERRUPT_LINE),
+            bound: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HEADER_BRIDGE
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        self.capabilities.iter().filter_map(|cap| match *cap {
+            Capability::Msi(msi) => Some(msi),
+            _ => None,
+        }).next()
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        self.capabilities.iter().filter_map(|cap| match *cap {
+            Capability::MsiX(msix) => Some(msix),
+            _ => None,
+        }).next()
+    }
+
+    pub fn pcie(&self) -> Option<PciExpress> {
+        self.capabilities.iter().filter_map(|cap| match *cap {
+            Capability::PciExpress(pcie) => Some(pcie),
+            _ => None,
+        }).next()
+    }
+
+    pub fn command(&self) -> Command {
+        Command::from_bits_truncate(self.address.read_u16(COMMAND))
+    }
+
+    /* Turn on decoding or bus mastering, e.g. before using the BARs */
+    pub fn enable(&self, bits: Command) {
+        let command = self.address.read_u16(COMMAND);
+        self.address.write_u16(COMMAND, command | bits.bits());
+    }
+
+    pub fn disable(&self, bits: Command) {
+        let command = self.address.read_u16(COMMAND);
+        self.address.write_u16(COMMAND, command & !bits.bits());
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{:04x}:{:04x}] class {:02x}{:02x}{:02x}", self.address,
+               self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if)
+    }
+}
+
+/* What a driver handles */
+#[derive(Clone, Copy, Debug)]
+pub enum Match {
+    /* A vendor and device id */
+    Id { vendor: u16, device: u16 },
+    /* A class and subclass, and programming interface unless it is None */
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } =>
+                device.vendor_id == vendor && device.device_id == id,
+            Match::Class { class, subclass, prog_if } =>
+                device.class == class && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if),
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /*
+     * Take the device, which matched. On error the device is left for other
+     * drivers. Called in task context, it may sleep.
+     */
+    pub probe: fn(&'static Device) -> Result<(), Errno>,
+}
+
+/* Found at boot and never freed, so they can be handed out as 'static */
+static DEVICES: SpinLock<Vec<&'static Device>> = SpinLock::named("pci devices", Vec::new());
+static DRIVERS: SpinLock<Vec<&'static Driver>> = SpinLock::named("pci drivers", Vec::new());
+
+/* Probe `driver` with `device` if it matches and is still free */
+fn bind(device: &'static Device, driver: &'static Driver)
+{
+    if !driver.matches.iter().any(|m| m.matches(device)) {
+        return;
+    }
+    if device.bound.compare_and_swap(false, true, Ordering::AcqRel) {
+        return;
+    }
+    match (driver.probe)(device) {
+        Ok(()) => log!("{}: bound to {}", device.address, driver.name),
+        Err(error) => {
+            warn!("{}: {} failed to probe it: {:?}", device.address, driver.name, error);
+            device.bound.store(false, Ordering::Release);
+        }
+    }
+}
+
+/*
+ * Add `driver`, which is probed with the matching devices found so far and
+ * with the ones enumeration finds later.
+ */
+pub fn register_driver(driver: &'static Driver)
+{
+    DRIVERS.lock().push(driver);
+    for device in devices() {
+        bind(device, driver);
+    }
+}
+
+/* Every function found, bridges included */
+pub fn devices() -> Vec<&'static Device>
+{
+    DEVICES.lock().clone()
+}
+
+pub fn find(addr: Address) -> Option<&'static Device>
+{
+    DEVICES.lock().iter().cloned().find(|device| device.address == addr)
+}
+
+/* Buses already scanned, so that a misconfigured bridge can't make us loop */
+struct Scan {
+    found: Vec<Device>,
+    visited: Vec<(u16, u8)>,
+}
+
+impl Scan {
+    fn bus(&mut self, segment: u16, bus: u8) {
+        if self.visited.contains(&(segment, bus)) {
+            return;
+        }
+        self.visited.push((segment, bus));
+
+        for device in 0..32 {
+            let addr = Address::new(segment, bus, device, 0);
+            if addr.read_u16(VENDOR_ID) == NO_VENDOR {
+                continue;
+            }
+            let functions = if addr.read_u8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+            for function in 0..functions {
+                let addr = Address::new(segment, bus, device, function);
+                if addr.read_u16(VENDOR_ID) != NO_VENDOR {
+                    self.function(addr);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, addr: Address) {
+        let device = Device::read(addr);
+        log!("{}", device);
+        for (index, bar) in device.bars.iter().enumerate() {
+            match *bar {
+                Some(Bar::Memory { addr, size, prefetchable, is_64bit }) =>
+                    debug!("  BAR{}: memory at 0x{:x}, 0x{:x} bytes{}{}", index, addr, size,
+                           if is_64bit { ", 64-bit" } else { "" },
+                           if prefetchable { ", prefetchable" } else { "" }),
+                Some(Bar::Io { port, size }) =>
+                    debug!("  BAR{}: I/O ports 0x{:x}, 0x{:x} bytes", index, port, size),
+                None => {}
+            }
+        }
+
+        let secondary = if device.is_bridge() {
+            Some((addr.read_u8(SECONDARY_BUS), addr.read_u8(SUBORDINATE_BUS)))
+        } else {
+            None
+        };
+        self.found.push(device);
+
+        /* The firmware numbers the buses, behind a bridge they are higher */
+        if let Some((secondary, subordinate)) = secondary {
+            if secondary > addr.bus && subordinate >= secondary {
+                self.bus(addr.segment, secondary);
+            } else {
+                warn!("{}: bridge to bus {} not configured, not scanning behind it",
+                      addr, secondary);
+            }
+        }
+    }
+}
+
+fn is_host_bridge(addr: Address) -> bool
+{
+    addr.read_u16(VENDOR_ID) != NO_VENDOR
+        && addr.read_u8(CLASS) == CLASS_BRIDGE && addr.read_u8(SUBCLASS) == SUBCLASS_HOST_BRIDGE
+}
+
+/*
+ * Find every function and probe the drivers registered so far. A host
+ * bridge at 00.0 with more functions has one root bus per function, as on
+ * older chipsets with several host bridges.
+ */
+pub fn init()
+{
+    ::arch::pci::init();
+
+    let mut scan = Scan { found: Vec::new(), visited: Vec::new() };
+    for (segment, bus) in ::arch::pci::root_buses() {
+        scan.bus(segment, bus);
+
+        let host = Address::new(segment, bus, 0, 0);
+        if is_host_bridge(host) && host.read_u8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {
+            for function in 1..8 {
+                if is_host_bridge(Address::new(segment, bus, 0, function)) {
+                    scan.bus(segment, bus.wrapping_add(function));
+                }
+            }
+        }
+    }
+    log!("Found {} PCI functions", scan.found.len());
+
+    let found: Vec<&'static Device> = scan.found.into_iter()
+        .map(|device| unsafe { &*Box::into_raw(Box::new(device)) })
+        .collect();
+    DEVICES.lock().extend(found.iter().cloned());
+
+    let drivers = DRIVERS.lock().clone();
+    for device in found {
+        for driver in drivers.iter() {
+            bind(device, driver);
+        }
+    }
+}
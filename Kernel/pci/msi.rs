@@ -0,0 +1,374 @@
+/*
+ * Message signalled interrupts. Instead of asserting an INTx line routed
+ * through the interrupt controllers, the function writes a message to the
+ * LAPIC of a CPU, so a driver gets vectors of its own on the CPUs it
+ * chooses. MSI-X has a table in a BAR with an address, data and mask bit
+ * per vector. Plain MSI has one address for up to 32 consecutive vectors
+ * and a mask bit per vector only if the function implements them.
+ *
+ * The vectors come from arch::vectors. Each one is handed to the driver as
+ * an Irq, which it binds a handler to; dropping it masks and frees the
+ * vector. Unbinding and dropping wait for a running handler to return, so
+ * they sleep and mustn't be done from the handler or with a lock held.
+ */
+use core::ptr;
+use alloc::Vec;
+
+use errno::Errno;
+use mm::pmm;
+use mm::vmm;
+use sync::IrqSpinLock;
+use sync::rcu;
+use super::{Bar, Command, Device, Msi, MsiX};
+
+pub use arch::vectors::IrqHandler;
+
+/* MSI capability registers, the data and mask ones move up with a 64-bit address */
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_DATA_32: u16 = 0x08;
+const MSI_MASK_32: u16 = 0x0C;
+const MSI_DATA_64: u16 = 0x0C;
+const MSI_MASK_64: u16 = 0x10;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+/* How many vectors are enabled, as a power of two */
+const MSI_CONTROL_ENABLED_SHIFT: u16 = 4;
+const MSI_CONTROL_ENABLED_MASK: u16 = 0x7 << MSI_CONTROL_ENABLED_SHIFT;
+
+/* MSI-X capability registers */
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_CONTROL_MASK_ALL: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+/* An MSI-X table entry */
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/* The MSI mask register is shared by the vectors of a function */
+static MSI_MASK_LOCK: IrqSpinLock<()> = IrqSpinLock::named("msi mask", ());
+
+enum Kind {
+    /* Vector `index` of the `count` enabled */
+    Msi { cap: Msi, index: usize, count: usize },
+    /* Virtual address of the table entry */
+    MsiX { entry: usize },
+}
+
+/* A device interrupt vector, see bind */
+pub struct Irq {
+    device: &'static Device,
+    kind: Kind,
+    cpu: usize,
+    vector: u8,
+    handler: Option<(IrqHandler, usize)>,
+}
+
+fn msi_data_register(cap: &Msi) -> u16
+{
+    cap.offset + if cap.is_64bit { MSI_DATA_64 } else { MSI_DATA_32 }
+}
+
+fn msi_mask_register(cap: &Msi) -> u16
+{
+    cap.offset + if cap.is_64bit { MSI_MASK_64 } else { MSI_MASK_32 }
+}
+
+impl Irq {
+    /* The CPU the interrupt is delivered to */
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    pub fn device(&self) -> &'static Device {
+        self.device
+    }
+
+    /*
+     * Whether the vector can be masked on its own. MSI-X vectors always
+     * can, MSI ones only if the function implements per-vector masking.
+     */
+    pub fn can_mask(&self) -> bool {
+        match self.kind {
+            Kind::Msi { ref cap, .. } => cap.maskable,
+            Kind::MsiX { .. } => true,
+        }
+    }
+
+    fn set_masked(&self, masked: bool) -> Result<(), Errno> {
+        match self.kind {
+            Kind::Msi { ref cap, index, .. } => {
+                if !cap.maskable {
+                    return Err(Errno::ENOSYS);
+                }
+                let register = msi_mask_register(cap);
+                let _guard = MSI_MASK_LOCK.lock();
+                let bits = self.device.address.read_u32(register);
+                let bits = if masked { bits | 1 << index } else { bits & !(1 << index) };
+                self.device.address.write_u32(register, bits);
+            }
+            Kind::MsiX { entry } => unsafe {
+                let control = (entry + MSIX_ENTRY_CONTROL) as *mut u32;
+                let bits = ptr::read_volatile(control);
+                let bits = if masked { bits | MSIX_ENTRY_MASKED } else { bits & !MSIX_ENTRY_MASKED };
+                ptr::write_volatile(control, bits);
+            },
+        }
+        Ok(())
+    }
+
+    /*
+     * Stop the function from raising the interrupt. Messages it would have
+     * sent are held as pending and delivered on unmask. Safe to call from
+     * the handler itself.
+     */
+    pub fn mask(&self) -> Result<(), Errno> {
+        self.set_masked(true)
+    }
+
+    pub fn unmask(&self) -> Result<(), Errno> {
+        self.set_masked(false)
+    }
+
+    /* Point the function's message at our CPU and vector */
+    fn write_message(&self) {
+        let (address, data) = ::arch::vectors::msi_message(self.cpu, self.vector);
+        match self.kind {
+            Kind::Msi { ref cap, .. } => {
+                let addr = self.device.address;
+                addr.write_u32(cap.offset + MSI_ADDRESS, address as u32);
+                if cap.is_64bit {
+                    addr.write_u32(cap.offset + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+                }
+                addr.write_u16(msi_data_register(cap), data as u16);
+            }
+            Kind::MsiX { entry } => unsafe {
+                ptr::write_volatile((entry + MSIX_ENTRY_ADDRESS) as *mut u32, address as u32);
+                ptr::write_volatile((entry + MSIX_ENTRY_ADDRESS_HIGH) as *mut u32,
+                                    (address >> 32) as u32);
+                ptr::write_volatile((entry + MSIX_ENTRY_DATA) as *mut u32, data);
+            },
+        }
+    }
+
+    /*
+     * Run `handler` with `data` in interrupt context whenever the interrupt
+     * arrives, and unmask it. The handler runs with interrupts disabled,
+     * after the EOI.
+     */
+    pub fn bind(&mut self, handler: IrqHandler, data: usize) -> Result<(), Errno> {
+        if self.handler.is_some() || !::arch::vectors::bind(self.cpu, self.vector, handler, data) {
+            return Err(Errno::EBUSY);
+        }
+        self.handler = Some((handler, data));
+        let _ = self.unmask();
+        Ok(())
+    }
+
+    /*
+     * Mask the interrupt and remove its handler. Once this returns the
+     * handler isn't running anywhere and its data can be freed.
+     */
+    pub fn unbind(&mut self) {
+        let _ = self.mask();
+        ::arch::vectors::unbind(self.cpu, self.vector);
+        if self.handler.take().is_some() {
+            rcu::synchronize_rcu();
+        }
+    }
+
+    /*
+     * Deliver the interrupt to `cpu` from now on, on a vector allocated
+     * there. The vectors of multiple message MSI share an address and
+     * can't be moved one by one. Without a mask an interrupt raised while
+     * the message is rewritten may go to either CPU.
+     */
+    pub fn set_cpu(&mut self, cpu: usize) -> Result<(), Errno> {
+        if let Kind::Msi { count, .. } = self.kind {
+            if count > 1 {
+                return Err(Errno::EINVAL);
+            }
+        }
+        if cpu == self.cpu {
+            return Ok(());
+        }
+        let (cpu, vector) = ::arch::vectors::allocate(Some(cpu), 1).ok_or(Errno::ENOSPC)?;
+        if let Some((handler, data)) = self.handler {
+            ::arch::vectors::bind(cpu, vector, handler, data);
+        }
+
+        let masked = self.mask().is_ok();
+        let (old_cpu, old_vector) = (self.cpu, self.vector);
+        self.cpu = cpu;
+        self.vector = vector;
+        self.write_message();
+        if masked && self.handler.is_some() {
+            let _ = self.unmask();
+        }
+
+        ::arch::vectors::free(old_cpu, old_vector);
+        Ok(())
+    }
+}
+
+impl Drop for Irq {
+    fn drop(&mut self) {
+        let _ = self.mask();
+        ::arch::vectors::free(self.cpu, self.vector);
+        if self.handler.is_some() {
+            rcu::synchronize_rcu();
+        }
+    }
+}
+
+fn msi_enabled(device: &Device) -> bool
+{
+    device.msi().map_or(false, |cap| {
+        device.address.read_u16(cap.offset + MSI_CONTROL) & MSI_CONTROL_ENABLE != 0
+    })
+}
+
+fn msix_enabled(device: &Device) -> bool
+{
+    device.msix().map_or(false, |cap| {
+        device.address.read_u16(cap.offset + MSIX_CONTROL) & MSIX_CONTROL_ENABLE != 0
+    })
+}
+
+/*
+ * Enable `count` MSI vectors, a power of two up to what the function
+ * supports, on `cpu` or on the least loaded CPU. They are masked until
+ * bound, if the function can mask them.
+ */
+pub fn enable_msi(device: &'static Device, count: usize, cpu: Option<usize>)
+                  -> Result<Vec<Irq>, Errno>
+{
+    let cap = device.msi().ok_or(Errno::ENODEV)?;
+    if !count.is_power_of_two() || count > cap.max_vectors as usize {
+        return Err(Errno::EINVAL);
+    }
+    if msi_enabled(device) || msix_enabled(device) {
+        return Err(Errno::EBUSY);
+    }
+    let (cpu, first) = ::arch::vectors::allocate(cpu, count).ok_or(Errno::ENOSPC)?;
+    let irqs: Vec<Irq> = (0..count).map(|index| Irq {
+        device: device,
+        kind: Kind::Msi { cap: cap, index: index, count: count },
+        cpu: cpu,
+        vector: first + index as u8,
+        handler: None,
+    }).collect();
+
+    let addr = device.address;
+    let control = addr.read_u16(cap.offset + MSI_CONTROL);
+    if cap.maskable {
+        addr.write_u32(msi_mask_register(&cap), !0u32 >> (32 - count));
+    }
+    /* The function puts the vector number in the low bits of the data */
+    irqs[0].write_message();
+    let enabled = (count.trailing_zeros() as u16) << MSI_CONTROL_ENABLED_SHIFT;
+    addr.write_u16(cap.offset + MSI_CONTROL,
+                   (control & !MSI_CONTROL_ENABLED_MASK) | enabled | MSI_CONTROL_ENABLE);
+    device.enable(Command::BUS_MASTER | Command::INTX_DISABLE);
+
+    log!("{}: {} MSI vectors from 0x{:x} on CPU {}", addr, count, first, cpu);
+    Ok(irqs)
+}
+
+/* Map the MSI-X table, which is somewhere in one of the memory BARs */
+fn map_msix_table(device: &Device, cap: &MsiX) -> Result<usize, Errno>
+{
+    let base = match device.bars.get(cap.table_bar as usize) {
+        Some(&Some(Bar::Memory { addr, .. })) => addr,
+        _ => return Err(Errno::ENODEV),
+    };
+    let table = base + cap.table_offset as u64;
+    let size = cap.table_size as usize * MSIX_ENTRY_SIZE;
+    /* Without PAE an i686 kernel can't map anything above 4GiB */
+    if table + size as u64 - 1 > usize::max_value() as u64 {
+        return Err(Errno::ENODEV);
+    }
+    device.enable(Command::MEMORY_SPACE);
+    Ok(pmm::with_frames(|fma| vmm::map_io(fma, table as usize, size)))
+}
+
+/*
+ * Enable the first `count` MSI-X vectors, each on `cpu` or on whichever
+ * CPU is least loaded at the time. They are masked until bound.
+ */
+pub fn enable_msix(device: &'static Device, count: usize, cpu: Option<usize>)
+                   -> Result<Vec<Irq>, Errno>
+{
+    let cap = device.msix().ok_or(Errno::ENODEV)?;
+    if count == 0 || count > cap.table_size as usize {
+        return Err(Errno::EINVAL);
+    }
+    if msi_enabled(device) || msix_enabled(device) {
+        return Err(Errno::EBUSY);
+    }
+    let table = map_msix_table(device, &cap)?;
+
+    /* Vectors dropped on failure are masked, which the entries may as well be */
+    let mut irqs = Vec::with_capacity(count);
+    for index in 0..count {
+        let (cpu, vector) = ::arch::vectors::allocate(cpu, 1).ok_or(Errno::ENOSPC)?;
+        irqs.push(Irq {
+            device: device,
+            kind: Kind::MsiX { entry: table + index * MSIX_ENTRY_SIZE },
+            cpu: cpu,
+            vector: vector,
+            handler: None,
+        });
+    }
+
+    /* Entries can't be written with MSI-X off, so mask the whole function meanwhile */
+    let addr = device.address;
+    let control = addr.read_u16(cap.offset + MSIX_CONTROL);
+    addr.write_u16(cap.offset + MSIX_CONTROL, control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK_ALL);
+    for irq in irqs.iter() {
+        let _ = irq.mask();
+        irq.write_message();
+    }
+    addr.write_u16(cap.offset + MSIX_CONTROL, (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_MASK_ALL);
+    device.enable(Command::BUS_MASTER | Command::INTX_DISABLE);
+
+    log!("{}: {} MSI-X vectors", addr, count);
+    Ok(irqs)
+}
+
+/*
+ * Enable `count` vectors with MSI-X if the function has it, MSI otherwise,
+ * for drivers that don't care which.
+ */
+pub fn enable(device: &'static Device, count: usize, cpu: Option<usize>)
+              -> Result<Vec<Irq>, Errno>
+{
+    if device.msix().is_some() {
+        enable_msix(device, count, cpu)
+    } else {
+        enable_msi(device, count, cpu)
+    }
+}
+
+/* Turn MSI and MSI-X off again, once the driver has dropped its Irqs */
+pub fn disable(device: &Device)
+{
+    let addr = device.address;
+    if let Some(cap) = device.msi() {
+        let control = addr.read_u16(cap.offset + MSI_CONTROL);
+        addr.write_u16(cap.offset + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+    }
+    if let Some(cap) = device.msix() {
+        let control = addr.read_u16(cap.offset + MSIX_CONTROL);
+        addr.write_u16(cap.offset + MSIX_CONTROL, control & !MSIX_CONTROL_ENABLE);
+    }
+}
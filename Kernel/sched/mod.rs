@@ -0,0 +1,604 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::VecDeque;
+
+use arch::context;
+use arch::{InterruptFrame, RESCHED_VECTOR};
+use arch::irq;
+use arch::percpu::{self, PerCpu};
+use sync::IrqSpinLock;
+
+pub mod task;
+pub mod wait;
+
+use self::task::{Task, TaskId, TASK_DEAD, TASK_RUNNABLE, TASK_RUNNING, TASK_SLEEPING};
+use self::task::{NR_PRIORITIES, PRIORITY_NORMAL, AFFINITY_ALL};
+
+/* Number of ticks a task may run before it is preempted */
+const TIMESLICE_TICKS: usize = 5;
+
+/* How often a busy CPU looks for idle CPUs to hand work to */
+const BALANCE_TICKS: u64 = 10;
+
+/* The affinity masks are a u64 */
+const MAX_CPUS: usize = 64;
+
+/* The runnable tasks of a CPU, one FIFO per priority level */
+struct RunQueue {
+    queues: [VecDeque<*mut Task>; NR_PRIORITIES],
+}
+
+unsafe impl Send for RunQueue {}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    fn push(&mut self, task: *mut Task) {
+        let priority = unsafe { (*task).priority };
+        self.queues[priority].push_back(task);
+    }
+
+    /* Take the most important task with at most `limit` priority */
+    fn pop(&mut self, limit: usize) -> Option<*mut Task> {
+        for priority in 0..(limit + 1) {
+            if let Some(task) = self.queues[priority].pop_front() {
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    /*
+     * Take a task that may run on `cpu`, for work stealing. Takes the most
+     * important one, from the back of its queue, as that is the one whose
+     * cache footprint on the victim is the coldest.
+     */
+    fn steal(&mut self, cpu: usize) -> Option<*mut Task> {
+        for queue in self.queues.iter_mut() {
+            let found = queue.iter().rposition(|&task| unsafe { (*task).may_run_on(cpu) });
+            if let Some(idx) = found {
+                return queue.remove(idx);
+            }
+        }
+        None
+    }
+}
+
+/* Scheduler state only ever touched by its own CPU, with interrupts disabled */
+struct CpuSched {
+    /* The idle task, run when there's nothing else to do */
+    idle: *mut Task,
+    /* The task we have just switched away from, see finish_switch */
+    prev: *mut Task,
+    ticks_left: usize,
+    need_resched: bool,
+    preempt_count: usize,
+    /* When the idle task was last switched to */
+    idle_since: u64,
+}
+
+/* The tasks are only ever touched by the CPU that owns the slot */
+unsafe impl Send for CpuSched {}
+
+/* Scheduler state of a CPU that the other CPUs look at */
+struct CpuShared {
+    /* Touched from the timer interrupt and by other CPUs */
+    run_queue: IrqSpinLock<RunQueue>,
+    /* Number of tasks in run_queue */
+    nr_running: AtomicUsize,
+    /* Priority of the running task, NR_PRIORITIES when idle */
+    current_priority: AtomicUsize,
+    context_switches: AtomicU64,
+    idle_ns: AtomicU64,
+}
+
+/* Scheduler counters of a CPU */
+#[derive(Clone, Copy, Debug)]
+pub struct CpuStats {
+    pub context_switches: u64,
+    pub idle_ns: u64,
+    pub nr_running: usize,
+}
+
+static CPUS: PerCpu<CpuSched> = PerCpu::new();
+static SHARED: PerCpu<CpuShared> = PerCpu::new();
+
+/* Set up the scheduler, must be called before the APs are started */
+pub fn init()
+{
+    assert!(::arch::cpu_count() <= MAX_CPUS, "at most {} CPUs are supported", MAX_CPUS);
+    unsafe {
+        CPUS.init(|_| CpuSched {
+            idle: ptr::null_mut(),
+            prev: ptr::null_mut(),
+            ticks_left: TIMESLICE_TICKS,
+            need_resched: false,
+            preempt_count: 0,
+            idle_since: 0,
+        });
+        SHARED.init(|_| CpuShared {
+            run_queue: IrqSpinLock::named("run queue", RunQueue::new()),
+            nr_running: AtomicUsize::new(0),
+            current_priority: AtomicUsize::new(NR_PRIORITIES),
+            context_switches: AtomicU64::new(0),
+            idle_ns: AtomicU64::new(0),
+        });
+    }
+    ::arch::register_handler(RESCHED_VECTOR, resched_interrupt);
+}
+
+/* The task running on this CPU */
+pub fn current() -> *mut Task
+{
+    percpu::current_task() as *mut Task
+}
+
+pub fn current_id() -> TaskId
+{
+    unsafe { (*current()).id }
+}
+
+/* Pick the CPU a task that becomes runnable should be queued on */
+fn select_cpu(task: *mut Task) -> usize
+{
+    let last = unsafe { (*task).cpu };
+    let mut best: Option<(usize, usize)> = None;
+
+    for cpu in 0..SHARED.len() {
+        if !unsafe { (*task).may_run_on(cpu) } {
+            continue;
+        }
+        let shared = SHARED.on_cpu(cpu);
+        let mut load = shared.nr_running.load(Ordering::Relaxed);
+        if shared.current_priority.load(Ordering::Relaxed) < NR_PRIORITIES {
+            load += 1;
+        }
+        /* Stay where the cache is warm, unless somewhere is less loaded */
+        let better = match best {
+            None => true,
+            Some((_, best_load)) => load < best_load || (load == best_load && cpu == last),
+        };
+        if better {
+            best = Some((cpu, load));
+        }
+    }
+
+    match best {
+        Some((cpu, _)) => cpu,
+        None => panic!("task {} has an empty affinity mask", unsafe { (*task).id }),
+    }
+}
+
+/*
+ * Queue `task` on `cpu` and, if it should run before whatever `cpu` is
+ * running now, get that CPU to reschedule.
+ */
+fn enqueue_on(cpu: usize, task: *mut Task)
+{
+    let priority = unsafe {
+        (*task).cpu = cpu;
+        (*task).priority
+    };
+    let shared = SHARED.on_cpu(cpu);
+    shared.run_queue.lock().push(task);
+    shared.nr_running.fetch_add(1, Ordering::Relaxed);
+
+    if priority < shared.current_priority.load(Ordering::Relaxed) {
+        if cpu == percpu::cpu_id() {
+            let flags = irq::save_and_disable();
+            unsafe { CPUS.this_cpu_mut().need_resched = true; }
+            irq::restore(flags);
+        } else {
+            ::arch::send_ipi(cpu, RESCHED_VECTOR);
+        }
+    }
+}
+
+fn enqueue(task: *mut Task)
+{
+    enqueue_on(select_cpu(task), task);
+}
+
+/* Create a kernel thread running `entry` and queue it */
+pub fn spawn(name: &'static str, entry: fn()) -> TaskId
+{
+    spawn_with(name, entry, PRIORITY_NORMAL, AFFINITY_ALL)
+}
+
+/* Create a kernel thread with the given priority and CPU affinity mask */
+pub fn spawn_with(name: &'static str, entry: fn(), priority: usize, affinity: u64) -> TaskId
+{
+    assert!(priority < NR_PRIORITIES, "invalid priority {}", priority);
+    let mut task = Task::new(name, entry, task_start);
+    task.priority = priority;
+    task.affinity = affinity;
+    let task = Box::into_raw(task);
+    let id = unsafe { (*task).id };
+    enqueue(task);
+    id
+}
+
+/* Where new tasks start, on their own stack */
+extern "C" fn task_start() -> !
+{
+    /* We may have been switched to from an interrupt handler */
+    percpu::set_irq_depth(0);
+    finish_switch();
+    unsafe { irq::enable(); }
+
+    let entry = unsafe { (*current()).entry.unwrap() };
+    entry();
+    exit();
+}
+
+/* Take the next task off this CPU's queue */
+fn pop_local(limit: usize) -> Option<*mut Task>
+{
+    let shared = SHARED.this_cpu();
+    let task = shared.run_queue.lock().pop(limit);
+    if task.is_some() {
+        shared.nr_running.fetch_sub(1, Ordering::Relaxed);
+    }
+    task
+}
+
+/* Steal a task from the busiest CPU that has one we may run */
+fn steal() -> Option<*mut Task>
+{
+    let this = percpu::cpu_id();
+    let count = SHARED.len();
+
+    let mut victims: [(usize, usize); MAX_CPUS] = [(0, 0); MAX_CPUS];
+    let mut nr_victims = 0;
+    for i in 1..count {
+        let cpu = (this + i) % count;
+        let load = SHARED.on_cpu(cpu).nr_running.load(Ordering::Relaxed);
+        if load > 0 {
+            victims[nr_victims] = (load, cpu);
+            nr_victims += 1;
+        }
+    }
+    victims[..nr_victims].sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    for &(_, cpu) in victims[..nr_victims].iter() {
+        let shared = SHARED.on_cpu(cpu);
+        if let Some(task) = shared.run_queue.lock().steal(this) {
+            shared.nr_running.fetch_sub(1, Ordering::Relaxed);
+            unsafe { (*task).cpu = this; }
+            return Some(task);
+        }
+    }
+    None
+}
+
+/*
+ * Pick the next task to run and switch to it. If the current task is
+ * still running and nobody more important wants the CPU, this returns
+ * right away.
+ *
+ * Interrupts must be disabled.
+ */
+unsafe fn schedule()
+{
+    let cpu = CPUS.this_cpu_mut();
+    let shared = SHARED.this_cpu();
+    let prev = current();
+    cpu.need_resched = false;
+
+    let prev_running = prev != cpu.idle && (*prev).state() == TASK_RUNNING
+        && (*prev).may_run_on(percpu::cpu_id());
+
+    /* A running task only gives way to tasks at least as important */
+    let limit = if prev_running { (*prev).priority } else { NR_PRIORITIES - 1 };
+    let mut next = pop_local(limit);
+    if next.is_none() && !prev_running {
+        next = steal();
+    }
+    let next = match next {
+        Some(next) => next,
+        None if prev_running => return,
+        None => cpu.idle,
+    };
+    if next == prev {
+        /* Woken up before we got to switch away */
+        (*prev).set_state(TASK_RUNNING);
+        return;
+    }
+
+    /* Wait for the CPU that last ran `next` to be done switching away */
+    while (*next).on_cpu.load(Ordering::Acquire) {
+        /* Do nothing */
+    }
+    (*next).on_cpu.store(true, Ordering::Relaxed);
+    (*next).set_state(TASK_RUNNING);
+
+    /* Idle time accounting */
+    let now = ::time::now();
+    if prev == cpu.idle {
+        shared.idle_ns.fetch_add(now - cpu.idle_since, Ordering::Relaxed);
+    }
+    if next == cpu.idle {
+        cpu.idle_since = now;
+        shared.current_priority.store(NR_PRIORITIES, Ordering::Relaxed);
+    } else {
+        shared.current_priority.store((*next).priority, Ordering::Relaxed);
+    }
+    shared.context_switches.fetch_add(1, Ordering::Relaxed);
+
+    cpu.prev = prev;
+    cpu.ticks_left = TIMESLICE_TICKS;
+    percpu::set_current_task(next as usize);
+    if (*next).stack_top != 0 {
+        context::set_kernel_stack((*next).stack_top);
+    }
+    ::mm::vmm::switch_to((*next).address_space.as_ref());
+
+    context::save_fpu(&mut (*prev).fpu);
+    let irq_depth = percpu::irq_depth();
+    context::switch_context(&mut (*prev).rsp, (*next).rsp);
+
+    /* Back on `prev`, possibly on another CPU */
+    percpu::set_irq_depth(irq_depth);
+    finish_switch();
+}
+
+/*
+ * Complete a switch on the new task's stack: restore its FPU state and
+ * requeue or reap the task we came from, now that nothing uses its stack.
+ */
+fn finish_switch()
+{
+    unsafe {
+        let cpu = CPUS.this_cpu_mut();
+        let current = current();
+
+        context::restore_fpu(&(*current).fpu);
+
+        let prev = cpu.prev;
+        cpu.prev = ptr::null_mut();
+        if prev.is_null() {
+            return;
+        }
+
+        let state = (*prev).state();
+        (*prev).on_cpu.store(false, Ordering::Release);
+        if state == TASK_RUNNING && prev != cpu.idle {
+            /* Preempted or yielded, keep it here if its affinity allows */
+            (*prev).set_state(TASK_RUNNABLE);
+            let this = percpu::cpu_id();
+            if (*prev).may_run_on(this) {
+                enqueue_on(this, prev);
+            } else {
+                enqueue(prev);
+            }
+        } else if state == TASK_DEAD {
+            drop(Box::from_raw(prev));
+        }
+    }
+}
+
+/* Give up the CPU to another runnable task, if there is one */
+pub fn yield_now()
+{
+    let flags = irq::save_and_disable();
+    unsafe {
+        /* Let tasks of the same priority have a go */
+        CPUS.this_cpu_mut().need_resched = true;
+        schedule();
+    }
+    irq::restore(flags);
+}
+
+/* Wake up `task` if it's sleeping */
+pub fn wake(task: *mut Task)
+{
+    let woken = unsafe {
+        (*task).state.compare_and_swap(TASK_SLEEPING, TASK_RUNNABLE, Ordering::AcqRel)
+    };
+    if woken == TASK_SLEEPING {
+        enqueue(task);
+    }
+}
+
+/* Sleep for at least `ns` nanoseconds */
+pub fn sleep(ns: u64)
+{
+    let flags = irq::save_and_disable();
+    let task = current();
+    unsafe { (*task).set_state(TASK_SLEEPING); }
+
+    let waker = task as usize;
+    ::timer::schedule_after(ns, Box::new(move || wake(waker as *mut Task)));
+
+    unsafe { schedule(); }
+    irq::restore(flags);
+}
+
+/* Terminate the current task */
+pub fn exit() -> !
+{
+    irq::disable();
+    unsafe {
+        /* Free the user space here, the task may be reaped in an interrupt */
+        let space = (*current()).address_space.take();
+        ::mm::vmm::switch_to(None);
+        drop(space);
+        (*current()).set_state(TASK_DEAD);
+        schedule();
+    }
+    unreachable!("dead task was scheduled again");
+}
+
+/* Change the priority of the current task */
+pub fn set_priority(priority: usize)
+{
+    assert!(priority < NR_PRIORITIES, "invalid priority {}", priority);
+    let flags = irq::save_and_disable();
+    unsafe {
+        (*current()).priority = priority;
+        SHARED.this_cpu().current_priority.store(priority, Ordering::Relaxed);
+        CPUS.this_cpu_mut().need_resched = true;
+        schedule();
+    }
+    irq::restore(flags);
+}
+
+/*
+ * Restrict the current task to the CPUs in `affinity`, migrating it
+ * right away if it's running on a CPU outside the mask.
+ */
+pub fn set_affinity(affinity: u64)
+{
+    let flags = irq::save_and_disable();
+    unsafe {
+        (*current()).affinity = affinity;
+        if !(*current()).may_run_on(percpu::cpu_id()) {
+            schedule();
+        }
+    }
+    irq::restore(flags);
+}
+
+/* Keep the current task from being preempted, nests */
+pub fn preempt_disable()
+{
+    let flags = irq::save_and_disable();
+    if CPUS.is_initialized() {
+        unsafe { CPUS.this_cpu_mut().preempt_count += 1; }
+    }
+    irq::restore(flags);
+}
+
+/*
+ * Let the current task be preempted again, and switch away right now if a
+ * reschedule was asked for meanwhile. Interrupt handlers and code running
+ * with interrupts disabled leave that to whoever enables them.
+ */
+pub fn preempt_enable()
+{
+    let irqs_enabled = irq::enabled();
+    let flags = irq::save_and_disable();
+    if CPUS.is_initialized() {
+        unsafe {
+            let cpu = CPUS.this_cpu_mut();
+            cpu.preempt_count -= 1;
+            if cpu.preempt_count == 0 && cpu.need_resched && irqs_enabled
+                && !percpu::in_interrupt() && percpu::current_task() != 0 {
+                schedule();
+            }
+        }
+    }
+    irq::restore(flags);
+}
+
+/* Kick an idle CPU if this one has more work than it can do */
+fn balance()
+{
+    let this = percpu::cpu_id();
+    if SHARED.this_cpu().nr_running.load(Ordering::Relaxed) < 2 {
+        return;
+    }
+    for cpu in 0..SHARED.len() {
+        if cpu == this {
+            continue;
+        }
+        let shared = SHARED.on_cpu(cpu);
+        if shared.current_priority.load(Ordering::Relaxed) == NR_PRIORITIES
+            && shared.nr_running.load(Ordering::Relaxed) == 0 {
+            /* It will steal from us when it reschedules */
+            ::arch::send_ipi(cpu, RESCHED_VECTOR);
+            return;
+        }
+    }
+}
+
+/*
+ * Called from the timer interrupt on every CPU, with interrupts disabled.
+ * Switches away from the current task once its time slice is used up.
+ */
+pub fn tick()
+{
+    if percpu::current_task() == 0 {
+        /* This CPU hasn't entered the scheduler yet */
+        return;
+    }
+
+    if ::timer::ticks() % BALANCE_TICKS == 0 {
+        balance();
+    }
+
+    /* Code running with preemption enabled can't be in an RCU read section */
+    let preemptible = unsafe { CPUS.this_cpu_mut().preempt_count == 0 };
+    ::sync::rcu::tick(preemptible);
+
+    unsafe {
+        let cpu = CPUS.this_cpu_mut();
+        if cpu.ticks_left > 0 {
+            cpu.ticks_left -= 1;
+        }
+        if cpu.ticks_left == 0 || current() == cpu.idle {
+            cpu.need_resched = true;
+        }
+        if cpu.need_resched && cpu.preempt_count == 0 {
+            schedule();
+        }
+    }
+}
+
+/* Sent by other CPUs when they have queued work for us, or want us to steal */
+fn resched_interrupt(_frame: &mut InterruptFrame)
+{
+    if percpu::current_task() == 0 {
+        return;
+    }
+    unsafe {
+        let cpu = CPUS.this_cpu_mut();
+        cpu.need_resched = true;
+        if cpu.preempt_count == 0 {
+            schedule();
+        }
+    }
+}
+
+/* The scheduler counters of CPU `cpu` */
+pub fn cpu_stats(cpu: usize) -> CpuStats
+{
+    let shared = SHARED.on_cpu(cpu);
+    CpuStats {
+        context_switches: shared.context_switches.load(Ordering::Relaxed),
+        idle_ns: shared.idle_ns.load(Ordering::Relaxed),
+        nr_running: shared.nr_running.load(Ordering::Relaxed),
+    }
+}
+
+/*
+ * Turn the code running on this CPU into its idle task and start running
+ * other tasks.
+ */
+pub fn start() -> !
+{
+    irq::disable();
+    let idle = Box::into_raw(Task::bootstrap("idle"));
+    unsafe {
+        let cpu = CPUS.this_cpu_mut();
+        cpu.idle = idle;
+        cpu.idle_since = ::time::now();
+    }
+    percpu::set_current_task(idle as usize);
+    ::sync::rcu::cpu_online();
+    ::smp::cpu_online();
+    log!("CPU {} entering the scheduler", percpu::cpu_id());
+
+    loop {
+        unsafe {
+            schedule();
+            irq::enable_and_halt();
+        }
+        irq::disable();
+    }
+}
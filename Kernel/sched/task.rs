@@ -0,0 +1,116 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::Vec;
+
+use arch::context::{self, FpuState};
+use mm::vmm::AddressSpace;
+#[cfg(feature = "lockdep")]
+use sync::lockdep::HeldLocks;
+
+/* Size of the kernel stack of every task */
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+pub type TaskId = usize;
+
+/* Task states, kept in an AtomicUsize so that wakers on other CPUs can CAS them */
+pub const TASK_RUNNING: usize = 0;
+pub const TASK_RUNNABLE: usize = 1;
+pub const TASK_SLEEPING: usize = 2;
+pub const TASK_DEAD: usize = 3;
+
+/* Priority levels, lower is more important */
+pub const NR_PRIORITIES: usize = 4;
+pub const PRIORITY_HIGH: usize = 0;
+pub const PRIORITY_NORMAL: usize = 1;
+pub const PRIORITY_LOW: usize = 2;
+pub const PRIORITY_BATCH: usize = 3;
+
+/* A CPU affinity mask with every CPU set */
+pub const AFFINITY_ALL: u64 = !0;
+
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Task {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: AtomicUsize,
+    /* Set while a CPU is running on (or still switching away from) this task */
+    pub on_cpu: AtomicBool,
+    /* Saved stack pointer while switched out */
+    pub rsp: usize,
+    pub stack_top: usize,
+    stack: Option<Vec<u8>>,
+    /* Saved on every switch away, new tasks start with the reset state */
+    pub fpu: FpuState,
+    pub entry: Option<fn()>,
+    pub priority: usize,
+    /* Bit n is set if the task may run on CPU n */
+    pub affinity: u64,
+    /* The CPU whose run queue the task was last put on */
+    pub cpu: usize,
+    /* The user address space, kernel threads have none */
+    pub address_space: Option<AddressSpace>,
+    #[cfg(feature = "lockdep")]
+    pub held_locks: HeldLocks,
+}
+
+impl Task {
+    /* A new task that will start executing `entry` through `start` */
+    pub fn new(name: &'static str, entry: fn(), start: extern "C" fn() -> !) -> Box<Task> {
+        let stack = vec![0u8; KERNEL_STACK_SIZE];
+        let stack_top = stack.as_ptr() as usize + KERNEL_STACK_SIZE;
+        Box::new(Task {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+            name: name,
+            state: AtomicUsize::new(TASK_RUNNABLE),
+            on_cpu: AtomicBool::new(false),
+            rsp: context::init_stack(stack_top, start),
+            stack_top: stack_top,
+            stack: Some(stack),
+            fpu: FpuState::new(),
+            entry: Some(entry),
+            priority: PRIORITY_NORMAL,
+            affinity: AFFINITY_ALL,
+            cpu: 0,
+            address_space: None,
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
+        })
+    }
+
+    /*
+     * A task for the context that is already executing on this CPU, on
+     * whatever stack it has been given by the boot code.
+     */
+    pub fn bootstrap(name: &'static str) -> Box<Task> {
+        Box::new(Task {
+            id: NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed),
+            name: name,
+            state: AtomicUsize::new(TASK_RUNNING),
+            on_cpu: AtomicBool::new(true),
+            rsp: 0,
+            stack_top: 0,
+            stack: None,
+            fpu: FpuState::new(),
+            entry: None,
+            priority: PRIORITY_NORMAL,
+            affinity: AFFINITY_ALL,
+            cpu: ::arch::percpu::cpu_id(),
+            address_space: None,
+            #[cfg(feature = "lockdep")]
+            held_locks: HeldLocks::new(),
+        })
+    }
+
+    pub fn state(&self) -> usize {
+        self.state.load(Ordering::Acquire)
+    }
+
+    pub fn set_state(&self, state: usize) {
+        self.state.store(state, Ordering::Release);
+    }
+
+    pub fn may_run_on(&self, cpu: usize) -> bool {
+        cpu < 64 && self.affinity & (1 << cpu) != 0
+    }
+}
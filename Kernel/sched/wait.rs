@@ -0,0 +1,95 @@
+use core::mem;
+use alloc::Vec;
+
+use arch::irq;
+use sync::IrqSpinLock;
+use super::task::{Task, TASK_SLEEPING};
+use super::{current, schedule, wake};
+
+/*
+ * A queue of tasks sleeping until some condition becomes true.
+ *
+ * The condition is always checked under the queue lock, and wakers change
+ * it before taking the same lock, so a wakeup can't slip in between a
+ * waiter checking the condition and going to sleep. Wakers may run in
+ * interrupt context and on any CPU.
+ */
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Vec<*mut Task>>,
+}
+
+unsafe impl Send for WaitQueue {}
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: IrqSpinLock::new(Vec::new()) }
+    }
+
+    /*
+     * Sleep until `condition` returns true. It is called with the queue
+     * locked and interrupts disabled, so it must not block.
+     */
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        loop {
+            let flags = irq::save_and_disable();
+            let task = current();
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    drop(waiters);
+                    irq::restore(flags);
+                    return;
+                }
+                unsafe { (*task).set_state(TASK_SLEEPING); }
+                waiters.push(task);
+            }
+
+            unsafe { schedule(); }
+
+            /* Someone else may have woken us, don't leave a stale entry */
+            self.waiters.lock().retain(|&t| t != task);
+            irq::restore(flags);
+        }
+    }
+
+    /* Wake the task that has been waiting longest, returns false if none */
+    pub fn wake_one(&self) -> bool {
+        self.wake_one_after(|| {})
+    }
+
+    /* Wake every waiting task, returns how many there were */
+    pub fn wake_all(&self) -> usize {
+        self.wake_all_after(|| {})
+    }
+
+    /*
+     * Run `update` and wake a waiter, all with the queue locked. Waiters
+     * can't see the condition change until the queue is left alone, so
+     * they may free it right away, e.g. when it lives on their stack.
+     */
+    pub fn wake_one_after<F: FnOnce()>(&self, update: F) -> bool {
+        let mut waiters = self.waiters.lock();
+        update();
+        if waiters.is_empty() {
+            return false;
+        }
+        wake(waiters.remove(0));
+        true
+    }
+
+    pub fn wake_all_after<F: FnOnce()>(&self, update: F) -> usize {
+        let mut waiters = self.waiters.lock();
+        update();
+        /* Tasks that go back to sleep right away are left for next time */
+        let woken = mem::replace(&mut *waiters, Vec::new());
+        for &task in woken.iter() {
+            wake(task);
+        }
+        woken.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
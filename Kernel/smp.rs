@@ -0,0 +1,154 @@
+/*
+ * Running functions on other CPUs, e.g. to flush their TLBs or read their
+ * MSRs. The caller queues the function on every target CPU and sends them
+ * an IPI, the targets run it from the interrupt handler and count down
+ * the number of CPUs that still have to run it.
+ */
+use core::sync::atomic::{spin_loop_hint, AtomicU64, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::Vec;
+
+use arch::{InterruptFrame, CALL_FUNCTION_VECTOR};
+use arch::irq;
+use arch::percpu::{self, PerCpu};
+use sync::IrqSpinLock;
+
+struct CallData {
+    func: Box<Fn() + Send + Sync>,
+    /* Number of CPUs that still have to run `func` */
+    remaining: AtomicUsize,
+    /* Whether the caller waits for the call, and frees it afterwards */
+    wait: bool,
+}
+
+/* The pending calls of every CPU, pointers to CallData */
+static QUEUES: PerCpu<IrqSpinLock<Vec<usize>>> = PerCpu::new();
+
+/* CPUs that handle calls, i.e. that have interrupts enabled */
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/* Set up the call queues, must be called before the APs are started */
+pub fn init()
+{
+    unsafe {
+        QUEUES.init(|_| IrqSpinLock::named("smp call queue", Vec::new()));
+    }
+    ::arch::register_handler(CALL_FUNCTION_VECTOR, call_interrupt);
+}
+
+/* Start accepting calls on the current CPU */
+pub fn cpu_online()
+{
+    ONLINE.fetch_or(1 << percpu::cpu_id(), Ordering::SeqCst);
+}
+
+pub fn is_online(cpu: usize) -> bool
+{
+    cpu < 64 && ONLINE.load(Ordering::SeqCst) & (1 << cpu) != 0
+}
+
+/* Run a queued call, the last CPU to run a call nobody waits for frees it */
+unsafe fn run(data: *const CallData)
+{
+    let wait = (*data).wait;
+    ((*data).func)();
+    if (*data).remaining.fetch_sub(1, Ordering::AcqRel) == 1 && !wait {
+        drop(Box::from_raw(data as *mut CallData));
+    }
+}
+
+fn call_interrupt(_frame: &mut InterruptFrame)
+{
+    /* Calls may queue more calls, so don't hold on to the queue */
+    loop {
+        let data = {
+            let mut queue = QUEUES.this_cpu().lock();
+            if queue.is_empty() {
+                break;
+            }
+            queue.remove(0)
+        };
+        unsafe { run(data as *const CallData); }
+    }
+}
+
+fn queue(cpu: usize, data: *const CallData)
+{
+    QUEUES.on_cpu(cpu).lock().push(data as usize);
+    ::arch::send_ipi(cpu, CALL_FUNCTION_VECTOR);
+}
+
+/*
+ * Waiting with interrupts disabled would deadlock against a CPU that is
+ * waiting for us in turn.
+ */
+fn wait_for(data: *mut CallData)
+{
+    unsafe {
+        while (*data).remaining.load(Ordering::Acquire) != 0 {
+            spin_loop_hint();
+        }
+        drop(Box::from_raw(data));
+    }
+}
+
+/*
+ * Run `f` on `cpu` with interrupts disabled and wait for it to finish.
+ * Interrupts must be enabled, unless `cpu` is the current one.
+ */
+pub fn call_on<F: Fn() + Send + Sync + 'static>(cpu: usize, f: F)
+{
+    ::sched::preempt_disable();
+    if cpu == percpu::cpu_id() {
+        let flags = irq::save_and_disable();
+        f();
+        irq::restore(flags);
+        ::sched::preempt_enable();
+        return;
+    }
+
+    assert!(is_online(cpu), "call on CPU {}, which is not online", cpu);
+    assert!(irq::enabled(), "waiting for a call with interrupts disabled");
+    let data = Box::into_raw(Box::new(CallData {
+        func: Box::new(f),
+        remaining: AtomicUsize::new(1),
+        wait: true,
+    }));
+    queue(cpu, data);
+    ::sched::preempt_enable();
+    wait_for(data);
+}
+
+/*
+ * Run `f` on every online CPU, including the current one, with interrupts
+ * disabled. If `wait` is set, return only once all of them are done, which
+ * needs interrupts to be enabled.
+ */
+pub fn call_on_all<F: Fn() + Send + Sync + 'static>(f: F, wait: bool)
+{
+    assert!(!wait || irq::enabled(), "waiting for a call with interrupts disabled");
+
+    ::sched::preempt_disable();
+    let this = percpu::cpu_id();
+    let targets = ONLINE.load(Ordering::SeqCst) | (1 << this);
+    let data = Box::into_raw(Box::new(CallData {
+        func: Box::new(f),
+        remaining: AtomicUsize::new(targets.count_ones() as usize),
+        wait: wait,
+    }));
+
+    for cpu in 0..64 {
+        if cpu != this && targets & (1 << cpu) != 0 {
+            queue(cpu, data);
+        }
+    }
+
+    let flags = irq::save_and_disable();
+    unsafe { run(data); }
+    irq::restore(flags);
+    ::sched::preempt_enable();
+
+    if wait {
+        wait_for(data);
+    }
+}
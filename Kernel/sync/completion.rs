@@ -0,0 +1,75 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use sched::wait::WaitQueue;
+
+/* `done` once complete_all has been called */
+const COMPLETE_ALL: usize = !0;
+
+/*
+ * Waits for something to happen, e.g. a task to finish starting up or a
+ * device to answer. `complete` may be called from interrupt context, and
+ * before anyone started waiting.
+ */
+pub struct Completion {
+    done: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Completion {
+    pub const fn new() -> Completion {
+        Completion {
+            done: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /* Consume one completion if there is one */
+    pub fn try_wait(&self) -> bool {
+        let mut done = self.done.load(Ordering::Acquire);
+        loop {
+            if done == COMPLETE_ALL {
+                return true;
+            }
+            if done == 0 {
+                return false;
+            }
+            let old = self.done.compare_and_swap(done, done - 1, Ordering::AcqRel);
+            if old == done {
+                return true;
+            }
+            done = old;
+        }
+    }
+
+    /* Checked with the queue locked only, see `complete` */
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.try_wait());
+    }
+
+    /* Let one waiter through. The waiter may free the Completion once it is through */
+    pub fn complete(&self) {
+        self.waiters.wake_one_after(|| {
+            let mut done = self.done.load(Ordering::Relaxed);
+            while done != COMPLETE_ALL {
+                let old = self.done.compare_and_swap(done, done + 1, Ordering::AcqRel);
+                if old == done {
+                    break;
+                }
+                done = old;
+            }
+        });
+    }
+
+    /* Let every current and future waiter through, until `reinit` */
+    pub fn complete_all(&self) {
+        self.waiters.wake_all_after(|| self.done.store(COMPLETE_ALL, Ordering::Release));
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire) != 0
+    }
+
+    pub fn reinit(&self) {
+        self.done.store(0, Ordering::Release);
+    }
+}
@@ -0,0 +1,46 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use sched::wait::WaitQueue;
+use super::mutex::MutexGuard;
+
+/*
+ * A condition variable, used together with a Mutex. As usual, waiters
+ * must recheck their condition after `wait` returns.
+ */
+pub struct Condvar {
+    /* Bumped on every notification */
+    seq: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            seq: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /*
+     * Release the mutex, sleep until notified, then lock it again. The
+     * sequence number is sampled while still holding the mutex, so a
+     * notification sent after the condition was checked is never missed.
+     */
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let seq = self.seq.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters.wait_until(|| self.seq.load(Ordering::Acquire) != seq);
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
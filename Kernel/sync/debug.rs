@@ -0,0 +1,132 @@
+/*
+ * Debugging state embedded in every spinning lock. Debug builds remember
+ * which CPU holds the lock to catch recursive locking, and the `lockdep`
+ * feature validates the order locks are taken in. Otherwise this compiles
+ * to nothing.
+ */
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "lockdep")]
+use super::lockdep::LockClass;
+
+pub struct LockDebug {
+    /* The CPU holding the lock plus one, 0 if unlocked */
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
+    /*
+     * Bit n is set while CPU n reads an RwSpinLock. Nested reads on one CPU
+     * clear it early, which only loses the check, and CPUs from 64 on
+     * aren't tracked.
+     */
+    #[cfg(debug_assertions)]
+    readers: AtomicU64,
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
+}
+
+impl LockDebug {
+    #[allow(unused_variables)]
+    pub const fn new(name: &'static str) -> LockDebug {
+        LockDebug {
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(0),
+            #[cfg(debug_assertions)]
+            readers: AtomicU64::new(0),
+            #[cfg(feature = "lockdep")]
+            class: LockClass::new(name),
+        }
+    }
+
+    /* Called before spinning on the lock */
+    #[allow(unused_variables)]
+    #[inline(always)]
+    pub fn before_lock(&self, kind: &str) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(cpu) = ::arch::percpu::try_cpu_id() {
+                if self.owner.load(Ordering::Relaxed) == cpu + 1 {
+                    panic!("recursive locking of a {} on CPU {}", kind, cpu);
+                }
+            }
+        }
+        #[cfg(feature = "lockdep")]
+        self.class.acquire(false);
+    }
+
+    /* Called before spinning on an RwSpinLock for writing */
+    #[inline(always)]
+    pub fn before_write_lock(&self, kind: &str) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(cpu) = ::arch::percpu::try_cpu_id() {
+                if cpu < 64 && self.readers.load(Ordering::Relaxed) & (1 << cpu) != 0 {
+                    panic!("writing a {} that CPU {} is reading", kind, cpu);
+                }
+            }
+        }
+        self.before_lock(kind);
+    }
+
+    /* Called after a successful try_lock */
+    #[inline(always)]
+    pub fn after_trylock(&self) {
+        #[cfg(feature = "lockdep")]
+        self.class.acquire(true);
+        self.acquired();
+    }
+
+    /* Called once the lock is held, for locks with a single holder */
+    #[inline(always)]
+    pub fn acquired(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(cpu) = ::arch::percpu::try_cpu_id() {
+                self.owner.store(cpu + 1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /* Readers share the lock, so they aren't recorded as its owner */
+    #[inline(always)]
+    pub fn after_read_trylock(&self) {
+        #[cfg(feature = "lockdep")]
+        self.class.acquire(true);
+        self.read_acquired();
+    }
+
+    /* Called once the lock is held for reading */
+    #[inline(always)]
+    pub fn read_acquired(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(cpu) = ::arch::percpu::try_cpu_id() {
+                if cpu < 64 {
+                    self.readers.fetch_or(1 << cpu, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn read_released(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(cpu) = ::arch::percpu::try_cpu_id() {
+                if cpu < 64 {
+                    self.readers.fetch_and(!(1 << cpu), Ordering::Relaxed);
+                }
+            }
+        }
+        #[cfg(feature = "lockdep")]
+        self.class.release();
+    }
+
+    #[inline(always)]
+    pub fn released(&self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(0, Ordering::Relaxed);
+        #[cfg(feature = "lockdep")]
+        self.class.release();
+    }
+}
@@ -0,0 +1,106 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+
+use arch::irq;
+use super::debug::LockDebug;
+
+/*
+ * A spinlock that disables interrupts on the local CPU while held, so that
+ * an interrupt handler taking the same lock can't deadlock against the
+ * code it interrupted. RFLAGS.IF is restored when the guard is dropped.
+ */
+pub struct IrqSpinLock<T> {
+    locked: AtomicBool,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T: 'a> {
+    lock: &'a IrqSpinLock<T>,
+    flags: usize,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> IrqSpinLock<T> {
+        IrqSpinLock::named("IrqSpinLock", data)
+    }
+
+    /* A lock that lockdep reports under `name` */
+    pub const fn named(name: &'static str, data: T) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            locked: AtomicBool::new(false),
+            debug: LockDebug::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let flags = irq::save_and_disable();
+        self.debug.before_lock("IrqSpinLock");
+        while !self.try_acquire() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop_hint();
+            }
+        }
+        self.debug.acquired();
+        IrqSpinLockGuard { lock: self, flags: flags }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let flags = irq::save_and_disable();
+        if self.try_acquire() {
+            self.debug.after_trylock();
+            Some(IrqSpinLockGuard { lock: self, flags: flags })
+        } else {
+            irq::restore(flags);
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    /*
+     * Release the lock without a guard. The previous holder must never
+     * touch the data again, its interrupt state is not restored.
+     */
+    pub unsafe fn force_unlock(&self) {
+        self.debug.released();
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        self.lock.locked.store(false, Ordering::Release);
+        irq::restore(self.flags);
+    }
+}
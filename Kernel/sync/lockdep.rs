@@ -0,0 +1,286 @@
+/*
+ * A lightweight lock validator, built with the `lockdep` feature.
+ *
+ * Every lock gets a class the first time it is taken. Whenever a lock is
+ * acquired while others are held, the "taken after" edges between their
+ * classes are recorded, and an acquisition that would close a cycle in that
+ * graph (an ABBA inversion, or a longer one) is reported along with the
+ * chain that it conflicts with. It also reports locks that are taken both
+ * in interrupt handlers and with interrupts enabled, as the handler can
+ * then deadlock against the code it interrupted.
+ *
+ * Classes are per lock instance and are never freed, so locks embedded in
+ * short lived objects will eventually exhaust the class table, at which
+ * point validation is turned off.
+ */
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use arch::{irq, percpu};
+
+const MAX_CLASSES: usize = 256;
+const MAX_HELD: usize = 16;
+const MAX_CPUS: usize = 64;
+const WORDS: usize = MAX_CLASSES / 64;
+
+/* Class usage bits */
+const USED_IN_IRQ: u8 = 1 << 0;
+const USED_IRQS_ENABLED: u8 = 1 << 1;
+
+/* Embedded in every tracked lock */
+pub struct LockClass {
+    name: &'static str,
+    /* Index into the class table plus one, 0 until first taken */
+    id: AtomicUsize,
+}
+
+/* The locks held by a task, or by a CPU before it runs any task */
+#[derive(Clone, Copy)]
+pub struct HeldLocks {
+    classes: [usize; MAX_HELD],
+    depth: usize,
+}
+
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    keys: [usize; MAX_CLASSES],
+    usage: [u8; MAX_CLASSES],
+    /* Bit b of after[a] is set if class b has been taken while holding a */
+    after: [[u64; WORDS]; MAX_CLASSES],
+    nr_classes: usize,
+    /* Scratch space for `path` */
+    parent: [usize; MAX_CLASSES],
+    queue: [usize; MAX_CLASSES],
+}
+
+const NO_LOCKS: HeldLocks = HeldLocks { classes: [0; MAX_HELD], depth: 0 };
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+static mut GRAPH: Graph = Graph {
+    names: [""; MAX_CLASSES],
+    keys: [0; MAX_CLASSES],
+    usage: [0; MAX_CLASSES],
+    after: [[0; WORDS]; MAX_CLASSES],
+    nr_classes: 0,
+    parent: [0; MAX_CLASSES],
+    queue: [0; MAX_CLASSES],
+};
+static mut BOOT_HELD: [HeldLocks; MAX_CPUS] = [NO_LOCKS; MAX_CPUS];
+
+impl HeldLocks {
+    pub const fn new() -> HeldLocks {
+        NO_LOCKS
+    }
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass { name: name, id: AtomicUsize::new(0) }
+    }
+
+    /*
+     * Validate and record taking this lock. A trylock can't deadlock, so
+     * it is only recorded as held. Called before spinning or sleeping, so
+     * that a deadlock is reported rather than hit.
+     */
+    pub fn acquire(&self, trylock: bool) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let irqs_enabled = irq::enabled();
+        let flags = irq::save_and_disable();
+        lock_graph();
+        unsafe {
+            if let Some(id) = self.register() {
+                validate(id, trylock, irqs_enabled);
+                let held = held_locks();
+                if held.depth == MAX_HELD {
+                    disable();
+                    log!("lockdep: more than {} locks held, turning off", MAX_HELD);
+                } else {
+                    held.classes[held.depth] = id;
+                    held.depth += 1;
+                }
+            }
+        }
+        unlock_graph();
+        irq::restore(flags);
+    }
+
+    /* Forget that this lock is held, in whatever order guards are dropped */
+    pub fn release(&self) {
+        let id = self.id.load(Ordering::Relaxed);
+        if !ENABLED.load(Ordering::Relaxed) || id == 0 {
+            return;
+        }
+        let flags = irq::save_and_disable();
+        let held = unsafe { held_locks() };
+        if let Some(pos) = held.classes[..held.depth].iter().rposition(|&c| c == id - 1) {
+            for i in pos..held.depth - 1 {
+                held.classes[i] = held.classes[i + 1];
+            }
+            held.depth -= 1;
+        }
+        irq::restore(flags);
+    }
+
+    /* Look up or allocate the class id, with the graph locked */
+    unsafe fn register(&self) -> Option<usize> {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return Some(id - 1);
+        }
+        if GRAPH.nr_classes == MAX_CLASSES {
+            disable();
+            log!("lockdep: more than {} lock classes, turning off", MAX_CLASSES);
+            return None;
+        }
+        let id = GRAPH.nr_classes;
+        GRAPH.nr_classes += 1;
+        GRAPH.names[id] = self.name;
+        GRAPH.keys[id] = self as *const LockClass as usize;
+        self.id.store(id + 1, Ordering::Relaxed);
+        Some(id)
+    }
+}
+
+/* Stop validating, e.g. because we are about to report a problem or panic */
+pub fn disable()
+{
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+fn lock_graph()
+{
+    while GRAPH_LOCK.compare_and_swap(false, true, Ordering::Acquire) {
+        /* Do nothing */
+    }
+}
+
+fn unlock_graph()
+{
+    GRAPH_LOCK.store(false, Ordering::Release);
+}
+
+/* The held lock stack of the current context, interrupts must be disabled */
+unsafe fn held_locks() -> &'static mut HeldLocks
+{
+    let cpu = match percpu::try_cpu_id() {
+        Some(cpu) => cpu,
+        None => return &mut BOOT_HELD[0],
+    };
+    let task = ::sched::current();
+    if task.is_null() {
+        &mut BOOT_HELD[cpu]
+    } else {
+        &mut (*task).held_locks
+    }
+}
+
+unsafe fn validate(id: usize, trylock: bool, irqs_enabled: bool)
+{
+    let held = *held_locks();
+
+    if !trylock {
+        for &prev in held.classes[..held.depth].iter() {
+            if prev == id {
+                /* Recursion is left to the locks themselves, readers may nest */
+                continue;
+            }
+            if GRAPH.after[prev][id / 64] & (1 << (id % 64)) != 0 {
+                continue;
+            }
+            if path(id, prev) {
+                report_inversion(id, prev, &held);
+            }
+            GRAPH.after[prev][id / 64] |= 1 << (id % 64);
+        }
+    }
+
+    let old = GRAPH.usage[id];
+    let mut usage = old;
+    if percpu::try_cpu_id().is_some() && percpu::in_interrupt() {
+        usage |= USED_IN_IRQ;
+    } else if irqs_enabled {
+        usage |= USED_IRQS_ENABLED;
+    }
+    GRAPH.usage[id] = usage;
+    let both = USED_IN_IRQ | USED_IRQS_ENABLED;
+    if usage & both == both && old & both != both {
+        disable();
+        unlock_graph();
+        log!("lockdep: {} is taken in interrupt context and with interrupts enabled", class_name(id));
+        log!("lockdep: an interrupt can deadlock against its holder, use an IrqSpinLock");
+        panic!("lockdep: IRQ-unsafe lock {} used in interrupt context", class_name(id));
+    }
+}
+
+/*
+ * Breadth first search for a chain of "taken after" edges leading from
+ * `from` to `to`, leaving it in GRAPH.parent.
+ */
+unsafe fn path(from: usize, to: usize) -> bool
+{
+    let mut visited = [0u64; WORDS];
+    let mut head = 0;
+    let mut tail = 1;
+    GRAPH.queue[0] = from;
+    visited[from / 64] |= 1 << (from % 64);
+
+    while head < tail {
+        let class = GRAPH.queue[head];
+        head += 1;
+        if class == to {
+            return true;
+        }
+        for next in 0..GRAPH.nr_classes {
+            let bit = 1 << (next % 64);
+            if GRAPH.after[class][next / 64] & bit != 0 && visited[next / 64] & bit == 0 {
+                visited[next / 64] |= bit;
+                GRAPH.parent[next] = class;
+                GRAPH.queue[tail] = next;
+                tail += 1;
+            }
+        }
+    }
+    false
+}
+
+struct ClassName(usize);
+
+impl ::core::fmt::Display for ClassName {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        unsafe { write!(f, "{}@0x{:x}", GRAPH.names[self.0], GRAPH.keys[self.0]) }
+    }
+}
+
+fn class_name(id: usize) -> ClassName
+{
+    ClassName(id)
+}
+
+/*
+ * Taking `id` while holding `prev`, but `prev` has been taken after `id`
+ * before. Validation is turned off first, so that the locks taken by the
+ * logger and the panic handler don't recurse in here.
+ */
+unsafe fn report_inversion(id: usize, prev: usize, held: &HeldLocks) -> !
+{
+    disable();
+    unlock_graph();
+
+    log!("lockdep: possible deadlock, lock order inversion detected");
+    log!("lockdep: acquiring {} while holding {}", class_name(id), class_name(prev));
+    log!("lockdep: held locks, outermost first:");
+    for &class in held.classes[..held.depth].iter() {
+        log!("lockdep:     {}", class_name(class));
+    }
+    log!("lockdep: but earlier acquisitions established this order:");
+    let mut class = prev;
+    while class != id {
+        let parent = GRAPH.parent[class];
+        log!("lockdep:     {} taken while holding {}", class_name(class), class_name(parent));
+        class = parent;
+    }
+    panic!("lockdep: lock order inversion between {} and {}", class_name(id), class_name(prev));
+}
@@ -0,0 +1,37 @@
+/*
+ * Locking primitives.
+ *
+ * SpinLock, TicketLock and RwSpinLock disable preemption while held.
+ * IrqSpinLock disables interrupts instead, which keeps the holder from
+ * being preempted just the same, and must be used for anything that is
+ * touched from interrupt context. Debug builds panic when a CPU tries to
+ * take a lock it already holds, or to write an RwSpinLock it is reading,
+ * instead of deadlocking silently, and building with the `lockdep`
+ * feature checks that locks are always taken in the same order.
+ *
+ * Mutex, Semaphore, Condvar and Completion put the task to sleep instead
+ * of spinning, see sched::wait. Data that is mostly read can be protected
+ * with RCU instead of a lock.
+ */
+
+mod debug;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod spinlock;
+pub mod ticket;
+pub mod irq;
+pub mod rwlock;
+pub mod mutex;
+pub mod semaphore;
+pub mod condvar;
+pub mod completion;
+pub mod rcu;
+
+pub use self::spinlock::{SpinLock, SpinLockGuard};
+pub use self::ticket::{TicketLock, TicketLockGuard};
+pub use self::irq::{IrqSpinLock, IrqSpinLockGuard};
+pub use self::rwlock::{RwSpinLock, RwSpinLockReadGuard, RwSpinLockWriteGuard};
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::condvar::Condvar;
+pub use self::completion::Completion;
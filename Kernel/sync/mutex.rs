@@ -0,0 +1,107 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use sched;
+use sched::wait::WaitQueue;
+#[cfg(feature = "lockdep")]
+use super::lockdep::LockClass;
+
+/*
+ * A sleeping lock, for critical sections that may be long or block. Must
+ * not be taken from interrupt context, nor while holding a spinlock.
+ */
+pub struct Mutex<T> {
+    /* The Task holding the mutex, 0 if unlocked */
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: 'a> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex::named("Mutex", data)
+    }
+
+    /* A mutex that lockdep reports under `name` */
+    #[allow(unused_variables)]
+    pub const fn named(name: &'static str, data: T) -> Mutex<T> {
+        Mutex {
+            owner: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            #[cfg(feature = "lockdep")]
+            class: LockClass::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self, task: usize) -> bool {
+        self.owner.compare_and_swap(0, task, Ordering::Acquire) == 0
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let task = sched::current() as usize;
+        assert!(task != 0, "Mutex locked before the scheduler was started");
+        if self.owner.load(Ordering::Relaxed) == task {
+            panic!("recursive locking of a Mutex by task {}", sched::current_id());
+        }
+        #[cfg(feature = "lockdep")]
+        self.class.acquire(false);
+        if !self.try_acquire(task) {
+            self.waiters.wait_until(|| self.try_acquire(task));
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let task = sched::current() as usize;
+        assert!(task != 0, "Mutex locked before the scheduler was started");
+        if self.try_acquire(task) {
+            #[cfg(feature = "lockdep")]
+            self.class.acquire(true);
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        self.mutex.class.release();
+        self.mutex.owner.store(0, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ * Read-copy-update, for data that is read on hot paths and rarely written.
+ *
+ * Readers only disable preemption. Writers publish a new copy of the data
+ * and free the old one after a grace period, once every CPU has passed
+ * through a quiescent state: a scheduler tick that interrupted code
+ * with preemption enabled, which therefore can't be inside a read-side
+ * critical section. CPUs only take part once they have entered the
+ * scheduler, see `cpu_online`.
+ */
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use alloc::boxed::Box;
+use alloc::Vec;
+
+use arch::irq;
+use arch::percpu::{self, PerCpu};
+use super::{Completion, IrqSpinLock};
+
+pub type RcuCallback = Box<FnMut() + Send>;
+
+struct GracePeriods {
+    /* Number of grace periods started and completed so far */
+    started: u64,
+    completed: u64,
+    /* The grace period that queued callbacks are waiting for */
+    needed: u64,
+}
+
+static STATE: IrqSpinLock<GracePeriods> = IrqSpinLock::named("rcu", GracePeriods {
+    started: 0,
+    completed: 0,
+    needed: 0,
+});
+
+/* CPUs taking part in grace periods, and those the current one waits for */
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/* Callbacks queued on every CPU, with the grace period they wait for */
+static CALLBACKS: PerCpu<IrqSpinLock<Vec<(u64, RcuCallback)>>> = PerCpu::new();
+
+/* Set up the callback queues, must be called before the APs are started */
+pub fn init()
+{
+    unsafe {
+        CALLBACKS.init(|_| IrqSpinLock::named("rcu callbacks", Vec::new()));
+    }
+}
+
+/* Start reporting quiescent states for the current CPU */
+pub fn cpu_online()
+{
+    ONLINE.fetch_or(1 << percpu::cpu_id(), Ordering::SeqCst);
+}
+
+/* A read-side critical section, ends when dropped. Must not sleep. */
+pub struct RcuReadGuard {
+    /* Must be dropped on the CPU it was taken on */
+    _not_send: PhantomData<*const ()>,
+}
+
+pub fn read_lock() -> RcuReadGuard
+{
+    ::sched::preempt_disable();
+    RcuReadGuard { _not_send: PhantomData }
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        ::sched::preempt_enable();
+    }
+}
+
+/* With STATE locked */
+fn start_grace_period(gp: &mut GracePeriods)
+{
+    gp.started += 1;
+    let online = ONLINE.load(Ordering::SeqCst);
+    PENDING.store(online, Ordering::SeqCst);
+    if online == 0 {
+        gp.completed = gp.started;
+    }
+}
+
+/* Report that the current CPU is not in a read-side critical section */
+fn quiescent_state()
+{
+    let bit = 1 << percpu::cpu_id();
+    if PENDING.load(Ordering::Relaxed) & bit == 0 {
+        return;
+    }
+
+    let mut gp = STATE.lock();
+    let pending = PENDING.fetch_and(!bit, Ordering::SeqCst) & !bit;
+    if pending == 0 && gp.completed != gp.started {
+        gp.completed = gp.started;
+        if gp.needed > gp.completed {
+            start_grace_period(&mut gp);
+        }
+    }
+}
+
+/*
+ * Called from the scheduler tick, with interrupts disabled. `quiescent` is
+ * set if the interrupted code had preemption enabled.
+ */
+pub fn tick(quiescent: bool)
+{
+    if quiescent {
+        quiescent_state();
+    }
+
+    if CALLBACKS.this_cpu().lock().is_empty() {
+        return;
+    }
+
+    /* The callbacks may queue more callbacks, so don't hold on to the queue */
+    let completed = STATE.lock().completed;
+    loop {
+        let ready = {
+            let mut queue = CALLBACKS.this_cpu().lock();
+            match queue.iter().position(|&(target, _)| target <= completed) {
+                Some(idx) => Some(queue.remove(idx).1),
+                None => None,
+            }
+        };
+        match ready {
+            Some(mut callback) => callback(),
+            None => break,
+        }
+    }
+}
+
+/*
+ * Run `callback` once every read-side critical section that may have
+ * started before this call has ended. It runs in interrupt context on the
+ * current CPU.
+ */
+pub fn call_rcu(callback: RcuCallback)
+{
+    let flags = irq::save_and_disable();
+    let target = {
+        let mut gp = STATE.lock();
+        /* A grace period in progress may have missed readers we care about */
+        let target = gp.started + 1;
+        if gp.needed < target {
+            gp.needed = target;
+        }
+        if gp.completed == gp.started {
+            start_grace_period(&mut gp);
+        }
+        target
+    };
+    CALLBACKS.this_cpu().lock().push((target, callback));
+    irq::restore(flags);
+}
+
+/* Sleep until every read-side critical section started so far has ended */
+pub fn synchronize_rcu()
+{
+    if ONLINE.load(Ordering::SeqCst) == 0 {
+        /* Nobody is reading yet */
+        return;
+    }
+
+    let done = Completion::new();
+    let addr = &done as *const Completion as usize;
+    call_rcu(Box::new(move || unsafe { (*(addr as *const Completion)).complete(); }));
+    done.wait();
+}
+
+/*
+ * A pointer to RCU protected data of type T. Readers get a reference that
+ * is valid for their critical section, writers replace the whole value.
+ */
+pub struct Rcu<T> {
+    ptr: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+
+impl<T: Send + 'static> Rcu<T> {
+    pub const fn empty() -> Rcu<T> {
+        Rcu { ptr: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> Option<&'a T> {
+        unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+    }
+
+    /*
+     * Publish `value` and free the previous one after a grace period.
+     * Concurrent writers must be serialized by the caller.
+     */
+    pub fn publish(&self, value: Box<T>) {
+        let old = self.ptr.swap(Box::into_raw(value), Ordering::AcqRel);
+        if !old.is_null() {
+            let old = old as usize;
+            call_rcu(Box::new(move || unsafe { drop(Box::from_raw(old as *mut T)); }));
+        }
+    }
+
+    /* Take a copy of the current value, change it and publish it */
+    pub fn update<F: FnOnce(&mut T)>(&self, update: F) where T: Clone {
+        let mut value = {
+            let guard = read_lock();
+            Box::new(self.read(&guard).expect("updating empty Rcu").clone())
+        };
+        update(&mut value);
+        self.publish(value);
+    }
+}
@@ -0,0 +1,140 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+use super::debug::LockDebug;
+
+/* Set in `state` while a writer holds the lock, the rest counts readers */
+const WRITER: usize = 1 << (0usize.count_zeros() - 1);
+
+/*
+ * A reader-writer spinlock. Any number of readers or a single writer may
+ * hold it. Readers are not fair towards writers, so this is for data that
+ * is written rarely.
+ */
+pub struct RwSpinLock<T> {
+    state: AtomicUsize,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+
+pub struct RwSpinLockReadGuard<'a, T: 'a> {
+    lock: &'a RwSpinLock<T>,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(data: T) -> RwSpinLock<T> {
+        RwSpinLock::named("RwSpinLock", data)
+    }
+
+    /* A lock that lockdep reports under `name` */
+    pub const fn named(name: &'static str, data: T) -> RwSpinLock<T> {
+        RwSpinLock {
+            state: AtomicUsize::new(0),
+            debug: LockDebug::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & WRITER == 0
+            && self.state.compare_exchange_weak(state, state + 1,
+                                                Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn read(&self) -> RwSpinLockReadGuard<T> {
+        ::sched::preempt_disable();
+        self.debug.before_lock("RwSpinLock");
+        while !self.try_acquire_read() {
+            spin_loop_hint();
+        }
+        self.debug.read_acquired();
+        RwSpinLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwSpinLockWriteGuard<T> {
+        ::sched::preempt_disable();
+        self.debug.before_write_lock("RwSpinLock");
+        while !self.try_acquire_write() {
+            spin_loop_hint();
+        }
+        self.debug.acquired();
+        RwSpinLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<T>> {
+        ::sched::preempt_disable();
+        if self.try_acquire_read() {
+            self.debug.after_read_trylock();
+            Some(RwSpinLockReadGuard { lock: self })
+        } else {
+            ::sched::preempt_enable();
+            None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<T>> {
+        ::sched::preempt_disable();
+        if self.try_acquire_write() {
+            self.debug.after_trylock();
+            Some(RwSpinLockWriteGuard { lock: self })
+        } else {
+            ::sched::preempt_enable();
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'a, T> Deref for RwSpinLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.read_released();
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        ::sched::preempt_enable();
+    }
+}
+
+impl<'a, T> Deref for RwSpinLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwSpinLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        self.lock.state.store(0, Ordering::Release);
+        ::sched::preempt_enable();
+    }
+}
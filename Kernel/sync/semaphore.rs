@@ -0,0 +1,48 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use sched::wait::WaitQueue;
+
+/* A counting semaphore, `up` may be called from interrupt context */
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /* Take one unit if there is one, without sleeping */
+    pub fn try_down(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            let old = self.count.compare_and_swap(count, count - 1, Ordering::Acquire);
+            if old == count {
+                return true;
+            }
+            count = old;
+        }
+        false
+    }
+
+    /* Take one unit, sleeping until one is available */
+    pub fn down(&self) {
+        if !self.try_down() {
+            self.waiters.wait_until(|| self.try_down());
+        }
+    }
+
+    /* Give back one unit, waking a waiter */
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
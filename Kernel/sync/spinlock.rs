@@ -0,0 +1,101 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+
+use super::debug::LockDebug;
+
+/* A test-and-test-and-set spinlock, not to be taken from interrupt context */
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: 'a> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock::named("SpinLock", data)
+    }
+
+    /* A lock that lockdep reports under `name` */
+    pub const fn named(name: &'static str, data: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            debug: LockDebug::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        ::sched::preempt_disable();
+        self.debug.before_lock("SpinLock");
+        while !self.try_acquire() {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop_hint();
+            }
+        }
+        self.debug.acquired();
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        ::sched::preempt_disable();
+        if self.try_acquire() {
+            self.debug.after_trylock();
+            Some(SpinLockGuard { lock: self })
+        } else {
+            ::sched::preempt_enable();
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /* No locking needed, we have the only reference */
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    /*
+     * Release the lock without a guard, e.g. to get the logger back after
+     * a panic. The previous holder must never touch the data again.
+     */
+    pub unsafe fn force_unlock(&self) {
+        self.debug.released();
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        self.lock.locked.store(false, Ordering::Release);
+        ::sched::preempt_enable();
+    }
+}
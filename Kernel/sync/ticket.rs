@@ -0,0 +1,92 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+use super::debug::LockDebug;
+
+/*
+ * A fair spinlock: CPUs get the lock in the order they asked for it, so
+ * nobody starves under contention.
+ */
+pub struct TicketLock<T> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    debug: LockDebug,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T: 'a> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(data: T) -> TicketLock<T> {
+        TicketLock::named("TicketLock", data)
+    }
+
+    /* A lock that lockdep reports under `name` */
+    pub const fn named(name: &'static str, data: T) -> TicketLock<T> {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            debug: LockDebug::new(name),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<T> {
+        ::sched::preempt_disable();
+        self.debug.before_lock("TicketLock");
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop_hint();
+        }
+        self.debug.acquired();
+        TicketLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<TicketLockGuard<T>> {
+        ::sched::preempt_disable();
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        if self.next_ticket.compare_and_swap(serving, serving + 1, Ordering::Acquire) == serving {
+            self.debug.after_trylock();
+            Some(TicketLockGuard { lock: self })
+        } else {
+            ::sched::preempt_enable();
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<'a, T> Deref for TicketLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for TicketLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for TicketLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.debug.released();
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+        ::sched::preempt_enable();
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * System calls. The arch entry code hands over the number and the six
+ * argument registers, the handler's result goes back in the return
+ * register: the value on success, minus the error number on failure.
+ *
+ * The numbers are those of Linux on the same architecture, so that
+ * programs built with an unmodified toolchain make sense to us as far as
+ * we implement them.
+ */
+use arch::uaccess;
+use errno::Errno;
+
+pub type SyscallArgs = [usize; 6];
+pub type SyscallResult = Result<usize, Errno>;
+
+type SyscallHandler = fn(&SyscallArgs) -> SyscallResult;
+
+#[cfg(target_arch="x86_64")]
+pub const SYS_WRITE: usize = 1;
+#[cfg(target_arch="x86_64")]
+pub const SYS_SCHED_YIELD: usize = 24;
+#[cfg(target_arch="x86_64")]
+pub const SYS_EXIT: usize = 60;
+
+#[cfg(target_arch="x86")]
+pub const SYS_WRITE: usize = 4;
+#[cfg(target_arch="x86")]
+pub const SYS_SCHED_YIELD: usize = 158;
+#[cfg(target_arch="x86")]
+pub const SYS_EXIT: usize = 1;
+
+struct Syscall {
+    nr: usize,
+    handler: SyscallHandler,
+}
+
+static SYSCALLS: [Syscall; 3] = [
+    Syscall { nr: SYS_WRITE, handler: sys_write },
+    Syscall { nr: SYS_SCHED_YIELD, handler: sys_sched_yield },
+    Syscall { nr: SYS_EXIT, handler: sys_exit },
+];
+
+/* Run system call `nr`, called by the arch code with interrupts enabled */
+pub fn dispatch(nr: usize, args: &SyscallArgs) -> isize
+{
+    let result = match SYSCALLS.iter().find(|syscall| syscall.nr == nr) {
+        Some(syscall) => (syscall.handler)(args),
+        None => {
+            log!("task {} made unknown system call {}", ::sched::current_id(), nr);
+            Err(Errno::ENOSYS)
+        }
+    };
+    match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    }
+}
+
+/* write(fd, buf, count): stdout and stderr both go to the serial log */
+fn sys_write(args: &SyscallArgs) -> SyscallResult
+{
+    let (fd, buf, count) = (args[0], args[1], args[2]);
+    if fd != 1 && fd != 2 {
+        return Err(Errno::EBADF);
+    }
+    if !uaccess::is_user_range(buf, count) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut chunk = [0u8; 256];
+    let mut written = 0;
+    while written < count {
+        let len = ::core::cmp::min(count - written, chunk.len());
+        if let Err(errno) = uaccess::copy_from_user(&mut chunk[..len], buf + written) {
+            /* Report a partial write, the fault only if nothing was written */
+            return if written > 0 { Ok(written) } else { Err(errno) };
+        }
+        ::logging::write_raw(&chunk[..len]);
+        written += len;
+    }
+    Ok(written)
+}
+
+/* sched_yield() */
+fn sys_sched_yield(_args: &SyscallArgs) -> SyscallResult
+{
+    ::sched::yield_now();
+    Ok(0)
+}
+
+/* exit(status): never returns */
+fn sys_exit(args: &SyscallArgs) -> SyscallResult
+{
+    log!("task {} exited with status {}", ::sched::current_id(), args[0] as i32);
+    ::sched::exit();
+}
@@ -0,0 +1,36 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use mm::pmm::FrameAllocator;
+use timer::NSEC_PER_SEC;
+
+/* Clocksource reading at boot, `now` counts from here */
+static BOOT_NS: AtomicU64 = AtomicU64::new(0);
+
+/* Wall-clock time at boot, in seconds since the Unix epoch */
+static BOOT_UNIX: AtomicU64 = AtomicU64::new(0);
+
+/* Pick the clocksource and read the wall-clock time from the RTC */
+pub fn init(fma: &mut FrameAllocator)
+{
+    let source = ::arch::clock::init(fma);
+    BOOT_NS.store(::arch::clock::read_ns(), Ordering::Relaxed);
+
+    let date = ::arch::rtc::read();
+    BOOT_UNIX.store(date.to_unix(), Ordering::Relaxed);
+
+    log!("Clocksource: {:?}, booted at {} UTC", source, date);
+}
+
+/* Monotonic nanoseconds since boot, 0 before `init` */
+pub fn now() -> u64
+{
+    let boot = BOOT_NS.load(Ordering::Relaxed);
+    let now = ::arch::clock::read_ns();
+    if now > boot { now - boot } else { 0 }
+}
+
+/* Current wall-clock time, in seconds since the Unix epoch */
+pub fn wall_clock() -> u64
+{
+    BOOT_UNIX.load(Ordering::Relaxed) + now() / NSEC_PER_SEC
+}
@@ -0,0 +1,165 @@
+use core::cmp::Ordering;
+use alloc::BinaryHeap;
+use alloc::boxed::Box;
+
+use arch::percpu::PerCpu;
+
+/* Frequency of the scheduler tick */
+pub const HZ: u64 = 100;
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+pub const TICK_NS: u64 = NSEC_PER_SEC / HZ;
+
+pub type TimerCallback = Box<FnMut() + Send>;
+
+/* A pending timer, ordered so that the BinaryHeap pops the earliest first */
+struct TimerEntry {
+    deadline: u64,
+    seq: u64,
+    callback: TimerCallback,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &TimerEntry) -> bool {
+        self.deadline == other.deadline && self.seq == other.seq
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &TimerEntry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &TimerEntry) -> Ordering {
+        /* Reversed, timers with the same deadline fire in FIFO order */
+        other.deadline.cmp(&self.deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct CpuTimers {
+    queue: BinaryHeap<TimerEntry>,
+    next_seq: u64,
+    /* The deadline the hardware timer is currently programmed for */
+    programmed: u64,
+    ticks: u64,
+}
+
+static TIMERS: PerCpu<CpuTimers> = PerCpu::new();
+
+/* Set up the timer queues, must be called before the APs are started */
+pub fn init()
+{
+    unsafe {
+        TIMERS.init(|_| CpuTimers {
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            programmed: 0,
+            ticks: 0,
+        });
+    }
+    ::arch::timer::init();
+}
+
+/* Start the tick on the current CPU, this enables interrupts */
+pub fn init_cpu()
+{
+    ::arch::timer::init_cpu(TICK_NS);
+
+    let next = now() + TICK_NS;
+    TIMERS.with_this_cpu(|t| {
+        t.programmed = next;
+        ::arch::timer::program(next);
+    });
+
+    unsafe { ::arch::irq::enable(); }
+}
+
+/* Monotonic nanoseconds since boot */
+pub fn now() -> u64
+{
+    ::time::now()
+}
+
+/* Number of ticks the current CPU has taken */
+pub fn ticks() -> u64
+{
+    TIMERS.with_this_cpu(|t| t.ticks)
+}
+
+/*
+ * Run `callback` on the current CPU, in interrupt context, once `now()`
+ * has passed `deadline`.
+ *
+ * The hardware timer is programmed with interrupts still disabled, a task
+ * migrated in between would program the timer of a CPU whose queue
+ * doesn't hold the entry.
+ */
+pub fn schedule_at(deadline: u64, callback: TimerCallback)
+{
+    TIMERS.with_this_cpu(|t| {
+        let seq = t.next_seq;
+        t.next_seq += 1;
+        t.queue.push(TimerEntry {
+            deadline: deadline,
+            seq: seq,
+            callback: callback,
+        });
+        if deadline < t.programmed {
+            t.programmed = deadline;
+            ::arch::timer::program(deadline);
+        }
+    });
+}
+
+/* Run `callback` once `delay_ns` nanoseconds have passed */
+pub fn schedule_after(delay_ns: u64, callback: TimerCallback)
+{
+    schedule_at(now() + delay_ns, callback);
+}
+
+/* Pop the earliest timer if it has expired by `now` */
+fn pop_expired(now: u64) -> Option<TimerEntry>
+{
+    TIMERS.with_this_cpu(|t| {
+        let expired = match t.queue.peek() {
+            Some(entry) => entry.deadline <= now,
+            None => false,
+        };
+        if expired {
+            t.queue.pop()
+        } else {
+            None
+        }
+    })
+}
+
+/* Called from the timer interrupt of every CPU */
+pub fn interrupt()
+{
+    let now = now();
+
+    /* The callbacks may schedule new timers, so don't hold on to the queue */
+    while let Some(mut entry) = pop_expired(now) {
+        (entry.callback)();
+    }
+
+    /* Fire again at the next timer, or the next tick, whichever is first */
+    TIMERS.with_this_cpu(|t| {
+        t.ticks += 1;
+        let mut next = now + TICK_NS;
+        if let Some(entry) = t.queue.peek() {
+            if entry.deadline < next {
+                next = entry.deadline;
+            }
+        }
+        t.programmed = next;
+        ::arch::timer::program(next);
+    });
+
+    /* This may switch to another task */
+    ::sched::tick();
+}
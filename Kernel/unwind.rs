@@ -14,19 +14,24 @@
 #[no_mangle]	// This and pub neede for rust-lang/rust#51342
 pub fn panic_implementation(info: &::core::panic::PanicInfo) -> !
 {
+	// Don't let a lock held by the panicking code keep the message from us
+	#[cfg(feature = "lockdep")]
+	::sync::lockdep::disable();
+	::logging::enter_panic_mode();
+
 	let (file,line) = match info.location()
 		{
 		Some(loc) => (loc.file(), loc.line(),),
 		None => ("", 0),
 		};
 	if let Some(m) = info.message() {
-		log!("PANIC file='{}', line={} :: {}", file, line, m);
+		error!("PANIC file='{}', line={} :: {}", file, line, m);
 	}
 	else if let Some(m) = info.payload().downcast_ref::<&str>() {
-		log!("PANIC file='{}', line={} :: {}", file, line, m);
+		error!("PANIC file='{}', line={} :: {}", file, line, m);
 	}
 	else {
-		log!("PANIC file='{}', line={} :: ?", file, line);
+		error!("PANIC file='{}', line={} :: ?", file, line);
 	}
 	loop {}
 }